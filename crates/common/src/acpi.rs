@@ -0,0 +1,197 @@
+//! Just enough ACPI to find a table: the RSDP, the RSDT or XSDT it points
+//! to, and the HPET description.
+
+/// Bytes to read at the RSDP address; ACPI 1.0 RSDPs use the first 20.
+pub const RSDP_SIZE: usize = 36;
+pub const HEADER_SIZE: usize = 36;
+pub const HPET_SIGNATURE: &[u8; 4] = b"HPET";
+
+const RSDP_V1_SIZE: usize = 20;
+const ADDRESS_SPACE_MEMORY: u8 = 0;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rsdp {
+    /// Physical address of the XSDT, or of the RSDT when `extended` is false.
+    pub root: u64,
+    /// Whether root entries are 64-bit (XSDT) rather than 32-bit (RSDT).
+    pub extended: bool,
+}
+
+fn rd32(b: &[u8], o: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(b.get(o..o + 4)?.try_into().ok()?))
+}
+
+fn rd64(b: &[u8], o: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(b.get(o..o + 8)?.try_into().ok()?))
+}
+
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) == 0
+}
+
+pub fn parse_rsdp(bytes: &[u8]) -> Option<Rsdp> {
+    if bytes.get(..8)? != b"RSD PTR " || !checksum_ok(bytes.get(..RSDP_V1_SIZE)?) {
+        return None;
+    }
+    let revision = *bytes.get(15)?;
+    if revision >= 2 {
+        let len = rd32(bytes, 20)? as usize;
+        let xsdt = rd64(bytes, 24)?;
+        if xsdt != 0 && checksum_ok(bytes.get(..len)?) {
+            return Some(Rsdp {
+                root: xsdt,
+                extended: true,
+            });
+        }
+    }
+    Some(Rsdp {
+        root: u64::from(rd32(bytes, 16)?),
+        extended: false,
+    })
+}
+
+/// The full length of the table whose header starts `bytes`.
+pub fn table_length(header: &[u8]) -> Option<usize> {
+    let len = rd32(header, 4)? as usize;
+    (len >= HEADER_SIZE).then_some(len)
+}
+
+/// Checks the signature and checksum of the table at the start of `bytes`
+/// and returns it.
+pub fn parse_table<'a>(bytes: &'a [u8], signature: &[u8; 4]) -> Option<&'a [u8]> {
+    if bytes.get(..4)? != signature {
+        return None;
+    }
+    let table = bytes.get(..table_length(bytes)?)?;
+    checksum_ok(table).then_some(table)
+}
+
+pub fn has_signature(header: &[u8], signature: &[u8; 4]) -> bool {
+    header.get(..4) == Some(signature)
+}
+
+/// Physical addresses of the tables a checked RSDT or XSDT lists.
+pub fn root_entries(root: &[u8], extended: bool) -> impl Iterator<Item = u64> + '_ {
+    let size = if extended { 8 } else { 4 };
+    root.get(HEADER_SIZE..)
+        .unwrap_or_default()
+        .chunks_exact(size)
+        .map(move |entry| {
+            if extended {
+                rd64(entry, 0).unwrap_or(0)
+            } else {
+                rd32(entry, 0).map_or(0, u64::from)
+            }
+        })
+}
+
+/// Physical base of the HPET registers from a checked HPET table; None if
+/// they are not memory-mapped.
+pub fn hpet_base(table: &[u8]) -> Option<u64> {
+    if *table.get(40)? != ADDRESS_SPACE_MEMORY {
+        return None;
+    }
+    rd64(table, 44).filter(|&base| base != 0)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::{
+        HEADER_SIZE, HPET_SIGNATURE, Rsdp, has_signature, hpet_base, parse_rsdp, parse_table,
+        root_entries, table_length,
+    };
+    use std::vec::Vec;
+
+    fn fix_checksum(bytes: &mut [u8], at: usize) {
+        let sum = bytes.iter().fold(0u8, |s, &b| s.wrapping_add(b));
+        bytes[at] = bytes[at].wrapping_sub(sum);
+    }
+
+    fn rsdp(revision: u8, rsdt: u32, xsdt: u64) -> [u8; 36] {
+        let mut bytes = [0u8; 36];
+        bytes[..8].copy_from_slice(b"RSD PTR ");
+        bytes[15] = revision;
+        bytes[16..20].copy_from_slice(&rsdt.to_le_bytes());
+        bytes[20..24].copy_from_slice(&36u32.to_le_bytes());
+        bytes[24..32].copy_from_slice(&xsdt.to_le_bytes());
+        fix_checksum(&mut bytes[..20], 8);
+        fix_checksum(&mut bytes, 32);
+        bytes
+    }
+
+    fn table(signature: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut bytes = std::vec![0u8; HEADER_SIZE];
+        bytes[..4].copy_from_slice(signature);
+        bytes.extend_from_slice(body);
+        let len = bytes.len() as u32;
+        bytes[4..8].copy_from_slice(&len.to_le_bytes());
+        fix_checksum(&mut bytes, 9);
+        bytes
+    }
+
+    #[test]
+    fn rsdp_prefers_the_xsdt() {
+        assert_eq!(
+            parse_rsdp(&rsdp(2, 0x1000, 0x2000)),
+            Some(Rsdp {
+                root: 0x2000,
+                extended: true
+            })
+        );
+        assert_eq!(
+            parse_rsdp(&rsdp(0, 0x1000, 0)),
+            Some(Rsdp {
+                root: 0x1000,
+                extended: false
+            })
+        );
+
+        let mut bad = rsdp(2, 0x1000, 0x2000);
+        bad[16] ^= 1;
+        assert_eq!(parse_rsdp(&bad), None);
+        let mut bad = rsdp(2, 0x1000, 0x2000);
+        bad[0] = b'X';
+        assert_eq!(parse_rsdp(&bad), None);
+    }
+
+    #[test]
+    fn root_lists_tables_and_hpet_gives_its_base() {
+        let mut body = Vec::new();
+        for addr in [0x3000u64, 0x4000] {
+            body.extend_from_slice(&addr.to_le_bytes());
+        }
+        let xsdt = table(b"XSDT", &body);
+        let xsdt = parse_table(&xsdt, b"XSDT").expect("xsdt");
+        assert_eq!(
+            root_entries(xsdt, true).collect::<Vec<_>>(),
+            [0x3000, 0x4000]
+        );
+        let rsdt = table(b"RSDT", &[0x00, 0x50, 0, 0]);
+        assert_eq!(root_entries(&rsdt, false).collect::<Vec<_>>(), [0x5000]);
+
+        let mut body = [0u8; 20];
+        body[4] = 0;
+        body[8..16].copy_from_slice(&0xfed0_0000u64.to_le_bytes());
+        let hpet = table(HPET_SIGNATURE, &body);
+        assert!(has_signature(&hpet, HPET_SIGNATURE));
+        assert_eq!(table_length(&hpet), Some(56));
+        let hpet = parse_table(&hpet, HPET_SIGNATURE).expect("hpet");
+        assert_eq!(hpet_base(hpet), Some(0xfed0_0000));
+
+        let mut io = body;
+        io[4] = 1;
+        assert_eq!(hpet_base(&table(HPET_SIGNATURE, &io)), None);
+    }
+
+    #[test]
+    fn tables_with_bad_checksums_or_lengths_are_refused() {
+        let mut hpet = table(HPET_SIGNATURE, &[0; 20]);
+        assert!(parse_table(&hpet, b"APIC").is_none());
+        hpet[20] ^= 0xff;
+        assert!(parse_table(&hpet, HPET_SIGNATURE).is_none());
+        assert!(parse_table(&hpet[..40], HPET_SIGNATURE).is_none());
+        assert_eq!(table_length(&[0u8; 8]), None);
+    }
+}
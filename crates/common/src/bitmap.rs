@@ -0,0 +1,199 @@
+pub struct FrameBitmap<'a> {
+    words: &'a mut [u64],
+    refs: &'a mut [u16],
+    frames: usize,
+    free: usize,
+    hint: usize,
+}
+
+impl<'a> FrameBitmap<'a> {
+    pub fn new(words: &'a mut [u64], refs: &'a mut [u16], frames: usize) -> Self {
+        let frames = frames.min(words.len() * 64).min(refs.len());
+        words.fill(u64::MAX);
+        refs.fill(0);
+        Self {
+            words,
+            refs,
+            frames,
+            free: 0,
+            hint: 0,
+        }
+    }
+
+    pub fn words_for(frames: usize) -> usize {
+        frames.div_ceil(64)
+    }
+
+    pub fn frames(&self) -> usize {
+        self.frames
+    }
+
+    pub fn free_frames(&self) -> usize {
+        self.free
+    }
+
+    pub fn used_frames(&self) -> usize {
+        self.frames - self.free
+    }
+
+    pub fn refcount(&self, idx: usize) -> u16 {
+        self.refs.get(idx).copied().unwrap_or(0)
+    }
+
+    pub fn share(&mut self, idx: usize) -> bool {
+        if idx >= self.frames || !self.is_used(idx) || self.refs[idx] == u16::MAX {
+            return false;
+        }
+        self.refs[idx] = self.refs[idx].max(1) + 1;
+        true
+    }
+
+    pub fn is_used(&self, idx: usize) -> bool {
+        idx >= self.frames || self.words[idx / 64] & (1 << (idx % 64)) != 0
+    }
+
+    fn set(&mut self, idx: usize, used: bool) {
+        if idx >= self.frames || self.is_used(idx) == used {
+            return;
+        }
+        if used {
+            self.words[idx / 64] |= 1 << (idx % 64);
+            self.free -= 1;
+        } else {
+            self.words[idx / 64] &= !(1 << (idx % 64));
+            self.refs[idx] = 0;
+            self.free += 1;
+        }
+    }
+
+    pub fn mark_free(&mut self, start: usize, count: usize) {
+        for idx in start..start.saturating_add(count) {
+            self.set(idx, false);
+        }
+        self.hint = self.hint.min(start);
+    }
+
+    pub fn mark_used(&mut self, start: usize, count: usize) {
+        for idx in start..start.saturating_add(count) {
+            self.set(idx, true);
+        }
+    }
+
+    pub fn alloc(&mut self, count: usize, align: usize) -> Option<usize> {
+        if count == 0 || align == 0 || count > self.free {
+            return None;
+        }
+        let mut start = self.hint.next_multiple_of(align);
+        while start + count <= self.frames {
+            if self.words[start / 64] == u64::MAX && start.is_multiple_of(64) && align <= 64 {
+                start += 64;
+                continue;
+            }
+            match (start..start + count).find(|&idx| self.is_used(idx)) {
+                Some(used) => start = (used + 1).next_multiple_of(align),
+                None => {
+                    self.mark_used(start, count);
+                    self.refs[start..start + count].fill(1);
+                    if count == 1 {
+                        self.hint = start + 1;
+                    }
+                    return Some(start);
+                }
+            }
+        }
+        None
+    }
+
+    pub fn free(&mut self, start: usize, count: usize) {
+        for idx in start..start.saturating_add(count).min(self.frames) {
+            if self.refs[idx] > 1 {
+                self.refs[idx] -= 1;
+            } else {
+                self.mark_free(idx, 1);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::FrameBitmap;
+
+    #[test]
+    fn allocates_single_frames_from_free_ranges() {
+        let (mut words, mut refs) = ([0u64; 2], [0u16; 128]);
+        let mut bitmap = FrameBitmap::new(&mut words, &mut refs, 128);
+        assert_eq!(bitmap.free_frames(), 0);
+
+        bitmap.mark_free(10, 4);
+        assert_eq!(bitmap.alloc(1, 1), Some(10));
+        assert_eq!(bitmap.alloc(1, 1), Some(11));
+        assert_eq!(bitmap.used_frames(), 126);
+
+        bitmap.free(10, 1);
+        assert_eq!(bitmap.alloc(1, 1), Some(10));
+    }
+
+    #[test]
+    fn allocates_aligned_runs() {
+        let (mut words, mut refs) = ([0u64; 32], [0u16; 2048]);
+        let mut bitmap = FrameBitmap::new(&mut words, &mut refs, 2048);
+        bitmap.mark_free(3, 2000);
+
+        assert_eq!(bitmap.alloc(512, 512), Some(512));
+        assert_eq!(bitmap.alloc(512, 512), Some(1024));
+        assert_eq!(bitmap.alloc(512, 512), None);
+
+        bitmap.free(512, 512);
+        assert_eq!(bitmap.alloc(512, 512), Some(512));
+    }
+
+    #[test]
+    fn freeing_a_huge_run_releases_every_frame() {
+        let (mut words, mut refs) = ([0u64; 16], [0u16; 1024]);
+        let mut bitmap = FrameBitmap::new(&mut words, &mut refs, 1024);
+        bitmap.mark_free(0, 1024);
+
+        let huge = bitmap.alloc(512, 512).expect("huge run");
+        let single = bitmap.alloc(1, 1).expect("frame");
+        assert_eq!(bitmap.free_frames(), 511);
+
+        bitmap.free(huge, 512);
+        assert_eq!(bitmap.free_frames(), 1023);
+        assert!((huge..huge + 512).all(|idx| !bitmap.is_used(idx)));
+        assert!(bitmap.is_used(single));
+        assert_eq!(bitmap.alloc(512, 512), Some(huge));
+    }
+
+    #[test]
+    fn reports_exhaustion() {
+        let (mut words, mut refs) = ([0u64; 1], [0u16; 64]);
+        let mut bitmap = FrameBitmap::new(&mut words, &mut refs, 64);
+        bitmap.mark_free(0, 2);
+        assert!(bitmap.alloc(1, 1).is_some());
+        assert!(bitmap.alloc(1, 1).is_some());
+        assert_eq!(bitmap.alloc(1, 1), None);
+    }
+
+    #[test]
+    fn shared_frames_are_freed_by_their_last_owner() {
+        let (mut words, mut refs) = ([0u64; 1], [0u16; 64]);
+        let mut bitmap = FrameBitmap::new(&mut words, &mut refs, 64);
+        bitmap.mark_free(0, 4);
+
+        let frame = bitmap.alloc(1, 1).expect("frame");
+        assert_eq!(bitmap.refcount(frame), 1);
+        assert!(bitmap.share(frame));
+        assert_eq!(bitmap.refcount(frame), 2);
+
+        bitmap.free(frame, 1);
+        assert!(bitmap.is_used(frame));
+        assert_eq!(bitmap.refcount(frame), 1);
+
+        bitmap.free(frame, 1);
+        assert!(!bitmap.is_used(frame));
+        assert!(!bitmap.share(frame));
+    }
+}
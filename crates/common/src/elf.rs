@@ -1,17 +1,394 @@
+pub const PF_X: u32 = 1;
+pub const PF_W: u32 = 2;
+pub const PF_R: u32 = 4;
+
+pub const ET_DYN: u16 = 3;
+
+pub const PT_LOAD: u32 = 1;
+pub const PT_DYNAMIC: u32 = 2;
+pub const PT_INTERP: u32 = 3;
+pub const PT_TLS: u32 = 7;
+pub const PT_GNU_STACK: u32 = 0x6474_e551;
+pub const PT_GNU_RELRO: u32 = 0x6474_e552;
+
+pub const R_X86_64_NONE: u32 = 0;
+pub const R_X86_64_64: u32 = 1;
+pub const R_X86_64_GLOB_DAT: u32 = 6;
+pub const R_X86_64_JUMP_SLOT: u32 = 7;
+pub const R_X86_64_RELATIVE: u32 = 8;
+pub const R_X86_64_IRELATIVE: u32 = 37;
+
+const DT_NULL: u64 = 0;
+const DT_NEEDED: u64 = 1;
+const DT_PLTRELSZ: u64 = 2;
+const DT_HASH: u64 = 4;
+const DT_STRTAB: u64 = 5;
+const DT_SYMTAB: u64 = 6;
+const DT_RELA: u64 = 7;
+const DT_RELASZ: u64 = 8;
+const DT_RELAENT: u64 = 9;
+const DT_SYMENT: u64 = 11;
+const DT_REL: u64 = 17;
+const DT_PLTREL: u64 = 20;
+const DT_JMPREL: u64 = 23;
+
+const RELA_SIZE: usize = 24;
+const SYM_SIZE: usize = 24;
+const SHN_UNDEF: u16 = 0;
+const SHN_ABS: u16 = 0xfff1;
+const STB_LOCAL: u8 = 0;
+const STB_WEAK: u8 = 2;
+const STT_GNU_IFUNC: u8 = 10;
+
+pub const AT_NULL: u64 = 0;
+pub const AT_PHDR: u64 = 3;
+pub const AT_PHENT: u64 = 4;
+pub const AT_PHNUM: u64 = 5;
+pub const AT_PAGESZ: u64 = 6;
+pub const AT_BASE: u64 = 7;
+pub const AT_ENTRY: u64 = 9;
+pub const AT_RANDOM: u64 = 25;
+pub const AT_EXECFN: u64 = 31;
+
 #[derive(Clone, Copy, Debug)]
 pub struct ProgramHeader {
+    pub kind: u32,
     pub file_offset: usize,
     pub virt_addr: usize,
     pub file_size: usize,
     pub mem_size: usize,
     pub flags: u32,
+    pub align: usize,
+}
+
+/// A store the loader must make for one relocation of an image.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fixup {
+    Write {
+        addr: usize,
+        value: u64,
+    },
+    /// Store what the resolver function at `resolver` returns (`IRELATIVE`
+    /// and GNU ifunc symbols); it has to run in the process itself.
+    Resolve {
+        addr: usize,
+        resolver: usize,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RelocError {
+    Malformed,
+    Unsupported(u32),
+    /// The symbol with this `.dynsym` index is not defined by the image.
+    Undefined(u32),
+}
+
+#[derive(Default)]
+struct Dynamic {
+    rela: Option<usize>,
+    rela_size: usize,
+    rela_ent: Option<usize>,
+    jmprel: Option<usize>,
+    pltrel_size: usize,
+    pltrel: Option<u64>,
+    symtab: Option<usize>,
+    sym_ent: Option<usize>,
+    strtab: Option<usize>,
+    hash: Option<usize>,
+    rel: bool,
 }
 
 #[derive(Clone, Copy, Debug)]
 pub struct ElfImage<'a> {
+    pub kind: u16,
     pub entry: usize,
     pub data: &'a [u8],
-    pub program_headers: [Option<ProgramHeader>; 32],
+    phoff: usize,
+    phentsize: usize,
+    phnum: usize,
+}
+
+impl<'a> ElfImage<'a> {
+    pub fn phentsize(&self) -> usize {
+        self.phentsize
+    }
+
+    pub fn phnum(&self) -> usize {
+        self.phnum
+    }
+
+    /// Where the program header table ends up in memory, for `AT_PHDR`.
+    pub fn phdr_addr(&self) -> Option<usize> {
+        self.program_headers().find_map(|hdr| {
+            let off = self.phoff.checked_sub(hdr.file_offset)?;
+            (off < hdr.file_size).then_some(hdr.virt_addr + off)
+        })
+    }
+
+    /// Every program header, whatever its type.
+    pub fn segments(&self) -> impl Iterator<Item = ProgramHeader> + 'a {
+        let (data, phoff, phentsize) = (self.data, self.phoff, self.phentsize);
+        (0..self.phnum).filter_map(move |i| {
+            let o = phoff + i * phentsize;
+            Some(ProgramHeader {
+                kind: rd32(data, o)?,
+                flags: rd32(data, o + 4)?,
+                file_offset: rd64(data, o + 8)? as usize,
+                virt_addr: rd64(data, o + 16)? as usize,
+                file_size: rd64(data, o + 32)? as usize,
+                mem_size: rd64(data, o + 40)? as usize,
+                align: rd64(data, o + 48)? as usize,
+            })
+        })
+    }
+
+    /// The `PT_LOAD` headers.
+    pub fn program_headers(&self) -> impl Iterator<Item = ProgramHeader> + 'a {
+        self.segments().filter(|hdr| hdr.kind == PT_LOAD)
+    }
+
+    pub fn segment(&self, kind: u32) -> Option<ProgramHeader> {
+        self.segments().find(|hdr| hdr.kind == kind)
+    }
+
+    /// The `PT_INTERP` path without its NUL; empty if the header points
+    /// outside the file.
+    pub fn interpreter(&self) -> Option<&'a [u8]> {
+        let hdr = self.segment(PT_INTERP)?;
+        let bytes = hdr
+            .file_offset
+            .checked_add(hdr.file_size)
+            .and_then(|end| self.data.get(hdr.file_offset..end))
+            .unwrap_or_default();
+        Some(bytes.split(|&b| b == 0).next().unwrap_or_default())
+    }
+
+    /// Where the byte linked at `vaddr` sits in the file.
+    pub fn file_offset(&self, vaddr: usize) -> Option<usize> {
+        self.program_headers().find_map(|hdr| {
+            let off = vaddr.checked_sub(hdr.virt_addr)?;
+            (off < hdr.file_size).then_some(hdr.file_offset + off)
+        })
+    }
+
+    fn dynamic_entries(&self) -> Result<impl Iterator<Item = (u64, usize)> + 'a, RelocError> {
+        let range = match self.segment(PT_DYNAMIC) {
+            Some(hdr) => {
+                let end = hdr
+                    .file_offset
+                    .checked_add(hdr.file_size)
+                    .filter(|&end| end <= self.data.len())
+                    .ok_or(RelocError::Malformed)?;
+                hdr.file_offset..end
+            }
+            None => 0..0,
+        };
+        let data = self.data;
+        Ok(range
+            .step_by(16)
+            .map_while(move |o| Some((rd64(data, o)?, rd64(data, o + 8)? as usize)))
+            .take_while(|&(tag, _)| tag != DT_NULL))
+    }
+
+    fn dynamic(&self) -> Result<Dynamic, RelocError> {
+        let mut dynamic = Dynamic::default();
+        for (tag, val) in self.dynamic_entries()? {
+            match tag {
+                DT_RELA => dynamic.rela = Some(val),
+                DT_RELASZ => dynamic.rela_size = val,
+                DT_RELAENT => dynamic.rela_ent = Some(val),
+                DT_JMPREL => dynamic.jmprel = Some(val),
+                DT_PLTRELSZ => dynamic.pltrel_size = val,
+                DT_PLTREL => dynamic.pltrel = Some(val as u64),
+                DT_SYMTAB => dynamic.symtab = Some(val),
+                DT_SYMENT => dynamic.sym_ent = Some(val),
+                DT_STRTAB => dynamic.strtab = Some(val),
+                DT_HASH => dynamic.hash = Some(val),
+                DT_REL => dynamic.rel = true,
+                _ => {}
+            }
+        }
+        Ok(dynamic)
+    }
+
+    /// The `DT_NEEDED` shared object names, in order.
+    pub fn needed(&self) -> impl Iterator<Item = &'a [u8]> + 'a {
+        let data = self.data;
+        let strtab = self
+            .dynamic()
+            .ok()
+            .and_then(|dynamic| self.file_offset(dynamic.strtab?));
+        self.dynamic_entries()
+            .into_iter()
+            .flatten()
+            .filter(|&(tag, _)| tag == DT_NEEDED)
+            .filter_map(move |(_, name)| string(data, strtab?.checked_add(name)?))
+    }
+
+    /// The address of the global symbol `name` defined by the image, looked
+    /// up through `DT_HASH`, for a load `bias` bytes above the link addresses.
+    pub fn symbol(&self, name: &[u8], bias: usize) -> Option<u64> {
+        let (data, dynamic) = (self.data, self.dynamic().ok()?);
+        let hash = self.file_offset(dynamic.hash?)?;
+        let symtab = self.file_offset(dynamic.symtab?)?;
+        let strtab = self.file_offset(dynamic.strtab?)?;
+        let nbucket = rd32(data, hash)? as usize;
+        let nchain = rd32(data, hash + 4)? as usize;
+        let bucket = elf_hash(name) as usize % nbucket.max(1);
+        let mut index = rd32(data, hash + 8 + bucket * 4)? as usize;
+        for _ in 0..nchain {
+            if index == 0 {
+                break;
+            }
+            let sym = symtab.checked_add(index.checked_mul(SYM_SIZE)?)?;
+            let info = *data.get(sym + 4)?;
+            let shndx = rd16(data, sym + 6)?;
+            let defined = shndx != SHN_UNDEF && info >> 4 != STB_LOCAL;
+            let own_name = strtab.checked_add(rd32(data, sym)? as usize)?;
+            if defined && info & 0xf != STT_GNU_IFUNC && string(data, own_name) == Some(name) {
+                let value = rd64(data, sym + 8)?;
+                return Some(match shndx {
+                    SHN_ABS => value,
+                    _ => value.wrapping_add(bias as u64),
+                });
+            }
+            index = rd32(data, hash + 8 + (nbucket + index) * 4)? as usize;
+        }
+        None
+    }
+
+    /// Decodes the `DT_RELA` and `DT_JMPREL` tables for a load `bias` bytes
+    /// above the link addresses. Symbols resolve against the image's own
+    /// `.dynsym`; undefined weak ones become 0.
+    pub fn relocations(
+        &self,
+        bias: usize,
+    ) -> Result<impl Iterator<Item = Result<Fixup, RelocError>> + 'a, RelocError> {
+        self.relocations_with(bias, |_| None)
+    }
+
+    /// Like [`Self::relocations`], but symbols the image leaves undefined are
+    /// bound by name through `lookup`.
+    pub fn relocations_with<'f, F>(
+        &self,
+        bias: usize,
+        lookup: F,
+    ) -> Result<impl Iterator<Item = Result<Fixup, RelocError>> + 'f, RelocError>
+    where
+        'a: 'f,
+        F: Fn(&[u8]) -> Option<u64> + 'f,
+    {
+        let dynamic = self.dynamic()?;
+        if dynamic.rel
+            || dynamic.rela_ent.is_some_and(|ent| ent != RELA_SIZE)
+            || dynamic.sym_ent.is_some_and(|ent| ent != SYM_SIZE)
+            || (dynamic.jmprel.is_some() && dynamic.pltrel != Some(DT_RELA))
+        {
+            return Err(RelocError::Malformed);
+        }
+        let data = self.data;
+        let table = |vaddr: Option<usize>, len: usize| -> Result<_, RelocError> {
+            let Some(vaddr) = vaddr.filter(|_| len > 0) else {
+                return Ok(0..0);
+            };
+            let start = self.file_offset(vaddr).ok_or(RelocError::Malformed)?;
+            let end = start.checked_add(len).ok_or(RelocError::Malformed)?;
+            if end > data.len() {
+                return Err(RelocError::Malformed);
+            }
+            Ok(start..end)
+        };
+        let tables = [
+            table(dynamic.rela, dynamic.rela_size)?,
+            table(dynamic.jmprel, dynamic.pltrel_size)?,
+        ];
+        let offset = |vaddr: Option<usize>| match vaddr {
+            Some(vaddr) => self
+                .file_offset(vaddr)
+                .map(Some)
+                .ok_or(RelocError::Malformed),
+            None => Ok(None),
+        };
+        let (symtab, strtab) = (offset(dynamic.symtab)?, offset(dynamic.strtab)?);
+        let symbol = move |index: u32| -> Result<(u64, bool), RelocError> {
+            let word = |o: usize| rd64(data, o).ok_or(RelocError::Malformed);
+            let sym = (index as usize)
+                .checked_mul(SYM_SIZE)
+                .and_then(|off| off.checked_add(symtab?))
+                .ok_or(RelocError::Malformed)?;
+            let info = *data.get(sym + 4).ok_or(RelocError::Malformed)?;
+            let shndx = rd16(data, sym + 6).ok_or(RelocError::Malformed)?;
+            let value = word(sym + 8)?;
+            let ifunc = info & 0xf == STT_GNU_IFUNC;
+            if shndx != SHN_UNDEF {
+                let value = match shndx {
+                    SHN_ABS => value,
+                    _ => value.wrapping_add(bias as u64),
+                };
+                return Ok((value, ifunc));
+            }
+            let name =
+                rd32(data, sym).and_then(|name| string(data, strtab?.checked_add(name as usize)?));
+            match name.and_then(&lookup) {
+                Some(value) => Ok((value, false)),
+                None if info >> 4 == STB_WEAK => Ok((0, false)),
+                None => Err(RelocError::Undefined(index)),
+            }
+        };
+        Ok(tables
+            .into_iter()
+            .flat_map(|range| range.step_by(RELA_SIZE))
+            .filter_map(move |o| fixup(data, o, bias, &symbol).transpose()))
+    }
+}
+
+fn fixup(
+    data: &[u8],
+    o: usize,
+    bias: usize,
+    symbol: impl Fn(u32) -> Result<(u64, bool), RelocError>,
+) -> Result<Option<Fixup>, RelocError> {
+    let word = |o: usize| rd64(data, o).ok_or(RelocError::Malformed);
+    let (offset, info, addend) = (word(o)? as usize, word(o + 8)?, word(o + 16)?);
+    let addr = offset.checked_add(bias).ok_or(RelocError::Malformed)?;
+    let (kind, index) = (info as u32, (info >> 32) as u32);
+    let value = match kind {
+        R_X86_64_NONE => return Ok(None),
+        R_X86_64_RELATIVE => addend.wrapping_add(bias as u64),
+        R_X86_64_IRELATIVE => {
+            let resolver = addend.wrapping_add(bias as u64) as usize;
+            return Ok(Some(Fixup::Resolve { addr, resolver }));
+        }
+        R_X86_64_64 => match symbol(index)? {
+            (_, true) => return Err(RelocError::Unsupported(kind)),
+            (value, false) => value.wrapping_add(addend),
+        },
+        R_X86_64_GLOB_DAT | R_X86_64_JUMP_SLOT => match symbol(index)? {
+            (resolver, true) => {
+                let resolver = resolver as usize;
+                return Ok(Some(Fixup::Resolve { addr, resolver }));
+            }
+            (value, false) => value,
+        },
+        _ => return Err(RelocError::Unsupported(kind)),
+    };
+    Ok(Some(Fixup::Write { addr, value }))
+}
+
+/// The System V `DT_HASH` function.
+pub fn elf_hash(name: &[u8]) -> u32 {
+    name.iter().fold(0, |h: u32, &c| {
+        let h = (h << 4).wrapping_add(u32::from(c));
+        let g = h & 0xf000_0000;
+        (h ^ (g >> 24)) & !g
+    })
+}
+
+/// The NUL-terminated string at `o`.
+fn string(b: &[u8], o: usize) -> Option<&[u8]> {
+    let tail = b.get(o..)?;
+    Some(&tail[..tail.iter().position(|&c| c == 0)?])
 }
 
 fn rd16(b: &[u8], o: usize) -> Option<u16> {
@@ -46,37 +423,27 @@ pub fn parse_elf64(image: &[u8]) -> Option<ElfImage<'_>> {
         return None;
     }
 
+    let kind = rd16(image, 16)?;
     let entry = rd64(image, 24)? as usize;
     let phoff = rd64(image, 32)? as usize;
     let phentsize = rd16(image, 54)? as usize;
     let phnum = rd16(image, 56)? as usize;
 
-    let mut headers = [None; 32];
-    let mut seen = 0usize;
-
-    for i in 0..phnum {
-        let o = phoff + i * phentsize;
-        let p_type = rd32(image, o)?;
-        if p_type != 1 {
-            continue;
-        }
-        if seen == headers.len() {
-            break;
-        }
-        headers[seen] = Some(ProgramHeader {
-            flags: rd32(image, o + 4)?,
-            file_offset: rd64(image, o + 8)? as usize,
-            virt_addr: rd64(image, o + 16)? as usize,
-            file_size: rd64(image, o + 32)? as usize,
-            mem_size: rd64(image, o + 40)? as usize,
-        });
-        seen += 1;
+    if phentsize < 56 {
+        return None;
+    }
+    let table_end = phoff.checked_add(phentsize.checked_mul(phnum)?)?;
+    if table_end > image.len() {
+        return None;
     }
 
     Some(ElfImage {
+        kind,
         entry,
         data: image,
-        program_headers: headers,
+        phoff,
+        phentsize,
+        phnum,
     })
 }
 
@@ -84,10 +451,282 @@ pub fn parse_elf64(image: &[u8]) -> Option<ElfImage<'_>> {
 mod tests {
     extern crate std;
 
-    use super::parse_elf64;
+    use super::{
+        ElfImage, Fixup, PF_R, PF_W, PT_DYNAMIC, PT_GNU_STACK, PT_INTERP, PT_LOAD, PT_TLS,
+        R_X86_64_64, R_X86_64_GLOB_DAT, R_X86_64_IRELATIVE, R_X86_64_JUMP_SLOT, R_X86_64_NONE,
+        R_X86_64_RELATIVE, RelocError, elf_hash, parse_elf64,
+    };
+    use alloc::vec::Vec;
+
+    const DYNAMIC: usize = 0x200;
+    const RELA: usize = 0x300;
+    const JMPREL: usize = 0x400;
+    const SYMTAB: usize = 0x500;
+    const STRTAB: usize = 0xa00;
+    const HASH: usize = 0xb00;
+    const STRINGS: &[u8] = b"\0librt.so\0rt_write\0weak\0rt_exit\0ifunc\0";
+    const BIAS: usize = 0x5555_0000_0000;
+
+    fn put(image: &mut [u8], o: usize, bytes: &[u8]) {
+        image[o..o + bytes.len()].copy_from_slice(bytes);
+    }
+
+    /// An image whose one `PT_LOAD` maps the whole file at address 0, so link
+    /// addresses and file offsets agree, followed by `extra` headers.
+    fn image_with(extra: &[(u32, usize, usize, u32)]) -> Vec<u8> {
+        let mut image = std::vec![0u8; 0x1000];
+        put(&mut image, 0, b"\x7fELF\x02\x01");
+        put(&mut image, 16, &3u16.to_le_bytes());
+        put(&mut image, 32, &64u64.to_le_bytes());
+        put(&mut image, 54, &56u16.to_le_bytes());
+        put(&mut image, 56, &(1 + extra.len() as u16).to_le_bytes());
+        let load = (PT_LOAD, 0, 0x1000, PF_R | PF_W);
+        for (i, &(kind, offset, size, flags)) in [load].iter().chain(extra).enumerate() {
+            let o = 64 + i * 56;
+            put(&mut image, o, &kind.to_le_bytes());
+            put(&mut image, o + 4, &flags.to_le_bytes());
+            put(&mut image, o + 8, &(offset as u64).to_le_bytes());
+            put(&mut image, o + 16, &(offset as u64).to_le_bytes());
+            put(&mut image, o + 32, &(size as u64).to_le_bytes());
+            put(&mut image, o + 40, &(size as u64 * 2).to_le_bytes());
+            put(&mut image, o + 48, &16u64.to_le_bytes());
+        }
+        image
+    }
+
+    fn dynamic_image(rela: &[(u64, u32, u32, u64)], plt: &[(u64, u32, u32, u64)]) -> Vec<u8> {
+        let mut image = image_with(&[(PT_DYNAMIC, DYNAMIC, 0x100, PF_R)]);
+        let tags: [(u64, usize); 11] = [
+            (7, RELA),
+            (8, rela.len() * 24),
+            (9, 24),
+            (23, JMPREL),
+            (2, plt.len() * 24),
+            (20, 7),
+            (6, SYMTAB),
+            (11, 24),
+            (5, STRTAB),
+            (4, HASH),
+            (1, 1),
+        ];
+        for (i, (tag, val)) in tags.iter().enumerate() {
+            put(&mut image, DYNAMIC + i * 16, &tag.to_le_bytes());
+            put(
+                &mut image,
+                DYNAMIC + i * 16 + 8,
+                &(*val as u64).to_le_bytes(),
+            );
+        }
+        for (base, table) in [(RELA, rela), (JMPREL, plt)] {
+            for (i, &(offset, kind, sym, addend)) in table.iter().enumerate() {
+                let info = (u64::from(sym) << 32) | u64::from(kind);
+                put(&mut image, base + i * 24, &offset.to_le_bytes());
+                put(&mut image, base + i * 24 + 8, &info.to_le_bytes());
+                put(&mut image, base + i * 24 + 16, &addend.to_le_bytes());
+            }
+        }
+        // Symbols: 1 defined, 2 weak undefined, 3 undefined, 4 ifunc.
+        for (index, name, info, shndx, value) in [
+            (1, 10u32, 0x12u8, 5u16, 0x700u64),
+            (2, 19, 0x22, 0, 0),
+            (3, 24, 0x12, 0, 0),
+            (4, 32, 0x1a, 5, 0x800),
+        ] {
+            let o = SYMTAB + index * 24;
+            put(&mut image, o, &name.to_le_bytes());
+            image[o + 4] = info;
+            put(&mut image, o + 6, &shndx.to_le_bytes());
+            put(&mut image, o + 8, &value.to_le_bytes());
+        }
+        put(&mut image, STRTAB, STRINGS);
+        // Three buckets; "rt_exit" and "ifunc" share the last one.
+        for (i, word) in [3u32, 5, 1, 2, 3, 0, 0, 0, 4, 0].iter().enumerate() {
+            put(&mut image, HASH + i * 4, &word.to_le_bytes());
+        }
+        image
+    }
+
+    fn fixups(image: &ElfImage<'_>) -> Result<Vec<Fixup>, RelocError> {
+        image.relocations(BIAS)?.collect()
+    }
 
     #[test]
     fn rejects_non_elf() {
         assert!(parse_elf64(b"nope").is_none());
     }
+
+    #[test]
+    fn yields_every_load_header() {
+        let phnum = 40usize;
+        let mut image = std::vec![0u8; 64 + phnum * 56];
+        image[..6].copy_from_slice(b"\x7fELF\x02\x01");
+        image[32..40].copy_from_slice(&64u64.to_le_bytes());
+        image[54..56].copy_from_slice(&56u16.to_le_bytes());
+        image[56..58].copy_from_slice(&(phnum as u16).to_le_bytes());
+        for i in 0..phnum {
+            let o = 64 + i * 56;
+            let p_type: u32 = if i % 4 == 3 { 2 } else { 1 };
+            image[o..o + 4].copy_from_slice(&p_type.to_le_bytes());
+            image[o + 16..o + 24].copy_from_slice(&((i as u64) * 0x1000).to_le_bytes());
+        }
+
+        let elf = parse_elf64(&image).expect("elf");
+        assert_eq!(elf.program_headers().count(), 30);
+        assert_eq!(
+            elf.program_headers().last().expect("last").virt_addr,
+            38 * 0x1000
+        );
+    }
+
+    #[test]
+    fn locates_the_program_header_table_in_memory() {
+        let mut image = std::vec![0u8; 64 + 2 * 56];
+        image[..6].copy_from_slice(b"\x7fELF\x02\x01");
+        image[32..40].copy_from_slice(&64u64.to_le_bytes());
+        image[54..56].copy_from_slice(&56u16.to_le_bytes());
+        image[56..58].copy_from_slice(&2u16.to_le_bytes());
+        for (i, (offset, vaddr)) in [(0x1000u64, 0x20_1000u64), (0, 0x20_0000)]
+            .iter()
+            .enumerate()
+        {
+            let o = 64 + i * 56;
+            image[o..o + 4].copy_from_slice(&1u32.to_le_bytes());
+            image[o + 8..o + 16].copy_from_slice(&offset.to_le_bytes());
+            image[o + 16..o + 24].copy_from_slice(&vaddr.to_le_bytes());
+            image[o + 32..o + 40].copy_from_slice(&0x1000u64.to_le_bytes());
+        }
+
+        let elf = parse_elf64(&image).expect("elf");
+        assert_eq!(elf.phdr_addr(), Some(0x20_0040));
+        assert_eq!((elf.phnum(), elf.phentsize()), (2, 56));
+    }
+
+    #[test]
+    fn decodes_rela_and_plt_relocations() {
+        let image = dynamic_image(
+            &[
+                (0x900, R_X86_64_RELATIVE, 0, 0x40),
+                (0x908, R_X86_64_64, 1, 8),
+                (0x910, R_X86_64_GLOB_DAT, 2, 0),
+                (0x918, R_X86_64_NONE, 0, 0),
+                (0x920, R_X86_64_IRELATIVE, 0, 0x600),
+            ],
+            &[
+                (0x928, R_X86_64_JUMP_SLOT, 1, 0),
+                (0x930, R_X86_64_JUMP_SLOT, 4, 0),
+            ],
+        );
+        let elf = parse_elf64(&image).expect("elf");
+        let base = BIAS as u64;
+        assert_eq!(
+            fixups(&elf),
+            Ok(std::vec![
+                Fixup::Write {
+                    addr: BIAS + 0x900,
+                    value: base + 0x40
+                },
+                Fixup::Write {
+                    addr: BIAS + 0x908,
+                    value: base + 0x708
+                },
+                Fixup::Write {
+                    addr: BIAS + 0x910,
+                    value: 0
+                },
+                Fixup::Resolve {
+                    addr: BIAS + 0x920,
+                    resolver: BIAS + 0x600
+                },
+                Fixup::Write {
+                    addr: BIAS + 0x928,
+                    value: base + 0x700
+                },
+                Fixup::Resolve {
+                    addr: BIAS + 0x930,
+                    resolver: BIAS + 0x800
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn rejects_unsupported_and_undefined_relocations() {
+        let tpoff = dynamic_image(&[(0x900, 18, 1, 0)], &[]);
+        let elf = parse_elf64(&tpoff).expect("elf");
+        assert_eq!(fixups(&elf), Err(RelocError::Unsupported(18)));
+
+        let undefined = dynamic_image(&[], &[(0x900, R_X86_64_JUMP_SLOT, 3, 0)]);
+        let elf = parse_elf64(&undefined).expect("elf");
+        assert_eq!(fixups(&elf), Err(RelocError::Undefined(3)));
+
+        let mut rel = dynamic_image(&[], &[]);
+        put(&mut rel, DYNAMIC + 11 * 16, &17u64.to_le_bytes());
+        let elf = parse_elf64(&rel).expect("elf");
+        assert_eq!(fixups(&elf).err(), Some(RelocError::Malformed));
+    }
+
+    #[test]
+    fn binds_undefined_symbols_through_a_lookup() {
+        let image = dynamic_image(
+            &[(0x900, R_X86_64_64, 3, 4)],
+            &[(0x908, R_X86_64_JUMP_SLOT, 2, 0)],
+        );
+        let elf = parse_elf64(&image).expect("elf");
+        let lookup = |name: &[u8]| match name {
+            b"rt_exit" => Some(0x7000),
+            b"weak" => Some(0x8000),
+            _ => None,
+        };
+        let bound: Result<Vec<_>, _> = elf
+            .relocations_with(BIAS, lookup)
+            .expect("dynamic")
+            .collect();
+        assert_eq!(
+            bound,
+            Ok(std::vec![
+                Fixup::Write {
+                    addr: BIAS + 0x900,
+                    value: 0x7004
+                },
+                Fixup::Write {
+                    addr: BIAS + 0x908,
+                    value: 0x8000
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn finds_needed_objects_and_exported_symbols() {
+        let image = dynamic_image(&[], &[]);
+        let elf = parse_elf64(&image).expect("elf");
+        assert_eq!(elf_hash(b"printf"), 0x0779_05a6);
+        assert!(elf.needed().eq([&b"librt.so"[..]]));
+        assert_eq!(elf.symbol(b"rt_write", BIAS), Some(BIAS as u64 + 0x700));
+        assert_eq!(elf.symbol(b"rt_exit", BIAS), None);
+        assert_eq!(elf.symbol(b"ifunc", BIAS), None);
+        assert_eq!(elf.symbol(b"printf", BIAS), None);
+    }
+
+    #[test]
+    fn exposes_interp_tls_and_stack_headers() {
+        let mut image = image_with(&[
+            (PT_INTERP, 0x200, 12, PF_R),
+            (PT_TLS, 0x300, 0x10, PF_R),
+            (PT_GNU_STACK, 0, 0, PF_R | PF_W),
+        ]);
+        put(&mut image, 0x200, b"/lib/ld.so\0\0");
+        let elf = parse_elf64(&image).expect("elf");
+        assert_eq!(elf.kind, 3);
+        assert_eq!(elf.program_headers().count(), 1);
+        assert_eq!(elf.interpreter(), Some(&b"/lib/ld.so"[..]));
+        let tls = elf.segment(PT_TLS).expect("tls");
+        assert_eq!((tls.file_size, tls.mem_size, tls.align), (0x10, 0x20, 16));
+        assert_eq!(
+            elf.segment(PT_GNU_STACK).map(|hdr| hdr.flags),
+            Some(PF_R | PF_W)
+        );
+        assert_eq!(elf.file_offset(0x234), Some(0x234));
+        assert!(fixups(&elf).expect("no dynamic").is_empty());
+    }
 }
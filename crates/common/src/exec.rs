@@ -0,0 +1,147 @@
+use crate::elf::{AT_EXECFN, AT_NULL, AT_RANDOM};
+use alloc::vec::Vec;
+
+/// Upper bound on the bytes of argument and environment strings passed to `execve`.
+pub const ARG_MAX: usize = 128 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StackError {
+    TooLarge,
+    OutOfMemory,
+}
+
+/// The bytes of a System V initial process stack and where they start.
+#[derive(Debug)]
+pub struct InitialStack {
+    pub rsp: usize,
+    pub bytes: Vec<u8>,
+}
+
+/// Lays out argc, argv, envp and the auxiliary vector below `top`, followed
+/// by the strings, the `execfn` path and the 16 `AT_RANDOM` bytes. `auxv` must
+/// not contain `AT_RANDOM`, `AT_EXECFN` or `AT_NULL`; those are appended here.
+/// `rsp` is 16-byte aligned.
+pub fn initial_stack(
+    top: usize,
+    argv: &[Vec<u8>],
+    envp: &[Vec<u8>],
+    execfn: &[u8],
+    auxv: &[(u64, u64)],
+    random: [u8; 16],
+) -> Result<InitialStack, StackError> {
+    let strings: usize =
+        argv.iter().chain(envp).map(|s| s.len() + 1).sum::<usize>() + execfn.len() + 1;
+    if strings > ARG_MAX {
+        return Err(StackError::TooLarge);
+    }
+    let random_at = top - random.len();
+    let strings_at = random_at - strings;
+    let words = 1 + (argv.len() + 1) + (envp.len() + 1) + 2 * (auxv.len() + 3);
+    let rsp = (strings_at - words * 8) & !0xf;
+
+    let mut bytes = Vec::new();
+    bytes
+        .try_reserve_exact(top - rsp)
+        .map_err(|_| StackError::OutOfMemory)?;
+    bytes.resize(top - rsp, 0);
+
+    let mut words_out = Vec::new();
+    words_out
+        .try_reserve_exact(words)
+        .map_err(|_| StackError::OutOfMemory)?;
+    words_out.push(argv.len() as u64);
+    let mut cursor = strings_at;
+    for list in [argv, envp] {
+        for s in list {
+            let at = cursor - rsp;
+            bytes[at..at + s.len()].copy_from_slice(s);
+            words_out.push(cursor as u64);
+            cursor += s.len() + 1;
+        }
+        words_out.push(0);
+    }
+    let execfn_at = cursor - rsp;
+    bytes[execfn_at..execfn_at + execfn.len()].copy_from_slice(execfn);
+    for &(key, value) in auxv {
+        words_out.extend([key, value]);
+    }
+    words_out.extend([AT_RANDOM, random_at as u64, AT_EXECFN, cursor as u64]);
+    words_out.extend([AT_NULL, 0]);
+
+    for (i, word) in words_out.iter().enumerate() {
+        bytes[i * 8..i * 8 + 8].copy_from_slice(&word.to_le_bytes());
+    }
+    bytes[random_at - rsp..].copy_from_slice(&random);
+    Ok(InitialStack { rsp, bytes })
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::{ARG_MAX, StackError, initial_stack};
+    use crate::elf::{AT_EXECFN, AT_NULL, AT_PAGESZ, AT_RANDOM};
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    const TOP: usize = 0x7fff_f000;
+
+    fn word(stack: &[u8], rsp: usize, addr: usize) -> u64 {
+        let at = addr - rsp;
+        u64::from_le_bytes(stack[at..at + 8].try_into().expect("word"))
+    }
+
+    fn string(stack: &[u8], rsp: usize, addr: u64) -> &[u8] {
+        let rest = &stack[addr as usize - rsp..];
+        &rest[..rest.iter().position(|&b| b == 0).expect("nul")]
+    }
+
+    #[test]
+    fn lays_out_argc_argv_envp_and_auxv() {
+        let argv = [b"prog".to_vec(), b"-v".to_vec()];
+        let envp = [b"HOME=/".to_vec()];
+        let random = [7u8; 16];
+        let stack = initial_stack(
+            TOP,
+            &argv,
+            &envp,
+            b"/bin/prog",
+            &[(AT_PAGESZ, 4096)],
+            random,
+        )
+        .expect("stack");
+        let (rsp, bytes) = (stack.rsp, &stack.bytes);
+        assert_eq!(rsp % 16, 0);
+        assert_eq!(rsp + bytes.len(), TOP);
+
+        assert_eq!(word(bytes, rsp, rsp), 2);
+        assert_eq!(string(bytes, rsp, word(bytes, rsp, rsp + 8)), b"prog");
+        assert_eq!(string(bytes, rsp, word(bytes, rsp, rsp + 16)), b"-v");
+        assert_eq!(word(bytes, rsp, rsp + 24), 0);
+        assert_eq!(string(bytes, rsp, word(bytes, rsp, rsp + 32)), b"HOME=/");
+        assert_eq!(word(bytes, rsp, rsp + 40), 0);
+
+        let auxv: Vec<(u64, u64)> = (0..4)
+            .map(|i| {
+                let at = rsp + 48 + i * 16;
+                (word(bytes, rsp, at), word(bytes, rsp, at + 8))
+            })
+            .collect();
+        assert_eq!(auxv[0], (AT_PAGESZ, 4096));
+        assert_eq!(auxv[1].0, AT_RANDOM);
+        assert_eq!(auxv[2].0, AT_EXECFN);
+        assert_eq!(string(bytes, rsp, auxv[2].1), b"/bin/prog");
+        assert_eq!(auxv[3], (AT_NULL, 0));
+        let random_at = auxv[1].1 as usize - rsp;
+        assert_eq!(&bytes[random_at..random_at + 16], &random);
+    }
+
+    #[test]
+    fn refuses_oversized_arguments() {
+        let huge = vec![vec![b'x'; ARG_MAX]];
+        assert_eq!(
+            initial_stack(TOP, &huge, &[], b"", &[], [0; 16]).map(|s| s.rsp),
+            Err(StackError::TooLarge)
+        );
+    }
+}
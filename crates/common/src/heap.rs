@@ -0,0 +1,178 @@
+use core::alloc::Layout;
+use core::ptr::{self, NonNull};
+
+const MIN_BLOCK: usize = core::mem::size_of::<FreeBlock>();
+
+struct FreeBlock {
+    size: usize,
+    next: *mut FreeBlock,
+}
+
+pub struct LinkedListHeap {
+    head: *mut FreeBlock,
+    total: usize,
+    free: usize,
+}
+
+unsafe impl Send for LinkedListHeap {}
+
+fn block_size(layout: Layout) -> (usize, usize) {
+    let size = layout.size().max(MIN_BLOCK).next_multiple_of(MIN_BLOCK);
+    let align = layout.align().max(MIN_BLOCK);
+    (size, align)
+}
+
+impl LinkedListHeap {
+    pub const fn new() -> Self {
+        Self {
+            head: ptr::null_mut(),
+            total: 0,
+            free: 0,
+        }
+    }
+
+    pub fn total_bytes(&self) -> usize {
+        self.total
+    }
+
+    pub fn free_bytes(&self) -> usize {
+        self.free
+    }
+
+    /// # Safety
+    /// `start..start + size` must be writable memory owned by nobody else for
+    /// as long as the heap is alive.
+    pub unsafe fn add_region(&mut self, start: usize, size: usize) {
+        let aligned = start.next_multiple_of(MIN_BLOCK);
+        let end = (start + size) & !(MIN_BLOCK - 1);
+        if end <= aligned || end - aligned < MIN_BLOCK {
+            return;
+        }
+        self.total += end - aligned;
+        unsafe { self.insert(aligned, end - aligned) };
+    }
+
+    pub fn alloc(&mut self, layout: Layout) -> Option<NonNull<u8>> {
+        let (size, align) = block_size(layout);
+        let mut prev: *mut FreeBlock = ptr::null_mut();
+        let mut cur = self.head;
+
+        while !cur.is_null() {
+            let addr = cur as usize;
+            let (block_len, next) = unsafe { ((*cur).size, (*cur).next) };
+            let block_end = addr + block_len;
+            let start = addr.next_multiple_of(align);
+
+            if let Some(end) = start.checked_add(size)
+                && end <= block_end
+            {
+                if prev.is_null() {
+                    self.head = next;
+                } else {
+                    unsafe { (*prev).next = next };
+                }
+                self.free -= block_len;
+                unsafe {
+                    if start > addr {
+                        self.insert(addr, start - addr);
+                    }
+                    if block_end > end {
+                        self.insert(end, block_end - end);
+                    }
+                }
+                return NonNull::new(start as *mut u8);
+            }
+
+            prev = cur;
+            cur = next;
+        }
+        None
+    }
+
+    /// # Safety
+    /// `ptr` must come from [`LinkedListHeap::alloc`] on this heap with the
+    /// same `layout`.
+    pub unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        let (size, _) = block_size(layout);
+        unsafe { self.insert(ptr as usize, size) };
+    }
+
+    unsafe fn insert(&mut self, addr: usize, size: usize) {
+        self.free += size;
+
+        let mut prev: *mut FreeBlock = ptr::null_mut();
+        let mut cur = self.head;
+        while !cur.is_null() && (cur as usize) < addr {
+            prev = cur;
+            cur = unsafe { (*cur).next };
+        }
+
+        let block = addr as *mut FreeBlock;
+        unsafe {
+            block.write(FreeBlock { size, next: cur });
+            if !cur.is_null() && addr + size == cur as usize {
+                (*block).size += (*cur).size;
+                (*block).next = (*cur).next;
+            }
+            if prev.is_null() {
+                self.head = block;
+            } else if prev as usize + (*prev).size == addr {
+                (*prev).size += (*block).size;
+                (*prev).next = (*block).next;
+            } else {
+                (*prev).next = block;
+            }
+        }
+    }
+}
+
+impl Default for LinkedListHeap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::LinkedListHeap;
+    use core::alloc::Layout;
+
+    #[repr(align(4096))]
+    struct Arena([u8; 4096]);
+
+    #[test]
+    fn allocations_do_not_overlap_and_coalesce_on_free() {
+        let mut arena = std::boxed::Box::new(Arena([0; 4096]));
+        let mut heap = LinkedListHeap::new();
+        unsafe { heap.add_region(arena.0.as_mut_ptr() as usize, 4096) };
+        assert_eq!(heap.free_bytes(), 4096);
+
+        let small = Layout::from_size_align(24, 8).unwrap();
+        let aligned = Layout::from_size_align(64, 256).unwrap();
+        let a = heap.alloc(small).expect("a");
+        let b = heap.alloc(aligned).expect("b");
+        assert_eq!(b.as_ptr() as usize % 256, 0);
+        assert!(a.as_ptr() as usize + 24 <= b.as_ptr() as usize);
+
+        unsafe {
+            heap.dealloc(a.as_ptr(), small);
+            heap.dealloc(b.as_ptr(), aligned);
+        }
+        assert_eq!(heap.free_bytes(), 4096);
+
+        let whole = Layout::from_size_align(4096, 16).unwrap();
+        assert!(heap.alloc(whole).is_some());
+    }
+
+    #[test]
+    fn reports_exhaustion() {
+        let mut arena = std::boxed::Box::new(Arena([0; 4096]));
+        let mut heap = LinkedListHeap::new();
+        unsafe { heap.add_region(arena.0.as_mut_ptr() as usize, 4096) };
+
+        let big = Layout::from_size_align(4097, 16).unwrap();
+        assert!(heap.alloc(big).is_none());
+    }
+}
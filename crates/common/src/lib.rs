@@ -1,7 +1,20 @@
 #![no_std]
 
+#[cfg(feature = "alloc")]
+extern crate alloc;
+
+pub mod acpi;
+pub mod bitmap;
 pub mod elf;
+pub mod heap;
+pub mod sync;
 pub mod syscall;
+pub mod time;
 pub mod ustar;
 
+#[cfg(feature = "alloc")]
+pub mod exec;
+#[cfg(feature = "alloc")]
 pub mod process;
+#[cfg(feature = "alloc")]
+pub mod vma;
@@ -1,45 +1,344 @@
 #![allow(clippy::module_name_repetitions)]
 
-pub const PROCESS_FD_CAPACITY: usize = 8;
+use crate::vma::MemoryMap;
+use alloc::vec::Vec;
+use core::alloc::Layout;
+
 pub const FD_NONE: u64 = u64::MAX;
+pub const USER_RFLAGS: usize = 0x202;
+pub const USER_DS: usize = 0x18 | 3;
+pub const USER_CS: usize = 0x20 | 3;
+pub const KERNEL_STACK_SIZE: usize = 16 * 1024;
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
 pub struct ProcessContext {
+    pub rax: usize,
+    pub rbx: usize,
+    pub rcx: usize,
+    pub rdx: usize,
+    pub rsi: usize,
+    pub rdi: usize,
+    pub rbp: usize,
+    pub r8: usize,
+    pub r9: usize,
+    pub r10: usize,
+    pub r11: usize,
+    pub r12: usize,
+    pub r13: usize,
+    pub r14: usize,
+    pub r15: usize,
     pub rip: usize,
     pub rsp: usize,
-    pub rax: usize,
+    pub rflags: usize,
+    pub cs: usize,
+    pub ss: usize,
+    pub ds: usize,
+    pub es: usize,
+    pub fs: usize,
+    pub gs: usize,
+    /// The `FS_BASE` thread pointer used for TLS.
+    pub fs_base: usize,
 }
 
 impl ProcessContext {
     pub const fn new(rip: usize, rsp: usize) -> Self {
-        Self { rip, rsp, rax: 0 }
+        Self {
+            rax: 0,
+            rbx: 0,
+            rcx: 0,
+            rdx: 0,
+            rsi: 0,
+            rdi: 0,
+            rbp: 0,
+            r8: 0,
+            r9: 0,
+            r10: 0,
+            r11: 0,
+            r12: 0,
+            r13: 0,
+            r14: 0,
+            r15: 0,
+            rip,
+            rsp,
+            rflags: USER_RFLAGS,
+            cs: USER_CS,
+            ss: USER_DS,
+            ds: USER_DS,
+            es: USER_DS,
+            fs: 0,
+            gs: 0,
+            fs_base: 0,
+        }
     }
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlockReason {
+    WaitChild(Option<u64>),
+    Stdin,
+    /// `futex` wait on this user address; only `wake_futex` ends it.
+    Futex(usize),
+    /// `nanosleep` until the monotonic clock reads this many nanoseconds.
+    Sleep(u64),
+    /// A new thread held back until `clone` has written out its tid.
+    Starting,
+}
+
+/// For a [`Process`], `Runnable` until its last thread exits; processes are
+/// never `Blocked`, their threads are.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProcessState {
+    Runnable,
+    Blocked(BlockReason),
+    Zombie(i32),
+}
+
+pub struct KernelStack {
+    top: usize,
+    release: fn(usize),
+}
+
+const HEAP_STACK_LAYOUT: Layout = match Layout::from_size_align(KERNEL_STACK_SIZE, 16) {
+    Ok(layout) => layout,
+    Err(_) => panic!("bad kernel stack layout"),
+};
+
+fn release_heap_stack(top: usize) {
+    unsafe { alloc::alloc::dealloc((top - KERNEL_STACK_SIZE) as *mut u8, HEAP_STACK_LAYOUT) };
+}
+
+impl KernelStack {
+    pub fn new() -> Result<Self, ProcessError> {
+        let base = unsafe { alloc::alloc::alloc_zeroed(HEAP_STACK_LAYOUT) };
+        if base.is_null() {
+            return Err(ProcessError::OutOfMemory);
+        }
+        Ok(Self {
+            top: base as usize + KERNEL_STACK_SIZE,
+            release: release_heap_stack,
+        })
+    }
+
+    /// # Safety
+    /// `top` must be the end of a `KERNEL_STACK_SIZE` stack that stays usable
+    /// until `release(top)` runs when this value is dropped.
+    pub unsafe fn from_raw(top: usize, release: fn(usize)) -> Self {
+        Self { top, release }
+    }
+
+    pub fn top(&self) -> usize {
+        self.top
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        (self.release)(self.top);
+    }
+}
+
+impl core::fmt::Debug for KernelStack {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "KernelStack(top={:#x})", self.top())
+    }
+}
+
+pub struct AddressSpace {
+    root: usize,
+    release: fn(usize),
+}
+
+impl AddressSpace {
+    /// # Safety
+    /// `root` must be a page-table root that nothing else frees; `release(root)`
+    /// runs when this value is dropped, so it must not still be active then.
+    pub unsafe fn from_raw(root: usize, release: fn(usize)) -> Self {
+        Self { root, release }
+    }
+
+    pub fn root(&self) -> usize {
+        self.root
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        (self.release)(self.root);
+    }
+}
+
+impl core::fmt::Debug for AddressSpace {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "AddressSpace(root={:#x})", self.root)
+    }
+}
+
+#[derive(Clone, Copy)]
+#[repr(C, align(64))]
+struct FpuChunk([u8; 64]);
+
+/// A thread's saved x87/SSE/AVX registers, in the layout `xsave` (or
+/// `fxsave`) writes; 64-byte aligned as `xsave` requires.
+#[derive(Default)]
+pub struct FpuArea {
+    chunks: Vec<FpuChunk>,
+}
+
+impl FpuArea {
+    pub const fn empty() -> Self {
+        Self { chunks: Vec::new() }
+    }
+
+    /// An area holding `bytes`, padded with zeros to a multiple of 64.
+    pub fn new(bytes: &[u8]) -> Result<Self, ProcessError> {
+        let mut chunks = Vec::new();
+        chunks
+            .try_reserve_exact(bytes.len().div_ceil(64))
+            .map_err(|_| ProcessError::OutOfMemory)?;
+        chunks.extend(bytes.chunks(64).map(|part| {
+            let mut chunk = FpuChunk([0; 64]);
+            chunk.0[..part.len()].copy_from_slice(part);
+            chunk
+        }));
+        Ok(Self { chunks })
+    }
+
+    pub fn try_clone(&self) -> Result<Self, ProcessError> {
+        Self::new(self.as_bytes())
+    }
+
+    pub fn len(&self) -> usize {
+        self.chunks.len() * 64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chunks.is_empty()
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.chunks.as_ptr().cast(), self.len()) }
+    }
+
+    pub fn as_mut_ptr(&mut self) -> *mut u8 {
+        self.chunks.as_mut_ptr().cast()
+    }
+}
+
+fn no_fpu_state() -> Result<FpuArea, ProcessError> {
+    Ok(FpuArea::empty())
+}
+
+impl core::fmt::Debug for FpuArea {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "FpuArea(len={})", self.len())
+    }
+}
+
+/// What the threads of a process share.
+#[derive(Debug)]
 pub struct Process {
     pub pid: u64,
-    pub pagemap: usize,
-    pub fds: [u64; PROCESS_FD_CAPACITY],
-    pub fd_offsets: [usize; PROCESS_FD_CAPACITY],
+    pub parent: Option<u64>,
+    pub state: ProcessState,
+    pub address_space: Option<AddressSpace>,
+    pub memory: MemoryMap,
+    pub fds: Vec<u64>,
+    pub fd_offsets: Vec<usize>,
+}
+
+/// What the scheduler runs. A process's first thread has `tid == pid`.
+#[derive(Debug)]
+pub struct Thread {
+    pub tid: u64,
+    pub pid: u64,
+    /// `Zombie` once exited, until the scheduler has switched off its stack.
+    pub state: ProcessState,
     pub context: ProcessContext,
+    pub kernel_stack: KernelStack,
+    pub kernel_rsp: usize,
+    /// FPU registers while the thread is off its CPU.
+    pub fpu: FpuArea,
+    /// User address zeroed and futex-woken when the thread exits
+    /// (`CLONE_CHILD_CLEARTID`), or 0.
+    pub clear_child_tid: usize,
+    /// The CPU executing on this thread's kernel stack, if any.
+    pub cpu: Option<usize>,
 }
 
-impl Process {
-    pub const fn new(pid: u64, entry: usize) -> Self {
-        let mut fds = [FD_NONE; PROCESS_FD_CAPACITY];
-        fds[0] = 0;
-        fds[1] = 1;
-        fds[2] = 2;
+impl Thread {
+    fn new(
+        tid: u64,
+        pid: u64,
+        context: ProcessContext,
+        kernel_stack: KernelStack,
+        fpu: FpuArea,
+    ) -> Self {
         Self {
+            tid,
             pid,
-            pagemap: pid as usize * 0x1000,
-            fds,
-            fd_offsets: [0; PROCESS_FD_CAPACITY],
-            context: ProcessContext::new(entry, 0),
+            state: ProcessState::Runnable,
+            context,
+            kernel_stack,
+            kernel_rsp: 0,
+            fpu,
+            clear_child_tid: 0,
+            cpu: None,
         }
     }
 
+    pub fn is_live(&self) -> bool {
+        !matches!(self.state, ProcessState::Zombie(_))
+    }
+}
+
+fn try_vec<T: Clone>(items: &[T]) -> Result<Vec<T>, ProcessError> {
+    let mut v = Vec::new();
+    v.try_reserve_exact(items.len())
+        .map_err(|_| ProcessError::OutOfMemory)?;
+    v.extend_from_slice(items);
+    Ok(v)
+}
+
+impl Process {
+    pub fn new(
+        pid: u64,
+        address_space: AddressSpace,
+        memory: MemoryMap,
+    ) -> Result<Self, ProcessError> {
+        Ok(Self {
+            pid,
+            parent: None,
+            state: ProcessState::Runnable,
+            address_space: Some(address_space),
+            memory,
+            fds: try_vec(&[0, 1, 2])?,
+            fd_offsets: try_vec(&[0; 3])?,
+        })
+    }
+
+    pub fn try_clone(&self, address_space: AddressSpace) -> Result<Self, ProcessError> {
+        Ok(Self {
+            pid: self.pid,
+            parent: self.parent,
+            state: self.state,
+            address_space: Some(address_space),
+            memory: self
+                .memory
+                .try_clone()
+                .map_err(|_| ProcessError::OutOfMemory)?,
+            fds: try_vec(&self.fds)?,
+            fd_offsets: try_vec(&self.fd_offsets)?,
+        })
+    }
+
+    pub fn pagemap(&self) -> Option<usize> {
+        self.address_space.as_ref().map(AddressSpace::root)
+    }
+
+    pub fn is_live(&self) -> bool {
+        !matches!(self.state, ProcessState::Zombie(_))
+    }
+
     pub fn resolve_fd(&self, fd: u64) -> Option<(u64, usize)> {
         let idx = usize::try_from(fd).ok()?;
         let handle = *self.fds.get(idx)?;
@@ -56,90 +355,403 @@ impl Process {
         Some(())
     }
 
-    pub fn install_fd(&mut self, handle: u64) -> Option<u64> {
-        for i in 3..PROCESS_FD_CAPACITY {
+    pub fn install_fd(&mut self, handle: u64) -> Result<u64, ProcessError> {
+        for i in 3..self.fds.len() {
             if self.fds[i] == FD_NONE {
                 self.fds[i] = handle;
                 self.fd_offsets[i] = 0;
-                return Some(i as u64);
+                return Ok(i as u64);
             }
         }
-        None
+        self.fds
+            .try_reserve(1)
+            .and_then(|_| self.fd_offsets.try_reserve(1))
+            .map_err(|_| ProcessError::OutOfMemory)?;
+        self.fds.push(handle);
+        self.fd_offsets.push(0);
+        Ok(self.fds.len() as u64 - 1)
     }
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum ProcessError {
-    StackFull,
-    StackEmpty,
+    OutOfMemory,
+    NoCurrent,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WaitResult {
+    Reaped { pid: u64, code: i32 },
+    WouldBlock,
+    NoChildren,
 }
 
-pub struct ProcessStack<const N: usize> {
-    stack: [Option<Process>; N],
-    depth: usize,
+pub struct ProcessTable {
+    procs: Vec<Process>,
+    threads: Vec<Thread>,
+    /// The running thread of the CPU holding the table.
+    current: Option<u64>,
     next_pid: u64,
+    new_stack: fn() -> Result<KernelStack, ProcessError>,
+    new_fpu: fn() -> Result<FpuArea, ProcessError>,
 }
 
-impl<const N: usize> ProcessStack<N> {
+impl ProcessTable {
     pub const fn new() -> Self {
+        Self::with_stack_allocator(KernelStack::new)
+    }
+
+    pub const fn with_stack_allocator(
+        new_stack: fn() -> Result<KernelStack, ProcessError>,
+    ) -> Self {
         Self {
-            stack: [None; N],
-            depth: 0,
+            procs: Vec::new(),
+            threads: Vec::new(),
+            current: None,
             next_pid: 1,
+            new_stack,
+            new_fpu: no_fpu_state,
         }
     }
 
-    pub fn push_initial(&mut self, entry: usize) -> Result<u64, ProcessError> {
-        if self.depth != 0 {
-            return Ok(self.stack[0].map_or(1, |p| p.pid));
+    /// Sets how a fresh image's FPU state is made, for the first process
+    /// and every `exec`; forks and threads copy their creator's instead.
+    pub const fn with_fpu_state(mut self, new_fpu: fn() -> Result<FpuArea, ProcessError>) -> Self {
+        self.new_fpu = new_fpu;
+        self
+    }
+
+    pub fn push_initial(
+        &mut self,
+        context: ProcessContext,
+        address_space: AddressSpace,
+        memory: MemoryMap,
+    ) -> Result<u64, ProcessError> {
+        if let Some(root) = self.procs.first() {
+            return Ok(root.pid);
         }
         let pid = self.alloc_pid();
-        self.push(Process::new(pid, entry))?;
+        let thread = Thread::new(pid, pid, context, (self.new_stack)()?, (self.new_fpu)()?);
+        self.push(Process::new(pid, address_space, memory)?, thread)?;
+        self.current = Some(pid);
         Ok(pid)
     }
 
-    pub fn current(&self) -> Option<Process> {
-        self.depth.checked_sub(1).and_then(|i| self.stack[i])
+    /// Tells the table which thread is asking; each CPU sets this when it
+    /// takes the table lock.
+    pub fn set_current(&mut self, tid: Option<u64>) {
+        self.current = tid;
+    }
+
+    pub fn current_tid(&self) -> Option<u64> {
+        self.current
     }
 
-    pub fn fork_current(&mut self, parent_resume_rip: Option<usize>) -> Result<u64, ProcessError> {
-        let mut child = self.current().ok_or(ProcessError::StackEmpty)?;
+    pub fn current_pid(&self) -> Option<u64> {
+        Some(self.current_thread()?.pid)
+    }
+
+    pub fn current(&self) -> Option<&Process> {
+        self.get(self.current_pid()?)
+    }
+
+    pub fn current_mut(&mut self) -> Option<&mut Process> {
+        self.get_mut(self.current_pid()?)
+    }
+
+    pub fn current_thread(&self) -> Option<&Thread> {
+        self.thread(self.current?)
+    }
+
+    pub fn current_thread_mut(&mut self) -> Option<&mut Thread> {
+        self.thread_mut(self.current?)
+    }
+
+    pub fn get(&self, pid: u64) -> Option<&Process> {
+        self.procs.iter().find(|p| p.pid == pid)
+    }
+
+    pub fn get_mut(&mut self, pid: u64) -> Option<&mut Process> {
+        self.procs.iter_mut().find(|p| p.pid == pid)
+    }
+
+    pub fn thread(&self, tid: u64) -> Option<&Thread> {
+        self.threads.iter().find(|t| t.tid == tid)
+    }
+
+    pub fn thread_mut(&mut self, tid: u64) -> Option<&mut Thread> {
+        self.threads.iter_mut().find(|t| t.tid == tid)
+    }
+
+    pub fn has_live(&self) -> bool {
+        self.procs.iter().any(Process::is_live)
+    }
+
+    /// Copies the current process with only the calling thread; the child
+    /// sees 0 from the syscall.
+    pub fn fork_current(&mut self, address_space: AddressSpace) -> Result<u64, ProcessError> {
+        let parent = self.current_thread().ok_or(ProcessError::NoCurrent)?;
+        let (parent_pid, context) = (parent.pid, parent.context);
+        let fpu = parent.fpu.try_clone()?;
+        let kernel_stack = (self.new_stack)()?;
+        let mut child = self
+            .get(parent_pid)
+            .ok_or(ProcessError::NoCurrent)?
+            .try_clone(address_space)?;
         let child_pid = self.alloc_pid();
         child.pid = child_pid;
-        child.pagemap = child_pid as usize * 0x1000;
-        child.context.rax = 0;
-        if let Some(parent) = self.current_mut() {
-            parent.context.rax = child_pid as usize;
-            if let Some(rip) = parent_resume_rip {
-                parent.context.rip = rip;
+        child.parent = Some(parent_pid);
+        child.state = ProcessState::Runnable;
+        let context = ProcessContext { rax: 0, ..context };
+        self.push(
+            child,
+            Thread::new(child_pid, child_pid, context, kernel_stack, fpu),
+        )?;
+        Ok(child_pid)
+    }
+
+    /// Adds a thread to the current process that starts from `context`.
+    pub fn spawn_thread(&mut self, context: ProcessContext) -> Result<u64, ProcessError> {
+        let creator = self.current_thread().ok_or(ProcessError::NoCurrent)?;
+        let (pid, fpu) = (creator.pid, creator.fpu.try_clone()?);
+        let kernel_stack = (self.new_stack)()?;
+        self.threads
+            .try_reserve(1)
+            .map_err(|_| ProcessError::OutOfMemory)?;
+        let tid = self.alloc_pid();
+        self.threads
+            .push(Thread::new(tid, pid, context, kernel_stack, fpu));
+        Ok(tid)
+    }
+
+    /// Replaces the current process image; every other thread of it is gone.
+    pub fn exec_current(
+        &mut self,
+        context: ProcessContext,
+        address_space: AddressSpace,
+        memory: MemoryMap,
+    ) -> Result<Option<AddressSpace>, ProcessError> {
+        let tid = self.current.ok_or(ProcessError::NoCurrent)?;
+        let fpu = (self.new_fpu)()?;
+        let thread = self.current_thread_mut().ok_or(ProcessError::NoCurrent)?;
+        thread.context = context;
+        thread.fpu = fpu;
+        thread.clear_child_tid = 0;
+        let pid = thread.pid;
+        self.threads.retain(|t| t.pid != pid || t.tid == tid);
+        let proc = self.get_mut(pid).ok_or(ProcessError::NoCurrent)?;
+        proc.memory = memory;
+        Ok(proc.address_space.replace(address_space))
+    }
+
+    /// Ends the calling thread. Returns the pid if it was the last one, which
+    /// leaves the process a zombie with `code`.
+    pub fn exit_thread(&mut self, code: i32) -> Result<Option<u64>, ProcessError> {
+        let thread = self.current_thread_mut().ok_or(ProcessError::NoCurrent)?;
+        thread.state = ProcessState::Zombie(code);
+        let pid = thread.pid;
+        if self.threads.iter().any(|t| t.pid == pid && t.is_live()) {
+            return Ok(None);
+        }
+        self.exit_current(code).map(Some)
+    }
+
+    /// Marks every other thread of the current process exited, for
+    /// `exit_group` and `execve`. Returns false if the caller was itself
+    /// killed that way first.
+    pub fn kill_other_threads(&mut self) -> Result<bool, ProcessError> {
+        let tid = self.current.ok_or(ProcessError::NoCurrent)?;
+        let thread = self.current_thread().ok_or(ProcessError::NoCurrent)?;
+        if !thread.is_live() {
+            return Ok(false);
+        }
+        let pid = thread.pid;
+        for thread in self.threads.iter_mut() {
+            if thread.pid == pid && thread.tid != tid && thread.is_live() {
+                thread.state = ProcessState::Zombie(0);
             }
         }
-        self.push(child)?;
-        Ok(child_pid)
+        Ok(true)
+    }
+
+    /// CPUs (as a bitmask) still running other threads of the current process.
+    pub fn sibling_cpus(&self) -> u64 {
+        let Some(pid) = self.current_pid() else {
+            return 0;
+        };
+        self.threads
+            .iter()
+            .filter(|t| t.pid == pid && Some(t.tid) != self.current)
+            .filter_map(|t| t.cpu)
+            .fold(0, |mask, cpu| mask | 1 << cpu)
+    }
+
+    /// Ends the current process and all of its threads (`exit_group`).
+    pub fn exit_current(&mut self, code: i32) -> Result<u64, ProcessError> {
+        let pid = self.current_pid().ok_or(ProcessError::NoCurrent)?;
+        let proc = self.get_mut(pid).ok_or(ProcessError::NoCurrent)?;
+        proc.state = ProcessState::Zombie(code);
+        let parent = proc.parent;
+        for thread in self.threads.iter_mut().filter(|t| t.pid == pid) {
+            thread.state = ProcessState::Zombie(code);
+        }
+
+        for child in self.procs.iter_mut().filter(|p| p.parent == Some(pid)) {
+            child.parent = None;
+        }
+        self.reap_orphans();
+
+        for thread in self.threads.iter_mut().filter(|t| Some(t.pid) == parent) {
+            if let ProcessState::Blocked(BlockReason::WaitChild(target)) = thread.state
+                && target.is_none_or(|t| t == pid)
+            {
+                thread.state = ProcessState::Runnable;
+            }
+        }
+        Ok(pid)
+    }
+
+    pub fn wait_current(&mut self, target: Option<u64>) -> Result<WaitResult, ProcessError> {
+        let pid = self.current_pid().ok_or(ProcessError::NoCurrent)?;
+        let mut has_child = false;
+        let mut reaped = None;
+        for (i, p) in self.procs.iter().enumerate() {
+            if p.parent != Some(pid) || target.is_some_and(|t| t != p.pid) {
+                continue;
+            }
+            has_child = true;
+            if let ProcessState::Zombie(code) = p.state {
+                reaped = Some((i, p.pid, code));
+                break;
+            }
+        }
+
+        if let Some((i, pid, code)) = reaped {
+            self.procs.remove(i);
+            // A thread still on its way off a CPU is freed by `reap_orphans`.
+            self.threads.retain(|t| t.pid != pid || t.cpu.is_some());
+            return Ok(WaitResult::Reaped { pid, code });
+        }
+        if !has_child {
+            return Ok(WaitResult::NoChildren);
+        }
+        self.block_current(BlockReason::WaitChild(target))?;
+        Ok(WaitResult::WouldBlock)
     }
 
-    pub fn exec_current(&mut self, entry: usize) -> Result<(), ProcessError> {
-        let proc = self.current_mut().ok_or(ProcessError::StackEmpty)?;
-        proc.context = ProcessContext::new(entry, 0);
-        proc.pagemap = proc.pid as usize * 0x2000;
+    pub fn block_current(&mut self, reason: BlockReason) -> Result<(), ProcessError> {
+        let thread = self.current_thread_mut().ok_or(ProcessError::NoCurrent)?;
+        thread.state = ProcessState::Blocked(reason);
         Ok(())
     }
 
-    pub fn exit_current(&mut self) -> Result<Option<u64>, ProcessError> {
-        if self.depth == 0 {
-            return Err(ProcessError::StackEmpty);
+    /// Makes up to `count` threads of the current process waiting on the
+    /// futex at `addr` runnable and returns how many there were.
+    pub fn wake_futex(&mut self, addr: usize, count: usize) -> Result<usize, ProcessError> {
+        let pid = self.current_pid().ok_or(ProcessError::NoCurrent)?;
+        let mut woken = 0;
+        for thread in self.threads.iter_mut().filter(|t| t.pid == pid) {
+            if woken == count {
+                break;
+            }
+            if thread.state == ProcessState::Blocked(BlockReason::Futex(addr)) {
+                thread.state = ProcessState::Runnable;
+                woken += 1;
+            }
         }
-        self.depth -= 1;
-        self.stack[self.depth] = None;
-        Ok(self.current().map(|p| p.pid))
+        Ok(woken)
     }
 
-    fn push(&mut self, p: Process) -> Result<(), ProcessError> {
-        if self.depth >= N {
-            return Err(ProcessError::StackFull);
+    /// Picks the next runnable thread after the current one for `cpu`,
+    /// skipping threads other CPUs are running, and moves `cpu` onto it.
+    pub fn schedule_next(
+        &mut self,
+        cpu: usize,
+        ready: impl Fn(BlockReason) -> bool,
+    ) -> Option<u64> {
+        let len = self.threads.len();
+        let prev = self.current;
+        let start = prev
+            .and_then(|tid| self.threads.iter().position(|t| t.tid == tid))
+            .map_or(0, |i| i + 1);
+
+        let mut next = None;
+        for offset in 0..len {
+            let thread = &mut self.threads[(start + offset) % len];
+            if thread.cpu.is_some() && Some(thread.tid) != prev {
+                continue;
+            }
+            if let ProcessState::Blocked(reason) = thread.state
+                && ready(reason)
+            {
+                thread.state = ProcessState::Runnable;
+            }
+            if thread.state == ProcessState::Runnable {
+                thread.cpu = Some(cpu);
+                next = Some(thread.tid);
+                break;
+            }
         }
-        self.stack[self.depth] = Some(p);
-        self.depth += 1;
+        if next != prev
+            && let Some(thread) = prev.and_then(|tid| self.thread_mut(tid))
+        {
+            thread.cpu = None;
+        }
+        self.current = next;
+        next
+    }
+
+    /// The first deadline a sleeping thread waits for.
+    pub fn earliest_deadline(&self) -> Option<u64> {
+        self.threads
+            .iter()
+            .filter_map(|t| match t.state {
+                ProcessState::Blocked(BlockReason::Sleep(deadline)) => Some(deadline),
+                _ => None,
+            })
+            .min()
+    }
+
+    /// Makes every thread whose sleep ended by `now` runnable; returns how
+    /// many woke.
+    pub fn wake_sleepers(&mut self, now: u64) -> usize {
+        let mut woken = 0;
+        for thread in self.threads.iter_mut() {
+            if let ProcessState::Blocked(BlockReason::Sleep(deadline)) = thread.state
+                && deadline <= now
+            {
+                thread.state = ProcessState::Runnable;
+                woken += 1;
+            }
+        }
+        woken
+    }
+
+    pub fn is_blocked_on(&self, reason: BlockReason) -> bool {
+        self.threads
+            .iter()
+            .any(|t| t.state == ProcessState::Blocked(reason))
+    }
+
+    /// Frees exited threads no CPU is running, then orphaned zombie processes
+    /// with no threads left.
+    pub fn reap_orphans(&mut self) {
+        let current = self.current;
+        self.threads
+            .retain(|t| t.is_live() || t.cpu.is_some() || Some(t.tid) == current);
+        let threads = &self.threads;
+        self.procs.retain(|p| {
+            p.is_live() || p.parent.is_some() || threads.iter().any(|t| t.pid == p.pid)
+        });
+    }
+
+    fn push(&mut self, p: Process, thread: Thread) -> Result<(), ProcessError> {
+        self.procs
+            .try_reserve(1)
+            .and_then(|_| self.threads.try_reserve(1))
+            .map_err(|_| ProcessError::OutOfMemory)?;
+        self.procs.push(p);
+        self.threads.push(thread);
         Ok(())
     }
 
@@ -148,46 +760,435 @@ impl<const N: usize> ProcessStack<N> {
         self.next_pid += 1;
         pid
     }
+}
 
-    pub fn current_mut(&mut self) -> Option<&mut Process> {
-        self.depth
-            .checked_sub(1)
-            .and_then(move |i| self.stack[i].as_mut())
+impl Default for ProcessTable {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::ProcessStack;
+    use super::{
+        AddressSpace, BlockReason, FpuArea, KernelStack, ProcessContext, ProcessError,
+        ProcessState, ProcessTable, WaitResult,
+    };
+    use crate::vma::MemoryMap;
+    use core::sync::atomic::{AtomicUsize, Ordering::Relaxed};
 
-    #[test]
-    fn fork_pushes_new_top_and_exit_restores_previous() {
-        let mut stack: ProcessStack<8> = ProcessStack::new();
-        let pid1 = stack.push_initial(0x1000).expect("initial pid");
-        assert_eq!(pid1, 1);
+    fn space(root: usize) -> AddressSpace {
+        unsafe { AddressSpace::from_raw(root, |_| {}) }
+    }
+
+    fn table() -> ProcessTable {
+        let mut table = ProcessTable::new();
+        table
+            .push_initial(
+                ProcessContext::new(0x1000, 0x8000),
+                space(0x10_000),
+                MemoryMap::new(),
+            )
+            .expect("initial");
+        table
+    }
 
-        let child = stack.fork_current(None).expect("fork");
+    #[test]
+    fn fork_copies_parent_and_keeps_it_running() {
+        let mut table = table();
+        table.current_thread_mut().expect("parent").context.rbx = 0x55;
+        let child = table.fork_current(space(0x20_000)).expect("fork");
         assert_eq!(child, 2);
-        assert_eq!(stack.current().expect("current").pid, child);
+        assert_eq!(table.current_pid(), Some(1));
+
+        let proc = table.get(child).expect("child");
+        assert_eq!(proc.parent, Some(1));
+        assert_eq!(proc.state, ProcessState::Runnable);
+        assert_eq!(proc.pagemap(), Some(0x20_000));
+        let thread = table.thread(child).expect("child thread");
+        assert_eq!(thread.pid, child);
+        assert_eq!(thread.context.rax, 0);
+        assert_eq!(thread.context.rbx, 0x55);
+        assert_eq!(thread.context.rip, 0x1000);
+        assert_eq!(table.get(1).expect("parent").pagemap(), Some(0x10_000));
+    }
 
-        let underneath = stack.exit_current().expect("exit").expect("underneath");
-        assert_eq!(underneath, pid1);
-        assert_eq!(stack.current().expect("current").pid, pid1);
+    #[test]
+    fn round_robin_visits_every_runnable_process() {
+        let mut table = table();
+        table.fork_current(space(0x20_000)).expect("fork");
+        table.fork_current(space(0x30_000)).expect("fork");
+
+        let mut seen = [false; 4];
+        for _ in 0..3 {
+            let pid = table.schedule_next(0, |_| false).expect("runnable");
+            seen[pid as usize] = true;
+        }
+        assert_eq!(seen, [false, true, true, true]);
+    }
+
+    #[test]
+    fn sleepers_report_deadlines_and_wake_once_ready() {
+        let mut table = table();
+        let sleeper = table
+            .spawn_thread(ProcessContext::new(0x3000, 0x7000))
+            .expect("thread");
+        let reader = table
+            .spawn_thread(ProcessContext::new(0x3000, 0x7000))
+            .expect("thread");
+        assert_eq!(table.earliest_deadline(), None);
+        table.thread_mut(sleeper).expect("sleeper").state =
+            ProcessState::Blocked(BlockReason::Sleep(500));
+        table.current_thread_mut().expect("main").state =
+            ProcessState::Blocked(BlockReason::Sleep(200));
+        table.thread_mut(reader).expect("reader").state = ProcessState::Blocked(BlockReason::Stdin);
+        assert_eq!(table.earliest_deadline(), Some(200));
+        assert!(table.is_blocked_on(BlockReason::Stdin));
+        assert!(!table.is_blocked_on(BlockReason::Futex(0)));
+
+        assert_eq!(table.wake_sleepers(199), 0);
+        assert_eq!(table.schedule_next(0, |_| false), None);
+        assert_eq!(table.wake_sleepers(300), 1);
+        assert_eq!(table.earliest_deadline(), Some(500));
+        assert_eq!(table.schedule_next(0, |_| false), Some(1));
+    }
+
+    #[test]
+    fn wait_blocks_until_child_exits_then_reaps() {
+        let mut table = table();
+        let child = table.fork_current(space(0x20_000)).expect("fork");
+
+        assert_eq!(table.wait_current(None), Ok(WaitResult::WouldBlock));
+        assert_eq!(table.schedule_next(0, |_| false), Some(child));
+
+        assert_eq!(table.exit_current(7), Ok(child));
+        assert_eq!(table.schedule_next(0, |_| false), Some(1));
+        assert_eq!(
+            table.wait_current(None),
+            Ok(WaitResult::Reaped {
+                pid: child,
+                code: 7
+            })
+        );
+        assert_eq!(table.wait_current(None), Ok(WaitResult::NoChildren));
+    }
+
+    #[test]
+    fn blocked_process_wakes_when_ready() {
+        let mut table = table();
+        table.block_current(BlockReason::Stdin).expect("block");
+        assert_eq!(table.schedule_next(0, |_| false), None);
+        assert!(table.has_live());
+        assert_eq!(table.schedule_next(0, |r| r == BlockReason::Stdin), Some(1));
     }
 
     #[test]
-    fn exec_replaces_context() {
-        let mut stack: ProcessStack<4> = ProcessStack::new();
-        stack.push_initial(0x1111).expect("initial");
-        stack.exec_current(0x2222).expect("exec");
-        assert_eq!(stack.current().expect("proc").context.rip, 0x2222);
+    fn exited_orphan_keeps_kernel_stack_until_switched_away() {
+        let mut table = table();
+        let child = table.fork_current(space(0x20_000)).expect("fork");
+        let (parent_stack, child_stack) = (
+            table.thread(1).expect("parent").kernel_stack.top(),
+            table.thread(child).expect("child").kernel_stack.top(),
+        );
+        assert_ne!(parent_stack, child_stack);
+        assert_eq!(table.thread(child).expect("child").kernel_rsp, 0);
+
+        assert_eq!(table.exit_current(0), Ok(1));
+        table.reap_orphans();
+        assert!(table.get(1).is_some());
+
+        assert_eq!(table.schedule_next(0, |_| false), Some(child));
+        table.reap_orphans();
+        assert!(table.get(1).is_none());
+    }
+
+    #[test]
+    fn kernel_stacks_are_released_with_their_process() {
+        static RELEASED: AtomicUsize = AtomicUsize::new(0);
+        fn new_stack() -> Result<KernelStack, ProcessError> {
+            Ok(unsafe { KernelStack::from_raw(0x9000, |_| _ = RELEASED.fetch_add(1, Relaxed)) })
+        }
+
+        let mut table = ProcessTable::with_stack_allocator(new_stack);
+        table
+            .push_initial(
+                ProcessContext::new(0x1000, 0x8000),
+                space(0x10_000),
+                MemoryMap::new(),
+            )
+            .expect("initial");
+        let child = table.fork_current(space(0x20_000)).expect("fork");
+        assert_eq!(
+            table.thread(child).expect("child").kernel_stack.top(),
+            0x9000
+        );
+
+        assert_eq!(table.schedule_next(0, |_| false), Some(child));
+        table.exit_current(0).expect("exit");
+        assert_eq!(RELEASED.load(Relaxed), 0);
+        assert_eq!(table.schedule_next(0, |_| false), Some(1));
+        table.wait_current(Some(child)).expect("wait");
+        assert_eq!(RELEASED.load(Relaxed), 1);
+    }
+
+    #[test]
+    fn address_spaces_are_released_on_exec_and_reap() {
+        static RELEASED: AtomicUsize = AtomicUsize::new(0);
+        fn counted(root: usize) -> AddressSpace {
+            unsafe { AddressSpace::from_raw(root, |_| _ = RELEASED.fetch_add(1, Relaxed)) }
+        }
+
+        let mut table = table();
+        let child = table.fork_current(counted(0x20_000)).expect("fork");
+        assert_eq!(table.schedule_next(0, |_| false), Some(child));
+
+        let old = table
+            .exec_current(
+                ProcessContext::new(0x2000, 0x8000),
+                counted(0x30_000),
+                MemoryMap::new(),
+            )
+            .expect("exec");
+        assert_eq!(old.as_ref().map(AddressSpace::root), Some(0x20_000));
+        assert_eq!(RELEASED.load(Relaxed), 0);
+        drop(old);
+        assert_eq!(RELEASED.load(Relaxed), 1);
+        assert_eq!(table.current().and_then(|p| p.pagemap()), Some(0x30_000));
+
+        table.exit_current(0).expect("exit");
+        assert_eq!(table.schedule_next(0, |_| false), Some(1));
+        table.wait_current(Some(child)).expect("wait");
+        assert_eq!(RELEASED.load(Relaxed), 2);
+    }
+
+    #[test]
+    fn fpu_state_is_copied_on_fork_and_clone_and_reset_on_exec() {
+        fn fresh() -> Result<FpuArea, ProcessError> {
+            FpuArea::new(&[0x7f, 0x03])
+        }
+
+        let mut table = ProcessTable::new().with_fpu_state(fresh);
+        table
+            .push_initial(
+                ProcessContext::new(0x1000, 0x8000),
+                space(0x10_000),
+                MemoryMap::new(),
+            )
+            .expect("initial");
+        let fpu = &mut table.current_thread_mut().expect("parent").fpu;
+        assert_eq!(fpu.len(), 64);
+        assert_eq!(fpu.as_bytes()[..3], [0x7f, 0x03, 0]);
+        assert_eq!(fpu.as_mut_ptr() as usize % 64, 0);
+        unsafe { fpu.as_mut_ptr().add(63).write(0xaa) };
+
+        let child = table.fork_current(space(0x20_000)).expect("fork");
+        let tid = table
+            .spawn_thread(ProcessContext::new(0x3000, 0x7000))
+            .expect("thread");
+        for copy in [child, tid] {
+            let fpu = &table.thread(copy).expect("copy").fpu;
+            assert_eq!(fpu.as_bytes()[..2], [0x7f, 0x03]);
+            assert_eq!(fpu.as_bytes()[63], 0xaa);
+        }
+
+        table
+            .exec_current(
+                ProcessContext::new(0x2000, 0x8000),
+                space(0x30_000),
+                MemoryMap::new(),
+            )
+            .expect("exec");
+        let fpu = &table.current_thread().expect("exec'd").fpu;
+        assert_eq!(fpu.as_bytes()[63], 0);
+        assert_eq!(table.thread(child).expect("child").fpu.as_bytes()[63], 0xaa);
+    }
+
+    #[test]
+    fn threads_share_the_process_and_get_their_own_tids() {
+        let mut table = table();
+        let tid = table
+            .spawn_thread(ProcessContext::new(0x3000, 0x7000))
+            .expect("thread");
+        assert_eq!(tid, 2);
+        assert!(table.get(tid).is_none());
+        assert_eq!(table.thread(tid).expect("thread").pid, 1);
+
+        assert_eq!(table.schedule_next(0, |_| false), Some(tid));
+        assert_eq!(table.current_tid(), Some(tid));
+        assert_eq!(table.current_pid(), Some(1));
+        assert_eq!(table.current().and_then(|p| p.pagemap()), Some(0x10_000));
+        assert_eq!(table.fork_current(space(0x20_000)), Ok(3));
+        assert_eq!(table.get(3).expect("child").parent, Some(1));
+    }
+
+    #[test]
+    fn process_exits_with_its_last_thread() {
+        let mut table = table();
+        let child = table.fork_current(space(0x20_000)).expect("fork");
+        assert_eq!(table.schedule_next(0, |_| false), Some(child));
+        let tid = table
+            .spawn_thread(ProcessContext::new(0x3000, 0x7000))
+            .expect("thread");
+
+        assert_eq!(table.exit_thread(3), Ok(None));
+        assert_eq!(
+            table.get(child).expect("child").state,
+            ProcessState::Runnable
+        );
+        assert_eq!(table.schedule_next(0, |_| false), Some(tid));
+        table.reap_orphans();
+        assert!(table.thread(child).is_none());
+
+        assert_eq!(table.exit_thread(5), Ok(Some(child)));
+        assert_eq!(table.schedule_next(0, |_| false), Some(1));
+        assert_eq!(
+            table.wait_current(None),
+            Ok(WaitResult::Reaped {
+                pid: child,
+                code: 5
+            })
+        );
+        assert!(table.thread(tid).is_none());
+    }
+
+    #[test]
+    fn group_exit_ends_every_thread() {
+        let mut table = table();
+        let child = table.fork_current(space(0x20_000)).expect("fork");
+        assert_eq!(table.schedule_next(0, |_| false), Some(child));
+        let tid = table
+            .spawn_thread(ProcessContext::new(0x3000, 0x7000))
+            .expect("thread");
+
+        assert_eq!(table.exit_current(9), Ok(child));
+        assert!(table.thread(tid).is_none());
+        assert_eq!(table.schedule_next(0, |_| false), Some(1));
+        assert_eq!(
+            table.wait_current(None),
+            Ok(WaitResult::Reaped {
+                pid: child,
+                code: 9
+            })
+        );
+    }
+
+    #[test]
+    fn futex_wake_releases_matching_waiters_up_to_count() {
+        let mut table = table();
+        let context = ProcessContext::new(0x3000, 0x7000);
+        let waiters = [
+            table.spawn_thread(context).expect("thread"),
+            table.spawn_thread(context).expect("thread"),
+            table.spawn_thread(context).expect("thread"),
+        ];
+        for (tid, addr) in waiters.into_iter().zip([0x100, 0x100, 0x200]) {
+            assert_eq!(table.schedule_next(0, |_| false), Some(tid));
+            table
+                .block_current(BlockReason::Futex(addr))
+                .expect("block");
+        }
+        assert_eq!(table.schedule_next(0, |_| false), Some(1));
+
+        assert_eq!(table.wake_futex(0x100, 1), Ok(1));
+        assert_eq!(table.wake_futex(0x100, 8), Ok(1));
+        assert_eq!(table.wake_futex(0x100, 8), Ok(0));
+        assert_eq!(
+            table.thread(waiters[2]).expect("thread").state,
+            ProcessState::Blocked(BlockReason::Futex(0x200))
+        );
+    }
+
+    #[test]
+    fn exec_drops_the_other_threads() {
+        let mut table = table();
+        let tid = table
+            .spawn_thread(ProcessContext::new(0x3000, 0x7000))
+            .expect("thread");
+        table
+            .exec_current(
+                ProcessContext::new(0x2000, 0x8000),
+                space(0x30_000),
+                MemoryMap::new(),
+            )
+            .expect("exec");
+        assert!(table.thread(tid).is_none());
+        assert_eq!(table.current_thread().expect("thread").context.rip, 0x2000);
+    }
+
+    #[test]
+    fn cpus_never_share_a_thread() {
+        let mut table = table();
+        let tid = table
+            .spawn_thread(ProcessContext::new(0x3000, 0x7000))
+            .expect("thread");
+
+        table.set_current(None);
+        assert_eq!(table.schedule_next(0, |_| false), Some(1));
+        table.set_current(None);
+        assert_eq!(table.schedule_next(1, |_| false), Some(tid));
+        table.set_current(None);
+        assert_eq!(table.schedule_next(2, |_| false), None);
+
+        table.set_current(Some(1));
+        assert_eq!(table.schedule_next(0, |_| false), Some(1));
+        table.block_current(BlockReason::Stdin).expect("block");
+        assert_eq!(table.schedule_next(0, |_| false), None);
+        assert_eq!(table.thread(1).expect("thread").cpu, None);
+        assert_eq!(table.thread(tid).expect("thread").cpu, Some(1));
+    }
+
+    #[test]
+    fn group_exit_waits_for_threads_on_other_cpus() {
+        let mut table = table();
+        let tid = table
+            .spawn_thread(ProcessContext::new(0x3000, 0x7000))
+            .expect("thread");
+        table.set_current(None);
+        assert_eq!(table.schedule_next(3, |_| false), Some(1));
+        table.set_current(None);
+        assert_eq!(table.schedule_next(5, |_| false), Some(tid));
+
+        table.set_current(Some(1));
+        assert_eq!(table.kill_other_threads(), Ok(true));
+        assert_eq!(table.sibling_cpus(), 1 << 5);
+        table.set_current(Some(tid));
+        assert_eq!(table.kill_other_threads(), Ok(false));
+
+        table.reap_orphans();
+        assert!(table.thread(tid).is_some());
+        assert_eq!(table.schedule_next(5, |_| false), None);
+        table.set_current(Some(1));
+        assert_eq!(table.sibling_cpus(), 0);
+        table.reap_orphans();
+        assert!(table.thread(tid).is_none());
+    }
+
+    #[test]
+    fn process_count_is_not_fixed() {
+        let mut table = table();
+        for i in 0..64 {
+            table
+                .fork_current(space(0x20_000 + i * 0x1000))
+                .expect("fork");
+        }
+        assert_eq!(table.get(65).expect("last child").parent, Some(1));
+    }
+
+    #[test]
+    fn fd_table_grows_past_initial_slots() {
+        let mut table = table();
+        let proc = table.current_mut().expect("proc");
+        for handle in 0..64u64 {
+            let fd = proc.install_fd(100 + handle).expect("fd");
+            assert_eq!(fd, 3 + handle);
+        }
+        assert_eq!(proc.resolve_fd(66), Some((163, 0)));
     }
 
     #[test]
     fn install_and_resolve_fd() {
-        let mut stack: ProcessStack<2> = ProcessStack::new();
-        stack.push_initial(0x1000).expect("initial");
-        let proc = stack.current_mut().expect("proc");
+        let mut table = table();
+        let proc = table.current_mut().expect("proc");
         let fd = proc.install_fd(123).expect("fd");
         assert_eq!(proc.resolve_fd(fd), Some((123, 0)));
     }
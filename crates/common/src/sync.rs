@@ -0,0 +1,227 @@
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicU32, Ordering};
+
+/// A fair spinlock: each locker takes a ticket and is let in once the lock
+/// serves that number, so CPUs get the lock in the order they asked.
+pub struct TicketLock<T> {
+    next: AtomicU32,
+    serving: AtomicU32,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for TicketLock<T> {}
+unsafe impl<T: Send> Sync for TicketLock<T> {}
+
+pub struct TicketGuard<'a, T> {
+    lock: &'a TicketLock<T>,
+}
+
+impl<T> TicketLock<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            next: AtomicU32::new(0),
+            serving: AtomicU32::new(0),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn lock(&self) -> TicketGuard<'_, T> {
+        let ticket = self.next.fetch_add(1, Ordering::Relaxed);
+        while self.serving.load(Ordering::Acquire) != ticket {
+            core::hint::spin_loop();
+        }
+        TicketGuard { lock: self }
+    }
+
+    /// Takes the lock only if nobody holds or waits for it.
+    pub fn try_lock(&self) -> Option<TicketGuard<'_, T>> {
+        let serving = self.serving.load(Ordering::Acquire);
+        self.next
+            .compare_exchange(
+                serving,
+                serving.wrapping_add(1),
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            )
+            .ok()
+            .map(|_| TicketGuard { lock: self })
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.next.load(Ordering::Relaxed) != self.serving.load(Ordering::Relaxed)
+    }
+
+    /// Lets the next waiter in.
+    ///
+    /// # Safety
+    /// The lock must be held through a guard that was forgotten, e.g. one
+    /// kept across a context switch and released by whoever runs next.
+    pub unsafe fn force_unlock(&self) {
+        self.serving.fetch_add(1, Ordering::Release);
+    }
+}
+
+impl<T> Deref for TicketGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> DerefMut for TicketGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for TicketGuard<'_, T> {
+    fn drop(&mut self) {
+        unsafe { self.lock.force_unlock() };
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LockOrderError<C> {
+    /// The lock ranks at or above `held`, which is already taken.
+    Inversion {
+        held: C,
+    },
+    TooDeep,
+}
+
+/// The locks one CPU holds, checked against a hierarchy in which `C`'s
+/// order is the order locks must be taken in.
+pub struct HeldLocks<C, const N: usize> {
+    held: [Option<C>; N],
+    len: usize,
+}
+
+impl<C: Copy + Ord, const N: usize> HeldLocks<C, N> {
+    pub const fn new() -> Self {
+        Self {
+            held: [None; N],
+            len: 0,
+        }
+    }
+
+    /// Records taking `class`, which must rank after every lock held.
+    pub fn acquire(&mut self, class: C) -> Result<(), LockOrderError<C>> {
+        if let Some(held) = self.held[..self.len]
+            .iter()
+            .flatten()
+            .find(|&&h| h >= class)
+        {
+            return Err(LockOrderError::Inversion { held: *held });
+        }
+        self.record(class)
+    }
+
+    /// Records taking `class` without an order check, for locks taken with a
+    /// `try_lock` that cannot deadlock.
+    pub fn record(&mut self, class: C) -> Result<(), LockOrderError<C>> {
+        let slot = self.held.get_mut(self.len).ok_or(LockOrderError::TooDeep)?;
+        *slot = Some(class);
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Forgets the most recent `class`; guards may drop in any order.
+    pub fn release(&mut self, class: C) {
+        if let Some(i) = self.held[..self.len]
+            .iter()
+            .rposition(|&h| h == Some(class))
+        {
+            self.held.copy_within(i + 1..self.len, i);
+            self.len -= 1;
+            self.held[self.len] = None;
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl<C: Copy + Ord, const N: usize> Default for HeldLocks<C, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::{HeldLocks, LockOrderError, TicketLock};
+    use std::sync::Arc;
+    use std::thread;
+    use std::vec::Vec;
+
+    #[test]
+    fn ticket_lock_excludes_other_threads() {
+        let lock = Arc::new(TicketLock::new(0u64));
+        let workers: Vec<_> = (0..4)
+            .map(|_| {
+                let lock = Arc::clone(&lock);
+                thread::spawn(move || {
+                    for _ in 0..10_000 {
+                        *lock.lock() += 1;
+                    }
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        assert_eq!(*lock.lock(), 40_000);
+        assert!(!lock.is_locked());
+    }
+
+    #[test]
+    fn try_lock_fails_while_held_and_force_unlock_releases() {
+        let lock = TicketLock::new(());
+        let guard = lock.lock();
+        assert!(lock.try_lock().is_none());
+        drop(guard);
+        assert!(lock.try_lock().is_some());
+
+        core::mem::forget(lock.lock());
+        assert!(lock.is_locked());
+        unsafe { lock.force_unlock() };
+        assert!(!lock.is_locked());
+        drop(lock.lock());
+    }
+
+    #[test]
+    fn held_locks_enforce_the_hierarchy() {
+        let mut held = HeldLocks::<u8, 3>::new();
+        assert_eq!(held.acquire(1), Ok(()));
+        assert_eq!(held.acquire(4), Ok(()));
+        assert_eq!(held.acquire(2), Err(LockOrderError::Inversion { held: 4 }));
+        assert_eq!(held.acquire(4), Err(LockOrderError::Inversion { held: 4 }));
+        assert_eq!(held.len(), 2);
+
+        held.release(1);
+        assert_eq!(held.acquire(2), Err(LockOrderError::Inversion { held: 4 }));
+        held.release(4);
+        assert!(held.is_empty());
+        assert_eq!(held.acquire(2), Ok(()));
+    }
+
+    #[test]
+    fn held_locks_record_try_locks_and_cap_depth() {
+        let mut held = HeldLocks::<u8, 2>::new();
+        assert_eq!(held.acquire(5), Ok(()));
+        assert_eq!(held.record(1), Ok(()));
+        assert_eq!(held.record(3), Err(LockOrderError::TooDeep));
+        held.release(5);
+        held.release(7);
+        assert_eq!(held.len(), 1);
+    }
+}
@@ -0,0 +1,143 @@
+pub const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+const SCALE_SHIFT: u32 = 32;
+
+/// A ratio between two clocks in 32.32 fixed point, so converting a reading
+/// costs a multiply instead of a divide.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Scale {
+    mult: u64,
+}
+
+impl Scale {
+    pub const ZERO: Self = Self { mult: 0 };
+
+    /// `to` units per `from` units, e.g. nanoseconds elapsed per TSC ticks
+    /// counted over the same interval.
+    pub fn new(to: u64, from: u64) -> Option<Self> {
+        if from == 0 {
+            return None;
+        }
+        let mult = (u128::from(to) << SCALE_SHIFT) / u128::from(from);
+        Some(Self {
+            mult: u64::try_from(mult).ok()?,
+        })
+    }
+
+    /// Converts `value`, saturating at `u64::MAX`.
+    pub fn apply(self, value: u64) -> u64 {
+        let scaled = (u128::from(value) * u128::from(self.mult)) >> SCALE_SHIFT;
+        u64::try_from(scaled).unwrap_or(u64::MAX)
+    }
+}
+
+/// `struct timespec` as the syscalls read and write it.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Timespec {
+    pub tv_sec: i64,
+    pub tv_nsec: i64,
+}
+
+impl Timespec {
+    pub const SIZE: usize = 16;
+
+    pub fn from_nanos(ns: u64) -> Self {
+        Self {
+            tv_sec: (ns / NANOS_PER_SEC) as i64,
+            tv_nsec: (ns % NANOS_PER_SEC) as i64,
+        }
+    }
+
+    /// None for a negative time or `tv_nsec` out of range, which the
+    /// syscalls reject with `EINVAL`.
+    pub fn to_nanos(self) -> Option<u64> {
+        if !(0..NANOS_PER_SEC as i64).contains(&self.tv_nsec) {
+            return None;
+        }
+        let secs = u64::try_from(self.tv_sec).ok()?;
+        Some(
+            secs.saturating_mul(NANOS_PER_SEC)
+                .saturating_add(self.tv_nsec as u64),
+        )
+    }
+
+    pub fn from_le_bytes(bytes: [u8; Self::SIZE]) -> Self {
+        let (sec, nsec) = bytes.split_at(8);
+        Self {
+            tv_sec: i64::from_le_bytes(sec.try_into().unwrap_or_default()),
+            tv_nsec: i64::from_le_bytes(nsec.try_into().unwrap_or_default()),
+        }
+    }
+
+    pub fn to_le_bytes(self) -> [u8; Self::SIZE] {
+        let mut bytes = [0; Self::SIZE];
+        bytes[..8].copy_from_slice(&self.tv_sec.to_le_bytes());
+        bytes[8..].copy_from_slice(&self.tv_nsec.to_le_bytes());
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::{NANOS_PER_SEC, Scale, Timespec};
+
+    #[test]
+    fn scale_converts_between_clock_rates() {
+        // A 2.5 GHz TSC measured over 10 ms.
+        let tsc_to_ns = Scale::new(10_000_000, 25_000_000).expect("scale");
+        // Rounding the ratio down costs at most a nanosecond per second.
+        assert!(tsc_to_ns.apply(2_500_000_000).abs_diff(NANOS_PER_SEC) <= 1);
+        assert_eq!(tsc_to_ns.apply(0), 0);
+
+        let ns_to_lapic = Scale::new(625_000, 10_000_000).expect("scale");
+        assert_eq!(ns_to_lapic.apply(10_000_000), 625_000);
+        assert_eq!(ns_to_lapic.apply(1_000), 62);
+
+        // Ten years of a 3 GHz TSC still fits.
+        let ns = Scale::new(1, 3)
+            .expect("scale")
+            .apply(3 * NANOS_PER_SEC * 315_360_000);
+        assert!(ns.abs_diff(NANOS_PER_SEC * 315_360_000) < NANOS_PER_SEC);
+    }
+
+    #[test]
+    fn scale_rejects_zero_and_saturates() {
+        assert_eq!(Scale::new(1, 0), None);
+        assert_eq!(Scale::new(u64::MAX, 1), None);
+        assert_eq!(
+            Scale::new(1 << 30, 1).expect("scale").apply(u64::MAX),
+            u64::MAX
+        );
+        assert_eq!(Scale::ZERO.apply(12345), 0);
+    }
+
+    #[test]
+    fn timespec_round_trips_and_validates() {
+        let ts = Timespec::from_nanos(3 * NANOS_PER_SEC + 250);
+        assert_eq!(
+            ts,
+            Timespec {
+                tv_sec: 3,
+                tv_nsec: 250
+            }
+        );
+        assert_eq!(ts.to_nanos(), Some(3 * NANOS_PER_SEC + 250));
+        assert_eq!(Timespec::from_le_bytes(ts.to_le_bytes()), ts);
+
+        for bad in [(-1, 0), (0, -1), (0, NANOS_PER_SEC as i64)] {
+            let ts = Timespec {
+                tv_sec: bad.0,
+                tv_nsec: bad.1,
+            };
+            assert_eq!(ts.to_nanos(), None);
+        }
+        let far = Timespec {
+            tv_sec: i64::MAX,
+            tv_nsec: 0,
+        };
+        assert_eq!(far.to_nanos(), Some(u64::MAX));
+    }
+}
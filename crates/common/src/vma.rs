@@ -0,0 +1,400 @@
+use crate::syscall::{PROT_READ, PROT_WRITE};
+use alloc::vec::Vec;
+
+pub const PAGE_SIZE: usize = 0x1000;
+
+pub const fn page_align_up(addr: usize) -> usize {
+    (addr + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VmaKind {
+    Image,
+    Stack,
+    Heap,
+    Anonymous,
+    File,
+}
+
+/// The file behind a mapping: a VFS handle and the file offset of `Vma::start`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VmaFile {
+    pub handle: u64,
+    pub offset: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Vma {
+    pub start: usize,
+    pub end: usize,
+    pub prot: u32,
+    pub shared: bool,
+    pub kind: VmaKind,
+    pub file: Option<VmaFile>,
+}
+
+impl Vma {
+    pub const fn new(start: usize, end: usize, prot: u32, kind: VmaKind) -> Self {
+        Self {
+            start,
+            end,
+            prot,
+            shared: false,
+            kind,
+            file: None,
+        }
+    }
+
+    /// The file offset backing `addr`, if this area maps a file.
+    pub fn file_offset(&self, addr: usize) -> Option<usize> {
+        self.file.map(|f| f.offset + (addr - self.start))
+    }
+
+    fn starting_at(self, start: usize) -> Self {
+        let file = self.file.map(|f| VmaFile {
+            offset: f.offset + (start - self.start),
+            ..f
+        });
+        Self {
+            start,
+            file,
+            ..self
+        }
+    }
+
+    pub fn contains(&self, addr: usize) -> bool {
+        (self.start..self.end).contains(&addr)
+    }
+
+    fn overlaps(&self, start: usize, end: usize) -> bool {
+        self.start < end && start < self.end
+    }
+
+    fn joins(&self, next: &Vma) -> bool {
+        self.end == next.start
+            && self.prot == next.prot
+            && self.shared == next.shared
+            && self.kind == next.kind
+            && match (self.file, next.file) {
+                (None, None) => true,
+                (Some(a), Some(b)) => {
+                    a.handle == b.handle && self.file_offset(self.end) == Some(b.offset)
+                }
+                _ => false,
+            }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VmaError {
+    OutOfMemory,
+    Overlap,
+    Unmapped,
+}
+
+/// Sorted, non-overlapping areas of a process's user address space plus its
+/// `brk` heap bounds and where `mmap` starts looking for free space.
+#[derive(Debug, Default)]
+pub struct MemoryMap {
+    areas: Vec<Vma>,
+    brk_start: usize,
+    brk: usize,
+    mmap_base: usize,
+}
+
+impl MemoryMap {
+    pub const fn new() -> Self {
+        Self {
+            areas: Vec::new(),
+            brk_start: 0,
+            brk: 0,
+            mmap_base: 0,
+        }
+    }
+
+    pub fn areas(&self) -> &[Vma] {
+        &self.areas
+    }
+
+    pub fn try_clone(&self) -> Result<Self, VmaError> {
+        let mut areas = Vec::new();
+        areas
+            .try_reserve_exact(self.areas.len())
+            .map_err(|_| VmaError::OutOfMemory)?;
+        areas.extend_from_slice(&self.areas);
+        Ok(Self {
+            areas,
+            brk_start: self.brk_start,
+            brk: self.brk,
+            mmap_base: self.mmap_base,
+        })
+    }
+
+    pub fn find(&self, addr: usize) -> Option<&Vma> {
+        self.areas.iter().find(|a| a.contains(addr))
+    }
+
+    pub fn overlapping(&self, start: usize, end: usize) -> impl Iterator<Item = &Vma> {
+        self.areas.iter().filter(move |a| a.overlaps(start, end))
+    }
+
+    pub fn is_free(&self, start: usize, end: usize) -> bool {
+        start < end && !self.areas.iter().any(|a| a.overlaps(start, end))
+    }
+
+    pub fn insert(&mut self, vma: Vma) -> Result<(), VmaError> {
+        if !self.is_free(vma.start, vma.end) {
+            return Err(VmaError::Overlap);
+        }
+        let idx = self.areas.partition_point(|a| a.start < vma.start);
+        if idx > 0 && self.areas[idx - 1].joins(&vma) {
+            self.areas[idx - 1].end = vma.end;
+            if idx < self.areas.len() && self.areas[idx - 1].joins(&self.areas[idx]) {
+                self.areas[idx - 1].end = self.areas.remove(idx).end;
+            }
+            return Ok(());
+        }
+        if idx < self.areas.len() && vma.joins(&self.areas[idx]) {
+            self.areas[idx] = Vma {
+                end: self.areas[idx].end,
+                ..vma
+            };
+            return Ok(());
+        }
+        self.areas
+            .try_reserve(1)
+            .map_err(|_| VmaError::OutOfMemory)?;
+        self.areas.insert(idx, vma);
+        Ok(())
+    }
+
+    /// Drops `[start, end)` from every area, splitting the ones it cuts through.
+    pub fn remove(&mut self, start: usize, end: usize) -> Result<(), VmaError> {
+        self.rebuild(start, end, |_| None)
+    }
+
+    /// Changes the protection of `[start, end)`, which must be fully mapped.
+    pub fn protect(&mut self, start: usize, end: usize, prot: u32) -> Result<(), VmaError> {
+        let covered: usize = self
+            .overlapping(start, end)
+            .map(|a| a.end.min(end) - a.start.max(start))
+            .sum();
+        if start >= end || covered != end - start {
+            return Err(VmaError::Unmapped);
+        }
+        self.rebuild(start, end, |vma| Some(Vma { prot, ..vma }))
+    }
+
+    fn rebuild(
+        &mut self,
+        start: usize,
+        end: usize,
+        middle: impl Fn(Vma) -> Option<Vma>,
+    ) -> Result<(), VmaError> {
+        let mut out = Vec::new();
+        out.try_reserve_exact(self.areas.len() + 2)
+            .map_err(|_| VmaError::OutOfMemory)?;
+        for &a in &self.areas {
+            if !a.overlaps(start, end) {
+                out.push(a);
+                continue;
+            }
+            if a.start < start {
+                out.push(Vma { end: start, ..a });
+            }
+            let cut = Vma {
+                end: a.end.min(end),
+                ..a.starting_at(a.start.max(start))
+            };
+            out.extend(middle(cut));
+            if a.end > end {
+                out.push(a.starting_at(end));
+            }
+        }
+        self.areas = out;
+        Ok(())
+    }
+
+    /// First-fit search for `len` free bytes inside `[lo, hi)`.
+    pub fn find_free(&self, len: usize, lo: usize, hi: usize) -> Option<usize> {
+        let mut cursor = lo;
+        for a in &self.areas {
+            if a.end <= cursor {
+                continue;
+            }
+            if a.start >= cursor.checked_add(len)? {
+                break;
+            }
+            cursor = a.end;
+        }
+        (cursor.checked_add(len)? <= hi).then_some(cursor)
+    }
+
+    pub fn mmap_base(&self) -> usize {
+        self.mmap_base
+    }
+
+    pub fn set_mmap_base(&mut self, base: usize) {
+        self.mmap_base = base;
+    }
+
+    pub fn brk(&self) -> usize {
+        self.brk
+    }
+
+    pub fn brk_start(&self) -> usize {
+        self.brk_start
+    }
+
+    pub fn set_heap_start(&mut self, start: usize) {
+        self.brk_start = start;
+        self.brk = start;
+    }
+
+    /// Moves the program break, growing or shrinking the heap area to match.
+    pub fn set_brk(&mut self, brk: usize) -> Result<(), VmaError> {
+        if brk < self.brk_start {
+            return Err(VmaError::Unmapped);
+        }
+        let (old_end, new_end) = (page_align_up(self.brk), page_align_up(brk));
+        if new_end > old_end {
+            self.insert(Vma::new(
+                old_end,
+                new_end,
+                PROT_READ | PROT_WRITE,
+                VmaKind::Heap,
+            ))?;
+        } else if new_end < old_end {
+            self.remove(new_end, old_end)?;
+        }
+        self.brk = brk;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::{MemoryMap, PAGE_SIZE, Vma, VmaError, VmaFile, VmaKind};
+    use crate::syscall::{PROT_READ, PROT_WRITE};
+
+    const RW: u32 = PROT_READ | PROT_WRITE;
+
+    fn anon(start: usize, end: usize, prot: u32) -> Vma {
+        Vma::new(start, end, prot, VmaKind::Anonymous)
+    }
+
+    #[test]
+    fn adjacent_areas_merge_and_overlaps_are_refused() {
+        let mut map = MemoryMap::new();
+        map.insert(anon(0x1000, 0x3000, RW)).expect("first");
+        map.insert(anon(0x5000, 0x6000, RW)).expect("second");
+        map.insert(anon(0x3000, 0x5000, RW)).expect("gap");
+        assert_eq!(map.areas(), &[anon(0x1000, 0x6000, RW)]);
+        assert_eq!(map.insert(anon(0x2000, 0x4000, RW)), Err(VmaError::Overlap));
+    }
+
+    #[test]
+    fn remove_splits_partially_covered_areas() {
+        let mut map = MemoryMap::new();
+        map.insert(anon(0x1000, 0x9000, RW)).expect("insert");
+        map.remove(0x3000, 0x5000).expect("remove");
+        assert_eq!(
+            map.areas(),
+            &[anon(0x1000, 0x3000, RW), anon(0x5000, 0x9000, RW)]
+        );
+        assert!(map.find(0x4000).is_none());
+        assert!(map.find(0x5000).is_some());
+    }
+
+    #[test]
+    fn protect_splits_and_requires_a_fully_mapped_range() {
+        let mut map = MemoryMap::new();
+        map.insert(anon(0x1000, 0x4000, RW)).expect("insert");
+        map.protect(0x2000, 0x3000, PROT_READ).expect("protect");
+        assert_eq!(
+            map.areas(),
+            &[
+                anon(0x1000, 0x2000, RW),
+                anon(0x2000, 0x3000, PROT_READ),
+                anon(0x3000, 0x4000, RW),
+            ]
+        );
+        assert_eq!(
+            map.protect(0x3000, 0x5000, PROT_READ),
+            Err(VmaError::Unmapped)
+        );
+    }
+
+    #[test]
+    fn file_offsets_follow_splits_and_merges() {
+        let file = |start, end, offset| Vma {
+            file: Some(VmaFile { handle: 7, offset }),
+            ..Vma::new(start, end, PROT_READ, VmaKind::File)
+        };
+        let mut map = MemoryMap::new();
+        map.insert(file(0x1000, 0x5000, 0x4000)).expect("insert");
+        map.remove(0x2000, 0x3000).expect("remove");
+        assert_eq!(
+            map.areas(),
+            &[file(0x1000, 0x2000, 0x4000), file(0x3000, 0x5000, 0x6000)]
+        );
+        assert_eq!(
+            map.find(0x4800).and_then(|a| a.file_offset(0x4800)),
+            Some(0x7800)
+        );
+
+        map.insert(file(0x2000, 0x3000, 0x5000)).expect("refill");
+        assert_eq!(map.areas(), &[file(0x1000, 0x5000, 0x4000)]);
+
+        map.insert(file(0x5000, 0x6000, 0))
+            .expect("unrelated offset");
+        assert_eq!(map.areas().len(), 2);
+    }
+
+    #[test]
+    fn find_free_skips_used_ranges() {
+        let mut map = MemoryMap::new();
+        map.insert(anon(0x10_000, 0x12_000, RW)).expect("insert");
+        map.insert(anon(0x13_000, 0x14_000, RW)).expect("insert");
+        assert_eq!(map.find_free(PAGE_SIZE, 0x10_000, 0x20_000), Some(0x12_000));
+        assert_eq!(
+            map.find_free(2 * PAGE_SIZE, 0x10_000, 0x20_000),
+            Some(0x14_000)
+        );
+        assert_eq!(map.find_free(0x10_000, 0x10_000, 0x20_000), None);
+    }
+
+    #[test]
+    fn brk_grows_and_shrinks_the_heap_area() {
+        let mut map = MemoryMap::new();
+        map.set_heap_start(0x40_0800);
+        map.set_brk(0x40_2010).expect("grow");
+        assert_eq!(map.brk(), 0x40_2010);
+        assert_eq!(
+            map.areas(),
+            &[Vma::new(0x40_1000, 0x40_3000, RW, VmaKind::Heap)]
+        );
+
+        map.set_brk(0x40_1000).expect("shrink");
+        assert!(map.areas().is_empty());
+        assert_eq!(map.set_brk(0x40_0000), Err(VmaError::Unmapped));
+
+        map.insert(anon(0x40_2000, 0x40_3000, RW)).expect("block");
+        assert_eq!(map.set_brk(0x40_2800), Err(VmaError::Overlap));
+        assert_eq!(map.brk(), 0x40_1000);
+    }
+
+    #[test]
+    fn clones_keep_heap_and_mmap_bases() {
+        let mut map = MemoryMap::new();
+        map.set_heap_start(0x40_0000);
+        map.set_brk(0x40_1000).expect("grow");
+        map.set_mmap_base(0x1000_0123_4000);
+        let copy = map.try_clone().expect("clone");
+        assert_eq!(copy.areas(), map.areas());
+        assert_eq!((copy.brk_start(), copy.brk()), (0x40_0000, 0x40_1000));
+        assert_eq!(copy.mmap_base(), 0x1000_0123_4000);
+    }
+}
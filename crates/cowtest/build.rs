@@ -0,0 +1,7 @@
+/// A PIE naming `/lib/ld.so` as its interpreter. There is no linker script:
+/// lld's default layout gives each segment its own pages.
+fn main() {
+    for arg in ["--dynamic-linker=/lib/ld.so", "--image-base=0x400000"] {
+        println!("cargo:rustc-link-arg-bin=cowtest={arg}");
+    }
+}
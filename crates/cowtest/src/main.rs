@@ -0,0 +1,106 @@
+#![no_std]
+#![no_main]
+
+use common::syscall::{
+    FD_STDOUT, MAP_ANONYMOUS, MAP_PRIVATE, PROT_READ, PROT_WRITE, SYS_FORK, SYS_MMAP, SYS_WAIT4,
+};
+use core::ptr::{read_volatile, write_volatile};
+use rt::{syscall3, syscall6};
+
+#[panic_handler]
+fn panic(_: &core::panic::PanicInfo<'_>) -> ! {
+    rt::abort(b"[cowtest] panic")
+}
+
+fn fail(msg: &[u8]) -> ! {
+    for part in [&b"[cowtest] FAILED: "[..], msg, b"\n"] {
+        let _ = rt::write(FD_STDOUT, part);
+    }
+    rt::exit(1)
+}
+
+static mut DATA: u64 = 0x1111;
+
+struct Slots {
+    data: *mut u64,
+    stack: *mut u64,
+    mapped: *mut u64,
+}
+
+impl Slots {
+    fn read(&self) -> [u64; 3] {
+        unsafe {
+            [
+                read_volatile(self.data),
+                read_volatile(self.stack),
+                read_volatile(self.mapped),
+            ]
+        }
+    }
+
+    fn write(&self, values: [u64; 3]) {
+        unsafe {
+            write_volatile(self.data, values[0]);
+            write_volatile(self.stack, values[1]);
+            write_volatile(self.mapped, values[2]);
+        }
+    }
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn _start() -> ! {
+    let _ = rt::write(FD_STDOUT, b"[cowtest] checking copy-on-write fork\n");
+
+    let mapped = syscall6(
+        SYS_MMAP,
+        0,
+        4096,
+        u64::from(PROT_READ | PROT_WRITE),
+        u64::from(MAP_PRIVATE | MAP_ANONYMOUS),
+        u64::MAX,
+        0,
+    );
+    if mapped < 0 {
+        fail(b"mmap");
+    }
+    let mut stack_value = 0u64;
+    let slots = Slots {
+        data: &raw mut DATA,
+        stack: &raw mut stack_value,
+        mapped: mapped as *mut u64,
+    };
+    let before = [0x1111, 0x2222, 0x3333];
+    slots.write(before);
+
+    let pid = syscall3(SYS_FORK, 0, 0, 0);
+    if pid < 0 {
+        fail(b"fork");
+    }
+    if pid == 0 {
+        let inherited = slots.read() == before;
+        let mine = [0xc1, 0xc2, 0xc3];
+        slots.write(mine);
+        rt::exit(if inherited && slots.read() == mine {
+            0
+        } else {
+            2
+        });
+    }
+
+    let mine = [0xa1, 0xa2, 0xa3];
+    slots.write(mine);
+    let mut status = 0i32;
+    let waited = syscall3(SYS_WAIT4, pid as u64, &raw mut status as u64, 0);
+
+    if waited != pid || status != 0 {
+        fail(b"child saw parent writes or lost its own");
+    }
+    if slots.read() != mine {
+        fail(b"child writes leaked into parent");
+    }
+    let _ = rt::write(
+        FD_STDOUT,
+        b"[cowtest] ok: parent and child writes stayed private\n",
+    );
+    rt::exit(0)
+}
@@ -0,0 +1,50 @@
+#![no_std]
+#![no_main]
+
+use common::elf::{AT_BASE, AT_NULL};
+use common::syscall::FD_STDOUT;
+use core::arch::naked_asm;
+
+#[panic_handler]
+fn panic(_: &core::panic::PanicInfo<'_>) -> ! {
+    rt::abort(b"[dyntest] panic")
+}
+
+/// The `AT_BASE` entry of the auxiliary vector above `sp`.
+///
+/// # Safety
+/// `sp` must be the System V initial stack pointer.
+unsafe fn interpreter_base(sp: *const u64) -> u64 {
+    unsafe {
+        let mut word = sp.add(*sp as usize + 2);
+        while *word != 0 {
+            word = word.add(1);
+        }
+        word = word.add(1);
+        while *word != AT_NULL {
+            if *word == AT_BASE {
+                return *word.add(1);
+            }
+            word = word.add(2);
+        }
+    }
+    0
+}
+
+extern "C" fn main(sp: *const u64) -> ! {
+    if unsafe { interpreter_base(sp) } == 0 {
+        rt::abort(b"[dyntest] AT_BASE is 0; ld.so did not run");
+    }
+    // Every call below goes through librt.so.
+    let _ = rt::write(
+        FD_STDOUT,
+        b"[dyntest] ok: librt.so bound through /lib/ld.so\n",
+    );
+    rt::exit(0)
+}
+
+#[unsafe(naked)]
+#[unsafe(no_mangle)]
+pub extern "C" fn _start() -> ! {
+    naked_asm!("mov rdi, rsp", "call {main}", "ud2", main = sym main)
+}
@@ -1,25 +1,7 @@
-use std::env;
-use std::fs;
-use std::path::PathBuf;
-
+/// A PIE naming `/lib/ld.so` as its interpreter. There is no linker script:
+/// lld's default layout gives each segment its own pages.
 fn main() {
-    let out = PathBuf::from(env::var("OUT_DIR").expect("OUT_DIR"));
-    let script = out.join("link.ld");
-    fs::write(
-        &script,
-        r#"OUTPUT_FORMAT(elf64-x86-64)
-ENTRY(_start)
-SECTIONS
-{
-  . = 0xffffffff80400000;
-  .text : { *(.text*) }
-  .rodata : { *(.rodata*) }
-  .data : { *(.data*) }
-  .bss : { *(.bss*) *(COMMON) }
-}
-"#,
-    )
-    .expect("write linker script");
-
-    println!("cargo:rustc-link-arg-bin=fbfill=-T{}", script.display());
+    for arg in ["--dynamic-linker=/lib/ld.so", "--image-base=0x400000"] {
+        println!("cargo:rustc-link-arg-bin=fbfill={arg}");
+    }
 }
@@ -1,35 +1,12 @@
 #![no_std]
 #![no_main]
 
-use common::syscall::{SYS_EXIT, SYS_OPEN, SYS_READ, SYS_WRITE};
-use core::arch::asm;
+use common::syscall::{SYS_OPEN, SYS_READ, SYS_WRITE};
+use rt::syscall3;
 
 #[panic_handler]
 fn panic(_: &core::panic::PanicInfo<'_>) -> ! {
-    loop {
-        core::hint::spin_loop();
-    }
-}
-
-fn syscall3(n: u64, a: u64, b: u64, c: u64) -> isize {
-    let ret: i64;
-    unsafe {
-        asm!(
-            "int 0x80",
-            in("rax") n,
-            in("rdi") a,
-            in("rsi") b,
-            in("rdx") c,
-            lateout("rax") ret,
-            lateout("rcx") _,
-            lateout("r8") _,
-            lateout("r9") _,
-            lateout("r10") _,
-            lateout("r11") _,
-            options(nostack)
-        );
-    }
-    ret as isize
+    rt::abort(b"[fbfill] panic")
 }
 
 fn write_stdout(bytes: &[u8]) {
@@ -48,10 +25,7 @@ pub extern "C" fn _start() -> ! {
     let fd = syscall3(SYS_OPEN, path.len() as u64, path.as_ptr() as u64, 0);
     if fd < 0 {
         write_stdout(b"[fbfill] open /dev/fb0 failed\n");
-        let _ = syscall3(SYS_EXIT, 1, 0, 0);
-        loop {
-            unsafe { asm!("hlt") };
-        }
+        rt::exit(1)
     }
 
     let mut header = [0u8; 32];
@@ -63,10 +37,7 @@ pub extern "C" fn _start() -> ! {
     );
     if n != 32 {
         write_stdout(b"[fbfill] failed to read fb header\n");
-        let _ = syscall3(SYS_EXIT, 1, 0, 0);
-        loop {
-            unsafe { asm!("hlt") };
-        }
+        rt::exit(1)
     }
 
     let height = parse_u64_le(&header[8..16]) as usize;
@@ -75,10 +46,7 @@ pub extern "C" fn _start() -> ! {
 
     if height == 0 || pitch == 0 || bytes_per_pixel == 0 {
         write_stdout(b"[fbfill] invalid fb geometry\n");
-        let _ = syscall3(SYS_EXIT, 1, 0, 0);
-        loop {
-            unsafe { asm!("hlt") };
-        }
+        rt::exit(1)
     }
 
     let total = pitch.saturating_mul(height);
@@ -112,17 +80,11 @@ pub extern "C" fn _start() -> ! {
         let wrote = syscall3(SYS_WRITE, fd as u64, buf.as_ptr() as u64, chunk as u64);
         if wrote <= 0 {
             write_stdout(b"[fbfill] framebuffer write failed\n");
-            let _ = syscall3(SYS_EXIT, 1, 0, 0);
-            loop {
-                unsafe { asm!("hlt") };
-            }
+            rt::exit(1)
         }
         filled += wrote as usize;
     }
 
     write_stdout(b"[fbfill] filled framebuffer with blue\n");
-    let _ = syscall3(SYS_EXIT, 0, 0, 0);
-    loop {
-        unsafe { asm!("hlt") };
-    }
+    rt::exit(0)
 }
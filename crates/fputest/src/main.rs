@@ -0,0 +1,201 @@
+#![no_std]
+#![no_main]
+
+use common::syscall::{FD_STDOUT, SYS_FORK, SYS_WAIT4};
+use core::arch::asm;
+use core::arch::x86_64::__cpuid;
+use rt::syscall3;
+
+/// Rounds per process; the registers are reloaded from memory between them.
+const ROUNDS: u32 = 8;
+/// Additions per register per round, enough to be preempted mid-round.
+const SPINS: u64 = 500_000;
+/// xmm0-xmm14 accumulate; xmm15 holds the step.
+const SSE_LANES: usize = 15 * 2;
+const AVX_LANES: usize = 15 * 4;
+const MXCSR_DEFAULT: u32 = 0x1F80;
+/// Round toward zero, so the child's MXCSR differs from the parent's.
+const MXCSR_TRUNCATE: u32 = 0x7F80;
+const FORK_MARKER: u64 = 0x5eed_f00d_cafe_b0ba;
+
+const CPUID_OSXSAVE: u32 = 1 << 27;
+const CPUID_AVX: u32 = 1 << 28;
+const XCR0_SSE_AVX: u64 = 0b110;
+
+#[panic_handler]
+fn panic(_: &core::panic::PanicInfo<'_>) -> ! {
+    rt::abort(b"[fputest] panic")
+}
+
+fn fail(msg: &[u8]) -> ! {
+    for part in [&b"[fputest] FAILED: "[..], msg, b"\n"] {
+        let _ = rt::write(FD_STDOUT, part);
+    }
+    rt::exit(1)
+}
+
+/// Whether the kernel turned on AVX state (OSXSAVE set and ymm in XCR0).
+fn has_avx() -> bool {
+    let ecx = __cpuid(1).ecx;
+    if ecx & (CPUID_OSXSAVE | CPUID_AVX) != CPUID_OSXSAVE | CPUID_AVX {
+        return false;
+    }
+    let (lo, hi): (u32, u32);
+    unsafe { asm!("xgetbv", in("ecx") 0, out("eax") lo, out("edx") hi, options(nomem, nostack)) };
+    (u64::from(hi) << 32 | u64::from(lo)) & XCR0_SSE_AVX == XCR0_SSE_AVX
+}
+
+fn mxcsr() -> u32 {
+    let mut value = 0u32;
+    unsafe { asm!("stmxcsr [{}]", in(reg) &raw mut value, options(nostack)) };
+    value
+}
+
+fn set_mxcsr(value: u32) {
+    unsafe { asm!("ldmxcsr [{}]", in(reg) &raw const value, options(nostack)) };
+}
+
+/// Expands to a loop that loads the listed registers from `[{acc} + off]`,
+/// adds the step register to each `{spins}` times and stores them back. The
+/// values live only in registers while it runs, so a preemption that loses
+/// them shows up in the sums.
+macro_rules! accumulate {
+    (sse: $($n:literal @ $off:literal),*) => {
+        concat!(
+            $("movupd xmm", $n, ", [{acc} + ", $off, "]\n",)*
+            "movupd xmm15, [{step}]\n",
+            "2:\n",
+            $("addpd xmm", $n, ", xmm15\n",)*
+            "dec {spins}\n",
+            "jnz 2b\n",
+            $("movupd [{acc} + ", $off, "], xmm", $n, "\n",)*
+        )
+    };
+    (avx: $($n:literal @ $off:literal),*) => {
+        concat!(
+            $("vmovupd ymm", $n, ", [{acc} + ", $off, "]\n",)*
+            "vmovupd ymm15, [{step}]\n",
+            "2:\n",
+            $("vaddpd ymm", $n, ", ymm", $n, ", ymm15\n",)*
+            "dec {spins}\n",
+            "jnz 2b\n",
+            $("vmovupd [{acc} + ", $off, "], ymm", $n, "\n",)*
+            "vzeroupper\n",
+        )
+    };
+}
+
+// The crate is built without SSE, so compiled code never touches the vector
+// registers and the blocks below need not declare them clobbered.
+fn sse_round(acc: &mut [f64; SSE_LANES], step: &[f64; 2]) {
+    unsafe {
+        asm!(
+            accumulate!(sse:
+                0 @ 0, 1 @ 16, 2 @ 32, 3 @ 48, 4 @ 64, 5 @ 80, 6 @ 96, 7 @ 112,
+                8 @ 128, 9 @ 144, 10 @ 160, 11 @ 176, 12 @ 192, 13 @ 208, 14 @ 224
+            ),
+            acc = in(reg) acc.as_mut_ptr(),
+            step = in(reg) step.as_ptr(),
+            spins = inout(reg) SPINS => _,
+            options(nostack)
+        );
+    }
+}
+
+fn avx_round(acc: &mut [f64; AVX_LANES], step: &[f64; 4]) {
+    unsafe {
+        asm!(
+            accumulate!(avx:
+                0 @ 0, 1 @ 32, 2 @ 64, 3 @ 96, 4 @ 128, 5 @ 160, 6 @ 192, 7 @ 224,
+                8 @ 256, 9 @ 288, 10 @ 320, 11 @ 352, 12 @ 384, 13 @ 416, 14 @ 448
+            ),
+            acc = in(reg) acc.as_mut_ptr(),
+            step = in(reg) step.as_ptr(),
+            spins = inout(reg) SPINS => _,
+            options(nostack)
+        );
+    }
+}
+
+/// Lane `i` starts at `base + i`; every sum stays an exact multiple of
+/// `step`, so the expected bits can be worked out without the FPU.
+fn check(acc: &[f64], base: f64, step: f64) -> bool {
+    let added = f64::from(ROUNDS) * SPINS as f64 * step;
+    acc.iter()
+        .enumerate()
+        .all(|(i, v)| v.to_bits() == (base + i as f64 + added).to_bits())
+}
+
+fn work(base: f64, step: f64, avx: bool) -> Result<(), &'static [u8]> {
+    let mut sse = [0.0; SSE_LANES];
+    let mut wide = [0.0; AVX_LANES];
+    for (i, v) in sse.iter_mut().enumerate() {
+        *v = base + i as f64;
+    }
+    for (i, v) in wide.iter_mut().enumerate() {
+        *v = base + i as f64;
+    }
+    for _ in 0..ROUNDS {
+        sse_round(&mut sse, &[step; 2]);
+        if avx {
+            avx_round(&mut wide, &[step; 4]);
+        }
+    }
+    if !check(&sse, base, step) {
+        return Err(b"xmm sums are wrong");
+    }
+    if avx && !check(&wide, base, step) {
+        return Err(b"ymm sums are wrong");
+    }
+    Ok(())
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn _start() -> ! {
+    let _ = rt::write(FD_STDOUT, b"[fputest] running FP work in two processes\n");
+    if mxcsr() != MXCSR_DEFAULT {
+        fail(b"exec did not start with the default MXCSR");
+    }
+    let avx = has_avx();
+
+    unsafe { asm!("movq xmm15, {}", in(reg) FORK_MARKER, options(nomem, nostack)) };
+    let pid = syscall3(SYS_FORK, 0, 0, 0);
+    if pid < 0 {
+        fail(b"fork");
+    }
+    if pid == 0 {
+        let inherited: u64;
+        unsafe { asm!("movq {}, xmm15", out(reg) inherited, options(nomem, nostack)) };
+        if inherited != FORK_MARKER {
+            fail(b"child did not inherit xmm15 across fork");
+        }
+        set_mxcsr(MXCSR_TRUNCATE);
+        if let Err(msg) = work(1000.5, 0.25, avx) {
+            fail(msg);
+        }
+        if mxcsr() != MXCSR_TRUNCATE {
+            fail(b"child lost its MXCSR");
+        }
+        rt::exit(0);
+    }
+
+    if let Err(msg) = work(1.0, 1.0, avx) {
+        fail(msg);
+    }
+    if mxcsr() != MXCSR_DEFAULT {
+        fail(b"parent picked up the child's MXCSR");
+    }
+    let mut status = 0i32;
+    if syscall3(SYS_WAIT4, pid as u64, &raw mut status as u64, 0) != pid || status != 0 {
+        fail(b"child's FP state was corrupted");
+    }
+    let _ = rt::write(
+        FD_STDOUT,
+        if avx {
+            b"[fputest] ok: 2 processes kept their SSE and AVX registers\n"
+        } else {
+            b"[fputest] ok: 2 processes kept their SSE registers\n"
+        },
+    );
+    rt::exit(0)
+}
@@ -1,8 +1,8 @@
 #![no_std]
 #![no_main]
 
-use common::syscall::{FD_STDIN, FD_STDOUT};
-use core::arch::asm;
+use common::syscall::{FD_STDIN, FD_STDOUT, MAP_ANONYMOUS, MAP_PRIVATE, PROT_READ, PROT_WRITE};
+use core::ffi::CStr;
 
 mod syscall;
 
@@ -12,11 +12,32 @@ const SPAWN_TARGET: &str = if cfg!(feature = "test-build") {
     "/bin/shell.elf"
 };
 
+const SPAWN_ARGV: &[&CStr] = if cfg!(feature = "test-build") {
+    &[c"testbin.elf", c"hello", c"world"]
+} else {
+    &[c"shell"]
+};
+
+const SPAWN_ENVP: &[&CStr] = &[c"PATH=/bin", c"TERM=serial"];
+
+const TEST_PROGRAMS: &[&CStr] = if cfg!(feature = "test-build") {
+    &[
+        c"/bin/cowtest.elf",
+        c"/bin/threadtest.elf",
+        c"/bin/smptest.elf",
+        c"/bin/fputest.elf",
+        c"/bin/timetest.elf",
+        c"/bin/wxtest.elf",
+        c"/bin/mmtest.elf",
+        c"/bin/dyntest.elf",
+    ]
+} else {
+    &[]
+};
+
 #[panic_handler]
 fn panic(_: &core::panic::PanicInfo<'_>) -> ! {
-    loop {
-        core::hint::spin_loop();
-    }
+    rt::abort(b"[init] panic")
 }
 
 #[unsafe(no_mangle)]
@@ -26,7 +47,12 @@ pub extern "C" fn _start() -> ! {
         b"[init] hello from userspace via write() syscall\n",
     );
 
-    if let Ok(mapped) = syscall::memmap(16 * 1024) {
+    if let Ok(mapped) = syscall::mmap(
+        0,
+        16 * 1024,
+        PROT_READ | PROT_WRITE,
+        MAP_PRIVATE | MAP_ANONYMOUS,
+    ) {
         let _ = syscall::write(FD_STDOUT, b"[init] memmap ok at ");
         write_hex(mapped as usize);
         let _ = syscall::write(FD_STDOUT, b"\n");
@@ -34,24 +60,51 @@ pub extern "C" fn _start() -> ! {
 
     print_motd();
 
-    let _ = syscall::write(FD_STDOUT, b"[init] trying stack-process fork()\n");
-    let pid = syscall::fork(parent_resume as usize).unwrap_or(0);
-    if pid == 0 {
-        let _ = syscall::write(FD_STDOUT, b"[init] child process is now running\n");
-        let _ = syscall::write(FD_STDOUT, b"[init] child execve target: ");
-        let _ = syscall::write(FD_STDOUT, SPAWN_TARGET.as_bytes());
-        let _ = syscall::write(FD_STDOUT, b"\n");
-        let _ = syscall::execve(SPAWN_TARGET);
-        let _ = syscall::write(FD_STDOUT, b"[init] child execve failed, exiting\n");
-        let _ = syscall::exit(1);
+    let _ = syscall::write(FD_STDOUT, b"[init] trying fork()\n");
+    match syscall::fork() {
+        Ok(0) => {
+            let _ = syscall::write(FD_STDOUT, b"[init] child process is now running\n");
+            let _ = syscall::write(FD_STDOUT, b"[init] child execve target: ");
+            let _ = syscall::write(FD_STDOUT, SPAWN_TARGET.as_bytes());
+            let _ = syscall::write(FD_STDOUT, b"\n");
+            let _ = syscall::execve(SPAWN_TARGET, SPAWN_ARGV, SPAWN_ENVP);
+            let _ = syscall::write(FD_STDOUT, b"[init] child execve failed, exiting\n");
+            syscall::exit(1);
+        }
+        Ok(pid) => {
+            let mut status = 0;
+            let _ = syscall::wait(pid as i64, &mut status);
+            let _ = syscall::write(FD_STDOUT, b"[init] parent resumed after child exit\n");
+        }
+        Err(_) => {
+            let _ = syscall::write(FD_STDOUT, b"[init] fork failed\n");
+        }
     }
-
-    parent_resume()
+    run_test_programs();
+    interaction_and_shutdown()
 }
 
-extern "C" fn parent_resume() -> ! {
-    let _ = syscall::write(FD_STDOUT, b"[init] parent resumed after child exit\n");
-    interaction_and_shutdown()
+fn run_test_programs() {
+    for path in TEST_PROGRAMS {
+        let pid = match syscall::fork() {
+            Ok(pid) => pid,
+            Err(e) => {
+                let _ = syscall::write(FD_STDOUT, b"[init] fork failed (");
+                let _ = rt::write_decimal(FD_STDOUT, e.unsigned_abs() as u64);
+                let _ = syscall::write(FD_STDOUT, b"), skipping ");
+                let _ = syscall::write(FD_STDOUT, path.to_bytes());
+                let _ = syscall::write(FD_STDOUT, b"\n");
+                continue;
+            }
+        };
+        if pid == 0 {
+            let target = path.to_str().unwrap_or_default();
+            let _ = syscall::execve(target, &[path], SPAWN_ENVP);
+            syscall::exit(127);
+        }
+        let mut status = 0;
+        let _ = syscall::wait(pid as i64, &mut status);
+    }
 }
 
 fn print_motd() {
@@ -80,14 +133,7 @@ fn interaction_and_shutdown() -> ! {
     let _ = syscall::write(FD_STDOUT, b"[init] echo: ");
     let _ = syscall::write(FD_STDOUT, &buf[..n]);
     let _ = syscall::write(FD_STDOUT, b"[init] done\n");
-
-    unsafe {
-        asm!("out dx, al", in("dx") 0xF4u16, in("al") 0x10u8, options(nostack, nomem));
-    }
-
-    loop {
-        unsafe { asm!("hlt") };
-    }
+    syscall::exit(0)
 }
 
 fn write_hex(mut v: usize) {
@@ -1,30 +1,10 @@
-use core::arch::asm;
-
-use common::syscall::{SYS_EXECVE, SYS_EXIT, SYS_FORK, SYS_MEMMAP, SYS_OPEN, SYS_READ, SYS_WRITE};
-
-fn syscall3(n: u64, a: u64, b: u64, c: u64) -> isize {
-    let ret: i64;
-    unsafe {
-        asm!(
-            "int 0x80",
-            in("rax") n,
-            in("rdi") a,
-            in("rsi") b,
-            in("rdx") c,
-            lateout("rax") ret,
-            lateout("rcx") _,
-            lateout("r8") _,
-            lateout("r9") _,
-            lateout("r10") _,
-            lateout("r11") _,
-            options(nostack)
-        );
-    }
-    ret as isize
-}
+use core::ffi::CStr;
+
+use common::syscall::{SYS_EXECVE, SYS_FORK, SYS_MMAP, SYS_OPEN, SYS_READ, SYS_WAIT4};
+use rt::{syscall3, syscall4};
 
 pub fn write(fd: u64, bytes: &[u8]) -> Result<usize, isize> {
-    let ret = syscall3(SYS_WRITE, fd, bytes.as_ptr() as u64, bytes.len() as u64);
+    let ret = rt::write(fd, bytes);
     if ret < 0 { Err(ret) } else { Ok(ret as usize) }
 }
 
@@ -38,8 +18,16 @@ pub fn open(path: &str) -> Result<u64, isize> {
     if ret < 0 { Err(ret) } else { Ok(ret as u64) }
 }
 
-pub fn memmap(length: usize) -> Result<*mut u8, isize> {
-    let ret = syscall3(SYS_MEMMAP, length as u64, 0, 0);
+pub fn mmap(addr: usize, length: usize, prot: u32, flags: u32) -> Result<*mut u8, isize> {
+    let ret = rt::syscall6(
+        SYS_MMAP,
+        addr as u64,
+        length as u64,
+        u64::from(prot),
+        u64::from(flags),
+        u64::MAX,
+        0,
+    );
     if ret < 0 {
         Err(ret)
     } else {
@@ -47,17 +35,42 @@ pub fn memmap(length: usize) -> Result<*mut u8, isize> {
     }
 }
 
-pub fn fork(parent_resume_rip: usize) -> Result<usize, isize> {
-    let ret = syscall3(SYS_FORK, 0, parent_resume_rip as u64, 0);
+pub fn fork() -> Result<usize, isize> {
+    let ret = syscall3(SYS_FORK, 0, 0, 0);
     if ret < 0 { Err(ret) } else { Ok(ret as usize) }
 }
 
-pub fn execve(path: &str) -> Result<usize, isize> {
-    let ret = syscall3(SYS_EXECVE, path.len() as u64, path.as_ptr() as u64, 0);
+const EXEC_MAX_STRINGS: usize = 16;
+
+/// Builds the NULL-terminated pointer array execve expects.
+fn string_array(strings: &[&CStr]) -> Result<[u64; EXEC_MAX_STRINGS + 1], isize> {
+    if strings.len() > EXEC_MAX_STRINGS {
+        return Err(-7);
+    }
+    let mut array = [0u64; EXEC_MAX_STRINGS + 1];
+    for (slot, s) in array.iter_mut().zip(strings) {
+        *slot = s.as_ptr() as u64;
+    }
+    Ok(array)
+}
+
+pub fn execve(path: &str, argv: &[&CStr], envp: &[&CStr]) -> Result<usize, isize> {
+    let (argv, envp) = (string_array(argv)?, string_array(envp)?);
+    let ret = syscall4(
+        SYS_EXECVE,
+        path.len() as u64,
+        path.as_ptr() as u64,
+        argv.as_ptr() as u64,
+        envp.as_ptr() as u64,
+    );
     if ret < 0 { Err(ret) } else { Ok(ret as usize) }
 }
 
-pub fn exit(code: i32) -> Result<usize, isize> {
-    let ret = syscall3(SYS_EXIT, code as u64, 0, 0);
+pub fn wait(pid: i64, status: &mut i32) -> Result<usize, isize> {
+    let ret = syscall3(SYS_WAIT4, pid as u64, status as *mut i32 as u64, 0);
     if ret < 0 { Err(ret) } else { Ok(ret as usize) }
 }
+
+pub fn exit(code: i32) -> ! {
+    rt::exit(code)
+}
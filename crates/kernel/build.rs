@@ -1,5 +1,5 @@
 fn main() {
     let dir = std::env::var("CARGO_MANIFEST_DIR").expect("manifest dir");
     println!("cargo:rustc-link-arg=-T{dir}/linker.ld");
-    println!("cargo:rustc-link-arg=-no-pie");
+    println!("cargo:rustc-link-arg=-pie");
 }
@@ -0,0 +1,40 @@
+//! Finds ACPI tables from the RSDP Limine hands over. Tables are mapped
+//! read-only into a window of their own as they are looked at, since the
+//! HHDM need not cover ACPI memory.
+
+use crate::paging::{self, PAGE_SIZE, PageFlags};
+use common::acpi::{self, HEADER_SIZE, RSDP_SIZE};
+
+const ACPI_VA: usize = 0xffff_fc80_0000_0000;
+
+static mut NEXT_VA: usize = ACPI_VA;
+
+fn map(phys: usize, len: usize) -> Option<&'static [u8]> {
+    let start = phys & !(PAGE_SIZE - 1);
+    let end = phys.checked_add(len)?.next_multiple_of(PAGE_SIZE);
+    paging::reserve_kernel_region(ACPI_VA)?;
+    let va = unsafe { NEXT_VA };
+    for page in (start..end).step_by(PAGE_SIZE) {
+        paging::map_kernel_page(va + (page - start), page, PageFlags::NO_EXECUTE)?;
+    }
+    unsafe { NEXT_VA = va + (end - start) };
+    Some(unsafe { core::slice::from_raw_parts((va + (phys - start)) as *const u8, len) })
+}
+
+/// The table with `signature` listed by the RSDT or XSDT, checksum checked.
+pub fn find_table(rsdp: usize, signature: &[u8; 4]) -> Option<&'static [u8]> {
+    let rsdp = acpi::parse_rsdp(map(rsdp, RSDP_SIZE)?)?;
+    let root_sig = if rsdp.extended { b"XSDT" } else { b"RSDT" };
+    let root = map(rsdp.root as usize, HEADER_SIZE)?;
+    let root = acpi::parse_table(
+        map(rsdp.root as usize, acpi::table_length(root)?)?,
+        root_sig,
+    )?;
+    acpi::root_entries(root, rsdp.extended).find_map(|phys| {
+        let header = map(phys as usize, HEADER_SIZE)?;
+        if !acpi::has_signature(header, signature) {
+            return None;
+        }
+        acpi::parse_table(map(phys as usize, acpi::table_length(header)?)?, signature)
+    })
+}
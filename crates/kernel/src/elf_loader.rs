@@ -1,160 +1,325 @@
-use common::elf::ProgramHeader;
-use core::ptr;
+use crate::memory;
+use crate::paging::{self, PAGE_SIZE, PageFlags, USER_SPACE_END};
+use crate::tty::TTY;
+use alloc::vec::Vec;
+use common::elf::{
+    ET_DYN, ElfImage, Fixup, PF_W, PF_X, PT_GNU_STACK, PT_TLS, ProgramHeader, RelocError,
+};
+use common::syscall::{MAP_ANONYMOUS, MAP_PRIVATE, PROT_EXEC, PROT_READ, PROT_WRITE};
+use common::vma::{MemoryMap, Vma, VmaKind, page_align_up};
+use core::arch::global_asm;
+use core::fmt::Write;
 
-const INIT_LOAD_BUF_SIZE: usize = 2 * 1024 * 1024;
-const INIT_LOAD_SLOTS: usize = 16;
-static mut INIT_LOAD_BUFS: [[u8; INIT_LOAD_BUF_SIZE]; INIT_LOAD_SLOTS] =
-    [[0; INIT_LOAD_BUF_SIZE]; INIT_LOAD_SLOTS];
-static mut NEXT_LOAD_SLOT: usize = 0;
+const ENOEXEC: i64 = -8;
+const ENOMEM: i64 = -12;
+/// Room for the TCB self-pointer and the stack-protector canary at `fs:0x28`.
+const TCB_SIZE: usize = 64;
 
-fn rd16(b: &[u8], o: usize) -> Option<u16> {
-    Some(u16::from_le_bytes([*b.get(o)?, *b.get(o + 1)?]))
-}
+// Runs IRELATIVE and ifunc resolvers in user mode, then enters the program.
+// It is copied into its own page followed by the table it walks: a count,
+// the entry point and `count` (slot, resolver) pairs.
+global_asm!(
+    r#"
+.pushsection .rodata
+.balign 8
+.global resolve_stub_start
+resolve_stub_start:
+    lea rbx, [rip + resolve_stub_end]
+    mov r12, [rbx]
+    mov r13, [rbx + 8]
+    add rbx, 16
+2:
+    test r12, r12
+    jz 3f
+    call qword ptr [rbx + 8]
+    mov rcx, [rbx]
+    mov [rcx], rax
+    add rbx, 16
+    dec r12
+    jmp 2b
+3:
+    push r13
+    xor eax, eax
+    xor ecx, ecx
+    xor edx, edx
+    xor ebx, ebx
+    xor r12d, r12d
+    xor r13d, r13d
+    ret
+.balign 8
+.global resolve_stub_end
+resolve_stub_end:
+.popsection
+"#
+);
 
-fn rd32(b: &[u8], o: usize) -> Option<u32> {
-    Some(u32::from_le_bytes([
-        *b.get(o)?,
-        *b.get(o + 1)?,
-        *b.get(o + 2)?,
-        *b.get(o + 3)?,
-    ]))
+unsafe extern "C" {
+    static resolve_stub_start: u8;
+    static resolve_stub_end: u8;
 }
 
-fn rd64(b: &[u8], o: usize) -> Option<u64> {
-    Some(u64::from_le_bytes([
-        *b.get(o)?,
-        *b.get(o + 1)?,
-        *b.get(o + 2)?,
-        *b.get(o + 3)?,
-        *b.get(o + 4)?,
-        *b.get(o + 5)?,
-        *b.get(o + 6)?,
-        *b.get(o + 7)?,
-    ]))
+/// A mapped image: `entry` is its ELF entry point, `start` where its first
+/// thread begins (the resolver stub when it has one) and `base` the address
+/// its lowest segment landed at.
+pub struct LoadedElf {
+    pub entry: usize,
+    pub start: usize,
+    pub base: usize,
 }
 
-pub fn load_init_image(
-    bytes: &[u8],
-    headers: &[Option<ProgramHeader>; 32],
-    entry: usize,
-) -> Option<usize> {
-    let mut min_vaddr = usize::MAX;
-    let mut max_vaddr = 0usize;
+fn pages(hdr: &ProgramHeader) -> impl Iterator<Item = usize> {
+    let start = hdr.virt_addr & !(PAGE_SIZE - 1);
+    (start..hdr.virt_addr + hdr.mem_size).step_by(PAGE_SIZE)
+}
 
-    for hdr in headers.iter().flatten() {
-        min_vaddr = min_vaddr.min(hdr.virt_addr);
-        max_vaddr = max_vaddr.max(hdr.virt_addr.checked_add(hdr.mem_size)?);
+fn segment_prot(hdr: &ProgramHeader) -> u32 {
+    let mut prot = PROT_READ;
+    if hdr.flags & PF_W != 0 {
+        prot |= PROT_WRITE;
     }
+    if hdr.flags & PF_X != 0 {
+        prot |= PROT_EXEC;
+    }
+    prot
+}
 
-    if min_vaddr == usize::MAX || max_vaddr <= min_vaddr {
-        return None;
+/// How far to shift the image from its link addresses: a random bias for PIE
+/// (`ET_DYN`) images, 0 for fixed-address ones.
+pub fn load_bias(image: &ElfImage<'_>) -> usize {
+    if image.kind != ET_DYN {
+        return 0;
     }
+    let link_base = image.program_headers().map(|hdr| hdr.virt_addr).min();
+    link_base.map_or(0, memory::pie_bias)
+}
 
-    let image_size = max_vaddr - min_vaddr;
-    if image_size > INIT_LOAD_BUF_SIZE {
-        return None;
+/// The bias that puts an interpreter in a free part of the mmap area, clear
+/// of the program it serves; 0 for fixed-address interpreters.
+pub fn interpreter_bias(image: &ElfImage<'_>, memory: &MemoryMap) -> Result<usize, i64> {
+    if image.kind != ET_DYN {
+        return Ok(0);
     }
+    let start = image
+        .program_headers()
+        .map(|hdr| hdr.virt_addr & !(PAGE_SIZE - 1))
+        .min();
+    let end = image
+        .program_headers()
+        .map(|hdr| hdr.virt_addr.saturating_add(hdr.mem_size))
+        .max();
+    let (Some(start), Some(end)) = (start, end) else {
+        return Err(ENOEXEC);
+    };
+    let len = page_align_up(end.checked_sub(start).ok_or(ENOEXEC)?);
+    let at = memory::free_area(memory, len).ok_or(ENOMEM)?;
+    at.checked_sub(start).ok_or(ENOEXEC)
+}
 
-    let base = unsafe {
-        let slot = NEXT_LOAD_SLOT % INIT_LOAD_SLOTS;
-        NEXT_LOAD_SLOT = NEXT_LOAD_SLOT.wrapping_add(1);
-        core::ptr::addr_of_mut!(INIT_LOAD_BUFS[slot]) as *mut u8
+/// Maps `image` shifted by `bias`. Images with a `PT_INTERP` are left for
+/// their interpreter to relocate.
+pub fn load_image(
+    image: &ElfImage<'_>,
+    pml4: usize,
+    memory: &mut MemoryMap,
+    bias: usize,
+) -> Result<LoadedElf, i64> {
+    let bytes = image.data;
+    if image
+        .program_headers()
+        .any(|hdr| hdr.virt_addr.checked_add(bias).is_none())
+    {
+        return Err(ENOEXEC);
+    }
+    let headers = || {
+        image.program_headers().map(move |hdr| ProgramHeader {
+            virt_addr: hdr.virt_addr + bias,
+            ..hdr
+        })
     };
+    // Segments start out read-only and NX; their p_flags are applied once
+    // loading and relocation are done.
+    let base = PageFlags::USER | PageFlags::NO_EXECUTE;
+    let mut loaded = false;
 
-    unsafe { ptr::write_bytes(base, 0, image_size) };
+    for hdr in headers() {
+        let seg_end = hdr.virt_addr.checked_add(hdr.mem_size).ok_or(ENOEXEC)?;
+        if seg_end > USER_SPACE_END || hdr.file_size > hdr.mem_size {
+            return Err(ENOEXEC);
+        }
+        if hdr.flags & (PF_W | PF_X) == PF_W | PF_X {
+            return Err(ENOEXEC);
+        }
+        let src_end = hdr.file_offset.checked_add(hdr.file_size).ok_or(ENOEXEC)?;
+        let src = bytes.get(hdr.file_offset..src_end).ok_or(ENOEXEC)?;
 
-    for hdr in headers.iter().flatten() {
-        let src_end = hdr.file_offset.checked_add(hdr.file_size)?;
-        if src_end > bytes.len() {
-            return None;
+        // Only pages holding file bytes are mapped now; the rest of .bss is
+        // left to the page fault handler.
+        if hdr.file_size > 0 {
+            paging::map_zeroed(pml4, hdr.virt_addr, hdr.file_size, base).ok_or(ENOMEM)?;
+            paging::copy_into(pml4, hdr.virt_addr, src).ok_or(ENOMEM)?;
         }
+        loaded = true;
+    }
 
-        let dst_off = hdr.virt_addr.checked_sub(min_vaddr)?;
-        if dst_off.checked_add(hdr.mem_size)? > image_size {
-            return None;
+    if !loaded {
+        return Err(ENOEXEC);
+    }
+
+    let mut resolves = Vec::new();
+    if image.kind == ET_DYN && image.interpreter().is_none() {
+        for fixup in image.relocations(bias).map_err(reloc_error)? {
+            match fixup.map_err(reloc_error)? {
+                Fixup::Write { addr, value } => {
+                    paging::copy_into(pml4, addr, &value.to_le_bytes()).ok_or(ENOEXEC)?;
+                }
+                Fixup::Resolve { addr, resolver } => {
+                    resolves.try_reserve(1).map_err(|_| ENOMEM)?;
+                    resolves.push((addr, resolver));
+                }
+            }
         }
+    }
 
-        let src = unsafe { bytes.as_ptr().add(hdr.file_offset) };
-        unsafe {
-            ptr::copy_nonoverlapping(src, base.add(dst_off), hdr.file_size);
-            if hdr.mem_size > hdr.file_size {
-                ptr::write_bytes(
-                    base.add(dst_off + hdr.file_size),
-                    0,
-                    hdr.mem_size - hdr.file_size,
-                );
+    for hdr in headers() {
+        for page in pages(&hdr) {
+            let Some(mut flags) = paging::user_flags(pml4, page) else {
+                continue;
+            };
+            if hdr.flags & PF_W != 0 {
+                flags |= PageFlags::WRITABLE;
+            }
+            if hdr.flags & PF_X != 0 {
+                flags.remove(PageFlags::NO_EXECUTE);
             }
+            // Segments sharing a page must not make it both writable and executable.
+            if flags.contains(PageFlags::WRITABLE) && !flags.contains(PageFlags::NO_EXECUTE) {
+                return Err(ENOEXEC);
+            }
+            paging::set_flags(pml4, page, flags).ok_or(ENOEXEC)?;
         }
     }
 
-    let e_type = rd16(bytes, 16)?;
-    if e_type == 3 {
-        apply_relative_relocations(bytes, base as usize, min_vaddr)?;
+    let mut image_start = usize::MAX;
+    let mut image_end = 0;
+    for hdr in headers() {
+        for page in pages(&hdr) {
+            if memory.find(page).is_some() {
+                continue;
+            }
+            let prot =
+                paging::user_flags(pml4, page).map_or_else(|| segment_prot(&hdr), memory::prot_of);
+            memory
+                .insert(Vma::new(page, page + PAGE_SIZE, prot, VmaKind::Image))
+                .map_err(|_| ENOMEM)?;
+        }
+        image_start = image_start.min(hdr.virt_addr & !(PAGE_SIZE - 1));
+        image_end = image_end.max(hdr.virt_addr + hdr.mem_size);
     }
+    memory.set_heap_start(page_align_up(image_end));
 
-    entry
-        .checked_sub(min_vaddr)
-        .map(|entry_off| base as usize + entry_off)
+    let entry = image.entry.checked_add(bias).ok_or(ENOEXEC)?;
+    let start = if resolves.is_empty() {
+        entry
+    } else {
+        map_resolve_stub(pml4, memory, entry, &resolves)?
+    };
+    Ok(LoadedElf {
+        entry,
+        start,
+        base: image_start,
+    })
 }
 
-fn apply_relative_relocations(bytes: &[u8], base: usize, min_vaddr: usize) -> Option<()> {
-    let phoff = rd64(bytes, 32)? as usize;
-    let phentsize = rd16(bytes, 54)? as usize;
-    let phnum = rd16(bytes, 56)? as usize;
-
-    let mut rela_vaddr = 0usize;
-    let mut rela_size = 0usize;
-    let mut rela_ent = 24usize;
-
-    for i in 0..phnum {
-        let o = phoff + i * phentsize;
-        if rd32(bytes, o)? != 2 {
-            continue;
+fn reloc_error(err: RelocError) -> i64 {
+    let _ = match err {
+        RelocError::Malformed => writeln!(TTY.lock(), "[kernel] exec: malformed dynamic section"),
+        RelocError::Unsupported(kind) => {
+            writeln!(
+                TTY.lock(),
+                "[kernel] exec: unsupported relocation type {kind}"
+            )
         }
-        let dyn_off = rd64(bytes, o + 8)? as usize;
-        let dyn_size = rd64(bytes, o + 32)? as usize;
-        let end = dyn_off.checked_add(dyn_size)?;
-        if end > bytes.len() {
-            return None;
+        RelocError::Undefined(index) => {
+            writeln!(
+                TTY.lock(),
+                "[kernel] exec: undefined symbol #{index} needs an interpreter"
+            )
         }
+    };
+    ENOEXEC
+}
 
-        let mut d = dyn_off;
-        while d + 16 <= end {
-            let tag = rd64(bytes, d)? as i64;
-            let val = rd64(bytes, d + 8)? as usize;
-            match tag {
-                0 => break,
-                7 => rela_vaddr = val,
-                8 => rela_size = val,
-                9 => rela_ent = val,
-                _ => {}
-            }
-            d += 16;
-        }
+/// Maps the resolver stub and its table; the process starts there.
+fn map_resolve_stub(
+    pml4: usize,
+    memory: &mut MemoryMap,
+    entry: usize,
+    resolves: &[(usize, usize)],
+) -> Result<usize, i64> {
+    // The stub stores into the slots from user mode.
+    let writable = |addr: usize| {
+        memory
+            .find(addr)
+            .is_some_and(|vma| vma.prot & PROT_WRITE != 0 && addr + 8 <= vma.end)
+    };
+    if !resolves.iter().all(|&(addr, _)| writable(addr)) {
+        return Err(ENOEXEC);
     }
-
-    if rela_vaddr == 0 || rela_size == 0 || rela_ent == 0 {
-        return Some(());
+    let code = unsafe {
+        let start = &raw const resolve_stub_start;
+        let len = (&raw const resolve_stub_end).offset_from(start) as usize;
+        core::slice::from_raw_parts(start, len)
+    };
+    let mut bytes = Vec::new();
+    bytes
+        .try_reserve_exact(code.len() + 16 * (resolves.len() + 1))
+        .map_err(|_| ENOMEM)?;
+    bytes.extend_from_slice(code);
+    for (a, b) in [(resolves.len(), entry)].iter().chain(resolves) {
+        bytes.extend_from_slice(&(*a as u64).to_le_bytes());
+        bytes.extend_from_slice(&(*b as u64).to_le_bytes());
     }
 
-    let rela_off = rela_vaddr.checked_sub(min_vaddr)?;
-    let mut off = base.checked_add(rela_off)?;
-    let end = off.checked_add(rela_size)?;
+    let rw = PROT_READ | PROT_WRITE;
+    let flags = MAP_PRIVATE | MAP_ANONYMOUS;
+    let stub = memory::mmap(pml4, memory, 0, bytes.len(), rw, flags, None)?;
+    memory::write_user(pml4, memory, stub, &bytes).ok_or(ENOMEM)?;
+    memory::mprotect(pml4, memory, stub, bytes.len(), PROT_READ | PROT_EXEC)?;
+    Ok(stub)
+}
 
-    while off < end {
-        let r_offset = unsafe { *(off as *const u64) } as usize;
-        let r_info = unsafe { *((off + 8) as *const u64) };
-        let r_addend = unsafe { *((off + 16) as *const i64) } as isize;
+/// Builds the initial TLS block from `PT_TLS` (x86-64 variant II: the block
+/// sits just below the thread pointer, which points at a self-pointer) and
+/// returns the thread pointer, or 0 without `PT_TLS`.
+pub fn setup_tls(image: &ElfImage<'_>, pml4: usize, memory: &mut MemoryMap) -> Result<usize, i64> {
+    let Some(tls) = image.segment(PT_TLS) else {
+        return Ok(0);
+    };
+    let align = tls.align.max(8);
+    if !align.is_power_of_two() || align > PAGE_SIZE || tls.file_size > tls.mem_size {
+        return Err(ENOEXEC);
+    }
+    let src_end = tls.file_offset.checked_add(tls.file_size).ok_or(ENOEXEC)?;
+    let src = image.data.get(tls.file_offset..src_end).ok_or(ENOEXEC)?;
+    let block = tls.mem_size.checked_add(align - 1).ok_or(ENOEXEC)? & !(align - 1);
+    let len = block.checked_add(TCB_SIZE).ok_or(ENOEXEC)?;
 
-        let r_type = (r_info & 0xffff_ffff) as u32;
-        if r_type == 8 {
-            let dst = base.checked_add(r_offset.checked_sub(min_vaddr)?)? as *mut u64;
-            let val = (base as isize + r_addend) as u64;
-            unsafe { *dst = val };
-        }
+    let flags = MAP_PRIVATE | MAP_ANONYMOUS;
+    let area = memory::mmap(pml4, memory, 0, len, PROT_READ | PROT_WRITE, flags, None)?;
+    let tp = area + block;
+    memory::write_user(pml4, memory, area, src).ok_or(ENOMEM)?;
+    memory::write_user(pml4, memory, tp, &(tp as u64).to_le_bytes()).ok_or(ENOMEM)?;
+    Ok(tp)
+}
 
-        off = off.checked_add(rela_ent)?;
+/// How much stack to reserve up front: `PT_GNU_STACK`'s size when it asks
+/// for more than the default. Executable stacks are refused.
+pub fn stack_size(image: &ElfImage<'_>) -> Result<usize, i64> {
+    let Some(hdr) = image.segment(PT_GNU_STACK) else {
+        return Ok(memory::USER_STACK_SIZE);
+    };
+    if hdr.flags & PF_X != 0 {
+        return Err(ENOEXEC);
     }
-
-    Some(())
+    Ok(page_align_up(hdr.mem_size).clamp(memory::USER_STACK_SIZE, memory::USER_STACK_LIMIT))
 }
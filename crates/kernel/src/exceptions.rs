@@ -0,0 +1,272 @@
+use crate::interrupts::TrapFrame;
+use crate::serial::SerialWriter;
+use crate::tty::TTY;
+use crate::{memory, sched, uaccess};
+use core::arch::{asm, global_asm};
+use core::fmt::Write;
+
+pub const EXCEPTION_COUNT: usize = 32;
+pub const NMI_VECTOR: u64 = 2;
+pub const DOUBLE_FAULT_VECTOR: u64 = 8;
+pub const PAGE_FAULT_VECTOR: u64 = 14;
+const GENERAL_PROTECTION_VECTOR: u64 = 13;
+
+const PF_PRESENT: u64 = 1 << 0;
+const PF_WRITE: u64 = 1 << 1;
+const PF_INSTRUCTION: u64 = 1 << 4;
+
+const SIGILL: i32 = 4;
+const SIGTRAP: i32 = 5;
+const SIGBUS: i32 = 7;
+const SIGFPE: i32 = 8;
+const SIGSEGV: i32 = 11;
+
+global_asm!(
+    r#"
+.macro exception_stub vector, error_code
+exception_\vector:
+.if \error_code == 0
+    push 0
+.endif
+    push \vector
+    jmp trap_common
+.endm
+
+exception_stub 0, 0
+exception_stub 1, 0
+exception_stub 2, 0
+exception_stub 3, 0
+exception_stub 4, 0
+exception_stub 5, 0
+exception_stub 6, 0
+exception_stub 7, 0
+exception_stub 8, 1
+exception_stub 9, 0
+exception_stub 10, 1
+exception_stub 11, 1
+exception_stub 12, 1
+exception_stub 13, 1
+exception_stub 14, 1
+exception_stub 15, 0
+exception_stub 16, 0
+exception_stub 17, 1
+exception_stub 18, 0
+exception_stub 19, 0
+exception_stub 20, 0
+exception_stub 21, 1
+exception_stub 22, 0
+exception_stub 23, 0
+exception_stub 24, 0
+exception_stub 25, 0
+exception_stub 26, 0
+exception_stub 27, 0
+exception_stub 28, 0
+exception_stub 29, 1
+exception_stub 30, 1
+exception_stub 31, 0
+
+.pushsection .data.rel.ro, "aw"
+.balign 8
+.global exception_stubs
+exception_stubs:
+.irp vector, 0,1,2,3,4,5,6,7,8,9,10,11,12,13,14,15,16,17,18,19,20,21,22,23,24,25,26,27,28,29,30,31
+    .quad exception_\vector
+.endr
+.popsection
+"#
+);
+
+unsafe extern "C" {
+    static exception_stubs: [u64; EXCEPTION_COUNT];
+}
+
+pub fn stubs() -> &'static [u64; EXCEPTION_COUNT] {
+    unsafe { &exception_stubs }
+}
+
+const NAMES: [&str; EXCEPTION_COUNT] = [
+    "#DE divide error",
+    "#DB debug",
+    "NMI",
+    "#BP breakpoint",
+    "#OF overflow",
+    "#BR bound range exceeded",
+    "#UD invalid opcode",
+    "#NM device not available",
+    "#DF double fault",
+    "coprocessor segment overrun",
+    "#TS invalid TSS",
+    "#NP segment not present",
+    "#SS stack-segment fault",
+    "#GP general protection fault",
+    "#PF page fault",
+    "reserved",
+    "#MF x87 floating-point exception",
+    "#AC alignment check",
+    "#MC machine check",
+    "#XM SIMD floating-point exception",
+    "#VE virtualization exception",
+    "#CP control protection exception",
+    "reserved",
+    "reserved",
+    "reserved",
+    "reserved",
+    "reserved",
+    "reserved",
+    "#HV hypervisor injection exception",
+    "#VC VMM communication exception",
+    "#SX security exception",
+    "reserved",
+];
+
+fn signal_for(vector: u64) -> i32 {
+    match vector {
+        0 | 16 | 19 => SIGFPE,
+        1 | 3 => SIGTRAP,
+        6 => SIGILL,
+        17 => SIGBUS,
+        _ => SIGSEGV,
+    }
+}
+
+fn read_cr2() -> usize {
+    let cr2: usize;
+    unsafe { asm!("mov {}, cr2", out(reg) cr2, options(nomem, nostack, preserves_flags)) };
+    cr2
+}
+
+fn dump(frame: &TrapFrame, cr2: usize, pid: Option<u64>, tid: Option<u64>) {
+    let mut tty = TTY.lock();
+    let _ = write!(
+        tty,
+        "[kernel] exception {} (vector {}) error={:#x} in {} mode",
+        NAMES[frame.vector as usize],
+        frame.vector,
+        frame.error_code,
+        if frame.is_user() { "user" } else { "kernel" }
+    );
+    match (pid, tid) {
+        (Some(pid), Some(tid)) => {
+            let _ = writeln!(tty, ", pid={pid} tid={tid}");
+        }
+        (None, Some(tid)) => {
+            let _ = writeln!(tty, ", tid={tid}");
+        }
+        (_, None) => {
+            let _ = writeln!(tty, ", no process");
+        }
+    }
+    let rows = [
+        [
+            ("rip", frame.rip),
+            ("rsp", frame.rsp),
+            ("rflags", frame.rflags),
+            ("cr2", cr2 as u64),
+        ],
+        [
+            ("rax", frame.rax),
+            ("rbx", frame.rbx),
+            ("rcx", frame.rcx),
+            ("rdx", frame.rdx),
+        ],
+        [
+            ("rsi", frame.rsi),
+            ("rdi", frame.rdi),
+            ("rbp", frame.rbp),
+            ("r8", frame.r8),
+        ],
+        [
+            ("r9", frame.r9),
+            ("r10", frame.r10),
+            ("r11", frame.r11),
+            ("r12", frame.r12),
+        ],
+        [
+            ("r13", frame.r13),
+            ("r14", frame.r14),
+            ("r15", frame.r15),
+            ("cs", frame.cs),
+        ],
+        [
+            ("ss", frame.ss),
+            ("ds", frame.ds),
+            ("es", frame.es),
+            ("fs", frame.fs),
+        ],
+    ];
+    for row in rows {
+        let _ = write!(tty, "  ");
+        for (name, value) in row {
+            let _ = write!(tty, " {name:>6}={value:#018x}");
+        }
+        let _ = writeln!(tty);
+    }
+}
+
+/// Reports an NMI that was not a shootdown and carries on. The interrupted
+/// code may hold the TTY lock, so this writes straight to the serial port.
+pub fn unexpected_nmi(rip: u64) {
+    let _ = writeln!(SerialWriter, "[kernel] unexpected NMI at rip={rip:#x}");
+}
+
+pub fn handle(frame: &mut TrapFrame) {
+    let cr2 = if frame.vector == PAGE_FAULT_VECTOR {
+        read_cr2()
+    } else {
+        0
+    };
+    // Only user code and the uaccess copy write to user pages, and neither
+    // runs with the process table locked.
+    if frame.vector == PAGE_FAULT_VECTOR
+        && frame.error_code & (PF_PRESENT | PF_WRITE) == PF_PRESENT | PF_WRITE
+        && (frame.is_user() || uaccess::fixup(frame.rip).is_some())
+        && memory::resolve_copy_on_write_current(cr2)
+    {
+        return;
+    }
+    if frame.vector == PAGE_FAULT_VECTOR
+        && frame.is_user()
+        && frame.error_code & PF_PRESENT == 0
+        && memory::fault_in_current(
+            cr2,
+            frame.error_code & PF_WRITE != 0,
+            frame.error_code & PF_INSTRUCTION != 0,
+        )
+    {
+        return;
+    }
+    if !frame.is_user()
+        && matches!(frame.vector, PAGE_FAULT_VECTOR | GENERAL_PROTECTION_VECTOR)
+        && let Some(resume) = uaccess::fixup(frame.rip)
+    {
+        frame.rip = resume;
+        return;
+    }
+
+    let pid = if frame.is_user() {
+        sched::processes().current_pid()
+    } else {
+        sched::try_processes().and_then(|table| table.current_pid())
+    };
+    dump(frame, cr2, pid, sched::running());
+    if !frame.is_user() || frame.vector == DOUBLE_FAULT_VECTOR {
+        panic!(
+            "unrecoverable {} in kernel mode",
+            NAMES[frame.vector as usize]
+        );
+    }
+
+    let status = 128 + signal_for(frame.vector);
+    sched::save_current(frame);
+    if let Some(pid) = sched::exit_current(status) {
+        let _ = writeln!(
+            TTY.lock(),
+            "[kernel] pid={} killed by {} (exit status {})",
+            pid,
+            NAMES[frame.vector as usize],
+            status
+        );
+    }
+    sched::schedule(false);
+    sched::load_current(frame);
+}
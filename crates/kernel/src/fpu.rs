@@ -0,0 +1,185 @@
+//! x87/SSE/AVX state. The kernel itself is built without SSE, so the
+//! registers always hold the running thread's values; the scheduler saves
+//! them into the outgoing thread's area and loads the incoming one's on every
+//! switch.
+
+use alloc::vec::Vec;
+use common::process::{FpuArea, ProcessError, ProcessTable};
+use core::arch::asm;
+use core::arch::x86_64::{__cpuid, __cpuid_count};
+
+const CR0_MONITOR_COPROCESSOR: u64 = 1 << 1;
+const CR0_EMULATION: u64 = 1 << 2;
+const CR0_TASK_SWITCHED: u64 = 1 << 3;
+const CR0_NUMERIC_ERROR: u64 = 1 << 5;
+const CR4_OSFXSR: u64 = 1 << 9;
+const CR4_OSXMMEXCPT: u64 = 1 << 10;
+const CR4_OSXSAVE: u64 = 1 << 18;
+
+const CPUID_XSAVE: u32 = 1 << 26;
+const CPUID_AVX: u32 = 1 << 28;
+const CPUID_XSAVE_LEAF: u32 = 0xD;
+
+const XCR0_X87: u64 = 1 << 0;
+const XCR0_SSE: u64 = 1 << 1;
+const XCR0_AVX: u64 = 1 << 2;
+
+/// `fxsave` always writes 512 bytes.
+const FXSAVE_SIZE: usize = 512;
+const FCW_OFFSET: usize = 0;
+const MXCSR_OFFSET: usize = 24;
+/// All x87 exceptions masked, 64-bit precision, round to nearest.
+const FCW_DEFAULT: u16 = 0x037F;
+/// All SSE exceptions masked, round to nearest.
+const MXCSR_DEFAULT: u32 = 0x1F80;
+
+/// The state components `xsave` covers, or 0 when only `fxsave` is there.
+static mut XCR0: u64 = 0;
+/// What a new image starts with.
+static mut TEMPLATE: FpuArea = FpuArea::empty();
+
+pub struct FpuSupport {
+    pub area_size: usize,
+    pub xsave: bool,
+    pub avx: bool,
+}
+
+/// Picks the state to manage from CPUID, sets up the boot CPU and builds the
+/// initial state new images get. Needs the heap.
+pub fn init() -> Result<FpuSupport, ProcessError> {
+    let features = __cpuid(1).ecx;
+    let mut xcr0 = 0;
+    if features & CPUID_XSAVE != 0 {
+        let supported = u64::from(__cpuid_count(CPUID_XSAVE_LEAF, 0).eax);
+        xcr0 = XCR0_X87 | XCR0_SSE;
+        if features & CPUID_AVX != 0 {
+            xcr0 |= XCR0_AVX;
+        }
+        xcr0 &= supported;
+    }
+    unsafe { XCR0 = xcr0 };
+    init_cpu();
+
+    // With XCR0 set, EBX is the area size for the enabled components.
+    let area_size = if xcr0 == 0 {
+        FXSAVE_SIZE
+    } else {
+        __cpuid_count(CPUID_XSAVE_LEAF, 0).ebx as usize
+    };
+    let mut bytes = Vec::new();
+    bytes
+        .try_reserve_exact(area_size)
+        .map_err(|_| ProcessError::OutOfMemory)?;
+    bytes.resize(area_size, 0);
+    bytes[FCW_OFFSET..FCW_OFFSET + 2].copy_from_slice(&FCW_DEFAULT.to_le_bytes());
+    bytes[MXCSR_OFFSET..MXCSR_OFFSET + 4].copy_from_slice(&MXCSR_DEFAULT.to_le_bytes());
+    // The XSAVE header stays zero, so `xrstor` puts every other component
+    // in its initial state.
+    unsafe { TEMPLATE = FpuArea::new(&bytes)? };
+    reset();
+    Ok(FpuSupport {
+        area_size,
+        xsave: xcr0 != 0,
+        avx: xcr0 & XCR0_AVX != 0,
+    })
+}
+
+/// Enables SSE (and XSAVE, if `init` chose it) on this CPU.
+pub fn init_cpu() {
+    let xcr0 = unsafe { XCR0 };
+    let mut cr4 = CR4_OSFXSR | CR4_OSXMMEXCPT;
+    if xcr0 != 0 {
+        cr4 |= CR4_OSXSAVE;
+    }
+    unsafe {
+        asm!(
+            "mov {tmp}, cr0",
+            "and {tmp}, {clear}",
+            "or {tmp}, {set}",
+            "mov cr0, {tmp}",
+            "mov {tmp}, cr4",
+            "or {tmp}, {cr4}",
+            "mov cr4, {tmp}",
+            tmp = out(reg) _,
+            clear = in(reg) !(CR0_EMULATION | CR0_TASK_SWITCHED),
+            set = const CR0_MONITOR_COPROCESSOR | CR0_NUMERIC_ERROR,
+            cr4 = in(reg) cr4,
+            options(nostack, preserves_flags)
+        );
+        if xcr0 != 0 {
+            asm!(
+                "xsetbv",
+                in("ecx") 0,
+                in("eax") xcr0 as u32,
+                in("edx") (xcr0 >> 32) as u32,
+                options(nomem, nostack, preserves_flags)
+            );
+        }
+    }
+}
+
+fn template() -> &'static FpuArea {
+    let template = &raw const TEMPLATE;
+    // Only `init` writes it, before any thread exists.
+    unsafe { &*template }
+}
+
+/// A copy of the initial state, for a new image.
+pub fn initial_state() -> Result<FpuArea, ProcessError> {
+    template().try_clone()
+}
+
+/// Stores this CPU's registers into `area`.
+pub fn save(area: &mut FpuArea) {
+    if area.is_empty() {
+        return;
+    }
+    let ptr = area.as_mut_ptr();
+    unsafe {
+        if XCR0 != 0 {
+            asm!(
+                "xsave64 [{}]",
+                in(reg) ptr,
+                in("eax") u32::MAX,
+                in("edx") u32::MAX,
+                options(nostack, preserves_flags)
+            );
+        } else {
+            asm!("fxsave64 [{}]", in(reg) ptr, options(nostack, preserves_flags));
+        }
+    }
+}
+
+/// Loads this CPU's registers from `area`.
+pub fn restore(area: &FpuArea) {
+    if area.is_empty() {
+        return;
+    }
+    let ptr = area.as_bytes().as_ptr();
+    unsafe {
+        if XCR0 != 0 {
+            asm!(
+                "xrstor64 [{}]",
+                in(reg) ptr,
+                in("eax") u32::MAX,
+                in("edx") u32::MAX,
+                options(readonly, nostack, preserves_flags)
+            );
+        } else {
+            asm!("fxrstor64 [{}]", in(reg) ptr, options(readonly, nostack, preserves_flags));
+        }
+    }
+}
+
+/// Puts the registers in their initial state, as after `execve`.
+pub fn reset() {
+    restore(template());
+}
+
+/// Writes the live registers back to the running thread, so a fork or a new
+/// thread copies what it actually has.
+pub fn save_current(table: &mut ProcessTable) {
+    if let Some(thread) = table.current_thread_mut() {
+        save(&mut thread.fpu);
+    }
+}
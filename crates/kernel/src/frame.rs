@@ -0,0 +1,121 @@
+use crate::sync::{LockClass, SpinLock};
+use common::bitmap::FrameBitmap;
+use limine::memory_map::{Entry, EntryType};
+
+pub const FRAME_SIZE: usize = 0x1000;
+pub const HUGE_FRAME_SIZE: usize = 0x20_0000;
+const FRAMES_PER_HUGE: usize = HUGE_FRAME_SIZE / FRAME_SIZE;
+
+static FRAMES: SpinLock<Option<FrameBitmap<'static>>> = SpinLock::new(LockClass::Frames, None);
+
+#[derive(Clone, Copy, Default)]
+pub struct FrameStats {
+    pub total: usize,
+    pub free: usize,
+    pub used: usize,
+}
+
+fn usable(entry: &Entry) -> Option<(usize, usize)> {
+    if entry.entry_type != EntryType::USABLE {
+        return None;
+    }
+    let base = (entry.base as usize).next_multiple_of(FRAME_SIZE);
+    let end = (entry.base + entry.length) as usize & !(FRAME_SIZE - 1);
+    (base < end).then_some((base, end))
+}
+
+pub fn init(entries: &[&Entry]) -> Option<FrameStats> {
+    let top = entries
+        .iter()
+        .filter_map(|e| usable(e))
+        .map(|(_, end)| end)
+        .max()?;
+    let frames = top / FRAME_SIZE;
+    let words = FrameBitmap::words_for(frames);
+    let bitmap_bytes = (words * 8 + frames * 2).next_multiple_of(FRAME_SIZE);
+
+    let (bitmap_phys, _) = entries
+        .iter()
+        .filter_map(|e| usable(e))
+        .find(|(base, end)| end - base >= bitmap_bytes)?;
+
+    let storage = crate::paging::phys_to_virt(bitmap_phys);
+    let (bits, refs) = unsafe {
+        (
+            core::slice::from_raw_parts_mut(storage as *mut u64, words),
+            core::slice::from_raw_parts_mut((storage + words * 8) as *mut u16, frames),
+        )
+    };
+    let mut bitmap = FrameBitmap::new(bits, refs, frames);
+    for (base, end) in entries.iter().filter_map(|e| usable(e)) {
+        bitmap.mark_free(base / FRAME_SIZE, (end - base) / FRAME_SIZE);
+    }
+    bitmap.mark_used(0, 1);
+    bitmap.mark_used(bitmap_phys / FRAME_SIZE, bitmap_bytes / FRAME_SIZE);
+
+    *FRAMES.lock() = Some(bitmap);
+    Some(stats())
+}
+
+pub fn stats() -> FrameStats {
+    FRAMES
+        .lock()
+        .as_ref()
+        .map_or(FrameStats::default(), |b| FrameStats {
+            total: b.frames(),
+            free: b.free_frames(),
+            used: b.used_frames(),
+        })
+}
+
+fn alloc_zeroed(count: usize, align: usize) -> Option<usize> {
+    let idx = FRAMES.lock().as_mut()?.alloc(count, align)?;
+    let phys = idx * FRAME_SIZE;
+    unsafe {
+        core::ptr::write_bytes(
+            crate::paging::phys_to_virt(phys) as *mut u8,
+            0,
+            count * FRAME_SIZE,
+        )
+    };
+    Some(phys)
+}
+
+pub fn alloc_frame() -> Option<usize> {
+    alloc_zeroed(1, 1)
+}
+
+pub fn alloc_frames(count: usize) -> Option<usize> {
+    alloc_zeroed(count, 1)
+}
+
+pub fn alloc_huge_frame() -> Option<usize> {
+    alloc_zeroed(FRAMES_PER_HUGE, FRAMES_PER_HUGE)
+}
+
+pub fn share_frame(phys: usize) -> bool {
+    FRAMES
+        .lock()
+        .as_mut()
+        .is_some_and(|b| b.share(phys / FRAME_SIZE))
+}
+
+pub fn refcount(phys: usize) -> u16 {
+    FRAMES
+        .lock()
+        .as_ref()
+        .map_or(0, |b| b.refcount(phys / FRAME_SIZE))
+}
+
+pub fn free_frame(phys: usize) {
+    if let Some(bitmap) = FRAMES.lock().as_mut() {
+        bitmap.free(phys / FRAME_SIZE, 1);
+    }
+}
+
+#[allow(dead_code)]
+pub fn free_huge_frame(phys: usize) {
+    if let Some(bitmap) = FRAMES.lock().as_mut() {
+        bitmap.free(phys / FRAME_SIZE, FRAMES_PER_HUGE);
+    }
+}
@@ -0,0 +1,102 @@
+use crate::percpu::{self, MAX_CPUS};
+use core::arch::asm;
+
+pub const KERNEL_CS: u16 = 0x08;
+pub const KERNEL_DS: u16 = 0x10;
+const TSS_SEL: u16 = 0x28;
+
+pub const DOUBLE_FAULT_IST: u8 = 1;
+// NMIs can land where rsp still holds the user stack, e.g. just before sysretq.
+pub const NMI_IST: u8 = 2;
+const IST_STACK_SIZE: usize = 16 * 1024;
+
+#[repr(C, align(16))]
+struct IstStack([u8; IST_STACK_SIZE]);
+
+static mut DOUBLE_FAULT_STACKS: [IstStack; MAX_CPUS] =
+    [const { IstStack([0; IST_STACK_SIZE]) }; MAX_CPUS];
+static mut NMI_STACKS: [IstStack; MAX_CPUS] = [const { IstStack([0; IST_STACK_SIZE]) }; MAX_CPUS];
+
+#[repr(C, packed)]
+struct Tss {
+    reserved0: u32,
+    rsp: [u64; 3],
+    reserved1: u64,
+    ist: [u64; 7],
+    reserved2: u64,
+    reserved3: u16,
+    iomap_base: u16,
+}
+
+static mut TSS: [Tss; MAX_CPUS] = [const {
+    Tss {
+        reserved0: 0,
+        rsp: [0; 3],
+        reserved1: 0,
+        ist: [0; 7],
+        reserved2: 0,
+        reserved3: 0,
+        iomap_base: core::mem::size_of::<Tss>() as u16,
+    }
+}; MAX_CPUS];
+
+#[repr(C, packed)]
+struct GdtPtr {
+    limit: u16,
+    base: u64,
+}
+
+// Each CPU needs its own TSS descriptor: `ltr` marks it busy.
+static mut GDT: [[u64; 7]; MAX_CPUS] = [[
+    0,
+    0x00af_9a00_0000_ffff,
+    0x00cf_9200_0000_ffff,
+    0x00cf_f200_0000_ffff,
+    0x00af_fa00_0000_ffff,
+    0,
+    0,
+]; MAX_CPUS];
+
+pub fn install_gdt(cpu: usize) {
+    unsafe {
+        let tss = &raw mut TSS[cpu];
+        (*tss).ist[DOUBLE_FAULT_IST as usize - 1] =
+            (&raw const DOUBLE_FAULT_STACKS[cpu]) as u64 + IST_STACK_SIZE as u64;
+        (*tss).ist[NMI_IST as usize - 1] =
+            (&raw const NMI_STACKS[cpu]) as u64 + IST_STACK_SIZE as u64;
+
+        let gdt = &raw mut GDT[cpu];
+        let base = tss as u64;
+        let limit = (core::mem::size_of::<Tss>() - 1) as u64;
+        (*gdt)[5] = (limit & 0xffff)
+            | ((base & 0xff_ffff) << 16)
+            | (0x89 << 40)
+            | (((limit >> 16) & 0xf) << 48)
+            | (((base >> 24) & 0xff) << 56);
+        (*gdt)[6] = base >> 32;
+
+        let ptr = GdtPtr {
+            limit: (core::mem::size_of::<[u64; 7]>() - 1) as u16,
+            base: gdt as u64,
+        };
+        asm!("lgdt [{}]", in(reg) &ptr, options(readonly, nostack));
+        asm!(
+            "push {cs}",
+            "lea {tmp}, [rip + 2f]",
+            "push {tmp}",
+            "retfq",
+            "2:",
+            "mov ds, {ds:x}",
+            "mov es, {ds:x}",
+            "mov ss, {ds:x}",
+            cs = in(reg) u64::from(KERNEL_CS),
+            ds = in(reg) KERNEL_DS,
+            tmp = lateout(reg) _,
+        );
+        asm!("ltr {0:x}", in(reg) TSS_SEL, options(nostack, preserves_flags));
+    }
+}
+
+pub fn set_kernel_stack(top: usize) {
+    unsafe { TSS[percpu::current().index()].rsp[0] = top as u64 };
+}
@@ -0,0 +1,58 @@
+use crate::frame::{self, FRAME_SIZE, HUGE_FRAME_SIZE};
+use crate::paging::phys_to_virt;
+use crate::sync::{LockClass, SpinLock};
+use crate::tty::TTY;
+use common::heap::LinkedListHeap;
+use core::alloc::{GlobalAlloc, Layout};
+use core::fmt::Write;
+
+struct KernelHeap {
+    inner: SpinLock<LinkedListHeap>,
+}
+
+#[global_allocator]
+static HEAP: KernelHeap = KernelHeap {
+    inner: SpinLock::new(LockClass::Heap, LinkedListHeap::new()),
+};
+
+fn grow(heap: &mut LinkedListHeap, min_bytes: usize) -> Option<()> {
+    let (phys, len) = if min_bytes <= HUGE_FRAME_SIZE {
+        (frame::alloc_huge_frame()?, HUGE_FRAME_SIZE)
+    } else {
+        let len = min_bytes.next_multiple_of(HUGE_FRAME_SIZE);
+        (frame::alloc_frames(len / FRAME_SIZE)?, len)
+    };
+    unsafe { heap.add_region(phys_to_virt(phys), len) };
+    Some(())
+}
+
+unsafe impl GlobalAlloc for KernelHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut heap = self.inner.lock();
+        if let Some(ptr) = heap.alloc(layout) {
+            return ptr.as_ptr();
+        }
+        let needed = layout.size().saturating_add(layout.align());
+        if let Some(ptr) = grow(&mut heap, needed).and_then(|()| heap.alloc(layout)) {
+            return ptr.as_ptr();
+        }
+        drop(heap);
+        let _ = writeln!(
+            TTY.lock(),
+            "[heap] out of memory: {} bytes (align {})",
+            layout.size(),
+            layout.align()
+        );
+        core::ptr::null_mut()
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { self.inner.lock().dealloc(ptr, layout) };
+    }
+}
+
+pub fn init() -> Option<usize> {
+    let mut heap = HEAP.inner.lock();
+    grow(&mut heap, HUGE_FRAME_SIZE)?;
+    Some(heap.total_bytes())
+}
@@ -0,0 +1,67 @@
+use crate::paging::{self, PAGE_SIZE, PageFlags};
+
+const HPET_VA: usize = 0xffff_fc00_0000_0000;
+
+const REG_CAPABILITIES: usize = 0x000;
+const REG_CONFIG: usize = 0x010;
+const REG_COUNTER: usize = 0x0F0;
+
+const CONFIG_ENABLE: u64 = 1 << 0;
+const COUNTER_64BIT: u64 = 1 << 13;
+/// The spec caps the tick period at 100 ns.
+const MAX_PERIOD_FS: u64 = 100_000_000;
+const FEMTOS_PER_NANO: u64 = 1_000_000;
+
+static mut PERIOD_FS: u64 = 0;
+static mut COUNTER_MASK: u64 = u32::MAX as u64;
+
+fn read(reg: usize) -> u64 {
+    unsafe { ((HPET_VA + reg) as *const u64).read_volatile() }
+}
+
+fn write(reg: usize, value: u64) {
+    unsafe { ((HPET_VA + reg) as *mut u64).write_volatile(value) };
+}
+
+/// Maps the HPET at physical `base` and starts its main counter; returns
+/// the counter frequency in Hz.
+pub fn init(base: u64) -> Option<u64> {
+    if !(base as usize).is_multiple_of(PAGE_SIZE) {
+        return None;
+    }
+    paging::reserve_kernel_region(HPET_VA)?;
+    paging::map_kernel_page(
+        HPET_VA,
+        base as usize,
+        PageFlags::WRITABLE | PageFlags::NO_CACHE | PageFlags::NO_EXECUTE,
+    )?;
+    let caps = read(REG_CAPABILITIES);
+    let period = caps >> 32;
+    if period == 0 || period > MAX_PERIOD_FS {
+        return None;
+    }
+    unsafe {
+        PERIOD_FS = period;
+        if caps & COUNTER_64BIT != 0 {
+            COUNTER_MASK = u64::MAX;
+        }
+    }
+    write(REG_CONFIG, read(REG_CONFIG) | CONFIG_ENABLE);
+    Some(FEMTOS_PER_NANO * 1_000_000_000 / period)
+}
+
+/// Busy-waits at least `ns` on the main counter; returns how long it
+/// actually took by the counter.
+pub fn wait(ns: u64) -> u64 {
+    let (period, mask) = unsafe { (PERIOD_FS, COUNTER_MASK) };
+    let ticks = ns * FEMTOS_PER_NANO / period;
+    let start = read(REG_COUNTER);
+    let elapsed = loop {
+        let elapsed = read(REG_COUNTER).wrapping_sub(start) & mask;
+        if elapsed >= ticks {
+            break elapsed;
+        }
+        core::hint::spin_loop();
+    };
+    elapsed * period / FEMTOS_PER_NANO
+}
@@ -1,28 +1,429 @@
+use crate::exceptions::{self, DOUBLE_FAULT_VECTOR, EXCEPTION_COUNT, NMI_VECTOR};
+use crate::gdt::{DOUBLE_FAULT_IST, KERNEL_CS, KERNEL_DS, NMI_IST};
+use crate::paging::USER_SPACE_END;
+use crate::percpu::{self, PerCpu};
+use crate::{lapic, msr, sched, smp, timer};
+use common::process::{ProcessContext, USER_CS, USER_DS};
 use core::arch::{asm, global_asm};
+use core::sync::atomic::Ordering;
+
+pub const SYSCALL_VECTOR: u64 = 0x80;
+const TIMER_VECTOR: u64 = timer::TIMER_VECTOR as u64;
+const RESCHEDULE_VECTOR: u64 = smp::RESCHEDULE_VECTOR as u64;
+// Tag for frames built by the `syscall` instruction; not an IDT vector.
+const FAST_SYSCALL_VECTOR: u64 = 0x100;
+const RFLAGS_AC: u64 = 1 << 18;
+// Clear AC, DF, IF and TF on entry.
+const SYSCALL_MASK: u64 = RFLAGS_AC | (1 << 10) | (1 << 9) | (1 << 8);
 
 global_asm!(
     r#"
 .global syscall_int80
 syscall_int80:
+    push 0
+    push 0x80
+    jmp trap_common
+
+.global syscall_entry
+syscall_entry:
+    swapgs
+    mov gs:[{user_rsp}], rsp
+    mov rsp, gs:[{kernel_rsp}]
+    push {user_ds}
+    push qword ptr gs:[{user_rsp}]
+    push r11
+    push {user_cs}
+    push rcx
+    push 0
+    push {fast_syscall}
+    jmp trap_kernel_gs
+
+.global irq_timer
+irq_timer:
+    push 0
+    push {timer}
+    jmp trap_common
+
+.global irq_reschedule
+irq_reschedule:
+    push 0
+    push {reschedule}
+    jmp trap_common
+
+.global irq_spurious
+irq_spurious:
+    iretq
+
+// An NMI can land between a swapgs and the iretq or sysretq beside it, so
+// the saved cs does not say which gs is live. The per-CPU base is a kernel
+// address and a user one never is, so ask the MSR instead.
+.global nmi_entry
+nmi_entry:
+    push rax
     push rcx
-    push rdi
-    push rsi
     push rdx
+    push rsi
+    push rdi
+    push r8
+    push r9
+    push r10
+    push r11
+    push rbx
     sub rsp, 8
-    mov rcx, rdx
-    mov rdx, rsi
-    mov rsi, rdi
-    mov rdi, rax
-    call syscall_dispatch
+    cld
+    xor ebx, ebx
+    mov ecx, {gs_base_msr}
+    rdmsr
+    test edx, edx
+    js 1f
+    swapgs
+    mov ebx, 1
+1:
+    mov rdi, [rsp + {nmi_rip_offset}]
+    call nmi_dispatch
+    test ebx, ebx
+    jz 2f
+    swapgs
+2:
     add rsp, 8
-    pop rdx
+    pop rbx
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rdi
     pop rsi
+    pop rdx
+    pop rcx
+    pop rax
+    iretq
+
+// gs holds the per-CPU base in the kernel and the user's base outside it;
+// the saved cs says which side the trap came from.
+.global trap_common
+trap_common:
+    test byte ptr [rsp + 24], 3
+    jz trap_kernel_gs
+    swapgs
+trap_kernel_gs:
+    pushfq
+    and qword ptr [rsp], ~{rflags_ac}
+    popfq
+    push rax
+    push rbx
+    push rcx
+    push rdx
+    push rsi
+    push rdi
+    push rbp
+    push r8
+    push r9
+    push r10
+    push r11
+    push r12
+    push r13
+    push r14
+    push r15
+    mov eax, ds
+    push rax
+    mov eax, es
+    push rax
+    mov eax, fs
+    push rax
+    mov eax, gs
+    push rax
+    mov eax, {kernel_ds}
+    mov ds, eax
+    mov es, eax
+    mov rdi, rsp
+    call trap_dispatch
+    cmp qword ptr [rsp + {vector_offset}], {fast_syscall}
+    je sysret_return
+
+.global trap_return
+trap_return:
+    // Kernel frames keep the segment state they were interrupted with.
+    test byte ptr [rsp + {cs_offset}], 3
+    jnz 1f
+    add rsp, 16
+    jmp 2f
+1:
+    // Loading a gs selector would clear the per-CPU base; user gs stays null.
+    pop rax
+    pop rax
+    mov fs, eax
+    mov ecx, {fs_base_msr}
+    mov eax, gs:[{fs_base}]
+    mov edx, gs:[{fs_base} + 4]
+    wrmsr
+2:
+    pop rax
+    mov es, eax
+    pop rax
+    mov ds, eax
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rbp
     pop rdi
+    pop rsi
+    pop rdx
     pop rcx
+    pop rbx
+    pop rax
+    add rsp, 16
+    test byte ptr [rsp + 8], 3
+    jz 3f
+    swapgs
+3:
     iretq
-"#
+
+sysret_return:
+    pop rax
+    pop rax
+    mov fs, eax
+    mov ecx, {fs_base_msr}
+    mov eax, gs:[{fs_base}]
+    mov edx, gs:[{fs_base} + 4]
+    wrmsr
+    pop rax
+    mov es, eax
+    pop rax
+    mov ds, eax
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rbp
+    pop rdi
+    pop rsi
+    pop rdx
+    pop rcx
+    pop rbx
+    pop rax
+    add rsp, 16
+    mov rcx, [rsp]
+    mov r11, [rsp + 16]
+    mov rsp, [rsp + 24]
+    swapgs
+    sysretq
+
+.global switch_context
+switch_context:
+    push rbp
+    push rbx
+    push r12
+    push r13
+    push r14
+    push r15
+    mov [rdi], rsp
+    mov rsp, rsi
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    pop rbp
+    ret
+
+.global process_entry
+process_entry:
+    mov rdi, rsp
+    call enter_process
+    jmp trap_return
+"#,
+    kernel_ds = const KERNEL_DS,
+    rflags_ac = const RFLAGS_AC,
+    user_cs = const USER_CS,
+    user_ds = const USER_DS,
+    fast_syscall = const FAST_SYSCALL_VECTOR,
+    vector_offset = const core::mem::offset_of!(TrapFrame, vector),
+    cs_offset = const core::mem::offset_of!(TrapFrame, cs),
+    timer = const TIMER_VECTOR,
+    reschedule = const RESCHEDULE_VECTOR,
+    user_rsp = const core::mem::offset_of!(PerCpu, user_rsp),
+    kernel_rsp = const core::mem::offset_of!(PerCpu, syscall_rsp),
+    fs_base = const core::mem::offset_of!(PerCpu, user_fs_base),
+    fs_base_msr = const msr::FS_BASE,
+    gs_base_msr = const msr::GS_BASE,
+    nmi_rip_offset = const 11 * 8,
 );
 
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct TrapFrame {
+    pub gs: u64,
+    pub fs: u64,
+    pub es: u64,
+    pub ds: u64,
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub vector: u64,
+    pub error_code: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+impl TrapFrame {
+    pub fn is_user(&self) -> bool {
+        self.cs & 3 == 3
+    }
+
+    pub fn save(&self) -> ProcessContext {
+        ProcessContext {
+            rax: self.rax as usize,
+            rbx: self.rbx as usize,
+            rcx: self.rcx as usize,
+            rdx: self.rdx as usize,
+            rsi: self.rsi as usize,
+            rdi: self.rdi as usize,
+            rbp: self.rbp as usize,
+            r8: self.r8 as usize,
+            r9: self.r9 as usize,
+            r10: self.r10 as usize,
+            r11: self.r11 as usize,
+            r12: self.r12 as usize,
+            r13: self.r13 as usize,
+            r14: self.r14 as usize,
+            r15: self.r15 as usize,
+            rip: self.rip as usize,
+            rsp: self.rsp as usize,
+            rflags: self.rflags as usize,
+            cs: self.cs as usize,
+            ss: self.ss as usize,
+            ds: self.ds as usize,
+            es: self.es as usize,
+            fs: self.fs as usize,
+            gs: self.gs as usize,
+            fs_base: percpu::current().user_fs_base.load(Ordering::Relaxed) as usize,
+        }
+    }
+
+    pub fn load(&mut self, ctx: &ProcessContext) {
+        self.rax = ctx.rax as u64;
+        self.rbx = ctx.rbx as u64;
+        self.rcx = ctx.rcx as u64;
+        self.rdx = ctx.rdx as u64;
+        self.rsi = ctx.rsi as u64;
+        self.rdi = ctx.rdi as u64;
+        self.rbp = ctx.rbp as u64;
+        self.r8 = ctx.r8 as u64;
+        self.r9 = ctx.r9 as u64;
+        self.r10 = ctx.r10 as u64;
+        self.r11 = ctx.r11 as u64;
+        self.r12 = ctx.r12 as u64;
+        self.r13 = ctx.r13 as u64;
+        self.r14 = ctx.r14 as u64;
+        self.r15 = ctx.r15 as u64;
+        self.rip = ctx.rip as u64;
+        self.rsp = ctx.rsp as u64;
+        self.rflags = ctx.rflags as u64;
+        self.cs = ctx.cs as u64;
+        self.ss = ctx.ss as u64;
+        self.ds = ctx.ds as u64;
+        self.es = ctx.es as u64;
+        self.fs = ctx.fs as u64;
+        self.gs = ctx.gs as u64;
+        percpu::current()
+            .user_fs_base
+            .store(ctx.fs_base as u64, Ordering::Relaxed);
+    }
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn trap_dispatch(frame: &mut TrapFrame) {
+    match frame.vector {
+        SYSCALL_VECTOR | FAST_SYSCALL_VECTOR => {
+            sched::save_current(frame);
+            let args = [
+                frame.rdi, frame.rsi, frame.rdx, frame.r10, frame.r8, frame.r9,
+            ];
+            let ret = crate::syscall_dispatch(frame.rax, args);
+            sched::complete_syscall(ret);
+            sched::schedule(false);
+            sched::load_current(frame);
+            // sysret faults in ring 0 on a non-canonical rip, so leave through iretq.
+            if frame.rip >= USER_SPACE_END as u64 || frame.cs != USER_CS as u64 {
+                frame.vector = SYSCALL_VECTOR;
+            }
+        }
+        TIMER_VECTOR | RESCHEDULE_VECTOR => {
+            if frame.vector == TIMER_VECTOR {
+                timer::tick();
+            } else {
+                lapic::end_of_interrupt();
+            }
+            if frame.is_user() {
+                sched::save_current(frame);
+                sched::schedule(true);
+                sched::load_current(frame);
+            }
+        }
+        v if v < EXCEPTION_COUNT as u64 => exceptions::handle(frame),
+        _ => {}
+    }
+}
+
+/// The interrupted code may hold any lock, so nothing here may take one.
+#[unsafe(no_mangle)]
+extern "C" fn nmi_dispatch(rip: u64) {
+    if !smp::handle_nmi() {
+        exceptions::unexpected_nmi(rip);
+    }
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn enter_process(frame: &mut TrapFrame) {
+    sched::finish_switch();
+    sched::load_current(frame);
+}
+
+unsafe extern "C" {
+    fn syscall_entry();
+    fn syscall_int80();
+    fn irq_timer();
+    fn irq_reschedule();
+    fn irq_spurious();
+    fn nmi_entry();
+    fn process_entry();
+    pub fn switch_context(old_rsp: *mut usize, new_rsp: usize);
+}
+
+pub fn initial_kernel_rsp(stack_top: usize) -> usize {
+    let frame = stack_top - core::mem::size_of::<TrapFrame>();
+    let rsp = frame - 7 * 8;
+    unsafe {
+        core::ptr::write_bytes(rsp as *mut u64, 0, 6);
+        ((rsp + 6 * 8) as *mut u64).write(process_entry as *const () as usize as u64);
+    }
+    rsp
+}
+
 #[repr(C, packed)]
 struct IdtPtr {
     limit: u16,
@@ -54,10 +455,10 @@ impl IdtEntry {
         }
     }
 
-    fn set(&mut self, addr: u64, dpl: u8, selector: u16) {
+    fn set(&mut self, addr: u64, dpl: u8, selector: u16, ist: u8) {
         self.off1 = addr as u16;
         self.sel = selector;
-        self.ist = 0;
+        self.ist = ist;
         self.attrs = 0x8E | ((dpl & 0x3) << 5);
         self.off2 = (addr >> 16) as u16;
         self.off3 = (addr >> 32) as u32;
@@ -67,20 +468,74 @@ impl IdtEntry {
 
 static mut IDT: [IdtEntry; 256] = [IdtEntry::missing(); 256];
 
-unsafe extern "C" {
-    fn syscall_int80();
+pub fn install_idt() {
+    unsafe {
+        for (vector, &stub) in exceptions::stubs().iter().enumerate() {
+            let ist = if vector as u64 == DOUBLE_FAULT_VECTOR {
+                DOUBLE_FAULT_IST
+            } else {
+                0
+            };
+            IDT[vector].set(stub, 0, KERNEL_CS, ist);
+        }
+        IDT[NMI_VECTOR as usize].set(
+            nmi_entry as *const () as usize as u64,
+            0,
+            KERNEL_CS,
+            NMI_IST,
+        );
+        IDT[SYSCALL_VECTOR as usize].set(
+            syscall_int80 as *const () as usize as u64,
+            3,
+            KERNEL_CS,
+            0,
+        );
+        IDT[TIMER_VECTOR as usize].set(irq_timer as *const () as usize as u64, 0, KERNEL_CS, 0);
+        IDT[RESCHEDULE_VECTOR as usize].set(
+            irq_reschedule as *const () as usize as u64,
+            0,
+            KERNEL_CS,
+            0,
+        );
+        IDT[lapic::SPURIOUS_VECTOR as usize].set(
+            irq_spurious as *const () as usize as u64,
+            0,
+            KERNEL_CS,
+            0,
+        );
+    }
+    load_idt();
 }
 
-pub fn install_idt() {
+/// Loads the shared IDT on this CPU.
+pub fn load_idt() {
     unsafe {
-        let cs: u16;
-        asm!("mov {0:x}, cs", out(reg) cs, options(nostack, preserves_flags));
-        IDT[0x80].set(syscall_int80 as usize as u64, 3, cs);
         let ptr = IdtPtr {
             limit: (core::mem::size_of::<[IdtEntry; 256]>() - 1) as u16,
             base: (&raw const IDT) as *const _ as u64,
         };
         asm!("lidt [{}]", in(reg) &ptr, options(readonly, nostack));
-        asm!("sti");
     }
 }
+
+pub fn install_syscall() {
+    unsafe {
+        msr::write(msr::EFER, msr::read(msr::EFER) | msr::EFER_SCE);
+        msr::write(
+            msr::STAR,
+            (u64::from(KERNEL_CS) << 32) | ((USER_DS as u64 - 8) << 48),
+        );
+        msr::write(msr::LSTAR, syscall_entry as *const () as usize as u64);
+        msr::write(msr::SFMASK, SYSCALL_MASK);
+    }
+}
+
+pub fn set_syscall_stack(top: usize) {
+    percpu::current()
+        .syscall_rsp
+        .store(top as u64, Ordering::Relaxed);
+}
+
+pub fn idle_until_interrupt() {
+    unsafe { asm!("sti", "hlt", "cli", options(nomem, nostack)) };
+}
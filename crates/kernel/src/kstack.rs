@@ -0,0 +1,80 @@
+use crate::paging::{self, PAGE_SIZE, PageFlags};
+use crate::sync::{LockClass, SpinLock};
+use crate::{frame, smp};
+use alloc::vec::Vec;
+use common::process::{KERNEL_STACK_SIZE, KernelStack, ProcessError};
+
+const KSTACK_BASE: usize = 0xffff_fe00_0000_0000;
+const SLOT_SIZE: usize = KERNEL_STACK_SIZE + PAGE_SIZE;
+
+struct Slots {
+    next: usize,
+    free: Vec<usize>,
+}
+
+static SLOTS: SpinLock<Slots> = SpinLock::new(
+    LockClass::KernelStacks,
+    Slots {
+        next: 0,
+        free: Vec::new(),
+    },
+);
+
+pub fn init() -> Option<()> {
+    paging::reserve_kernel_region(KSTACK_BASE)
+}
+
+fn stack_base(slot: usize) -> usize {
+    KSTACK_BASE + slot * SLOT_SIZE + PAGE_SIZE
+}
+
+fn map_fresh(va: usize) -> Option<()> {
+    let phys = frame::alloc_frame()?;
+    let mapped = paging::map_kernel_page(va, phys, PageFlags::WRITABLE);
+    if mapped.is_none() {
+        frame::free_frame(phys);
+    }
+    mapped
+}
+
+fn unmap_stack(base: usize, len: usize) {
+    let mut frames = [None; KERNEL_STACK_SIZE / PAGE_SIZE];
+    for (phys, page) in frames.iter_mut().zip((base..base + len).step_by(PAGE_SIZE)) {
+        *phys = paging::unmap_kernel_page(page);
+    }
+    // CPUs the thread ran on may still cache the stack's translations.
+    smp::flush_tlb_others();
+    frames.into_iter().flatten().for_each(frame::free_frame);
+}
+
+pub fn alloc() -> Result<KernelStack, ProcessError> {
+    let slot = {
+        let mut slots = SLOTS.lock();
+        slots.free.pop().unwrap_or_else(|| {
+            slots.next += 1;
+            slots.next - 1
+        })
+    };
+    let base = stack_base(slot);
+    for page in (base..base + KERNEL_STACK_SIZE).step_by(PAGE_SIZE) {
+        if map_fresh(page).is_none() {
+            unmap_stack(base, page - base);
+            release_slot(slot);
+            return Err(ProcessError::OutOfMemory);
+        }
+    }
+    Ok(unsafe { KernelStack::from_raw(base + KERNEL_STACK_SIZE, free) })
+}
+
+fn release_slot(slot: usize) {
+    let mut slots = SLOTS.lock();
+    if slots.free.try_reserve(1).is_ok() {
+        slots.free.push(slot);
+    }
+}
+
+fn free(top: usize) {
+    let base = top - KERNEL_STACK_SIZE;
+    unmap_stack(base, KERNEL_STACK_SIZE);
+    release_slot((base - KSTACK_BASE) / SLOT_SIZE);
+}
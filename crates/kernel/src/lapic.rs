@@ -0,0 +1,94 @@
+use crate::msr;
+use crate::paging::{self, PageFlags};
+
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+const LAPIC_VA: usize = 0xffff_fd00_0000_0000;
+const APIC_BASE_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+const REG_ID: usize = 0x020;
+const REG_EOI: usize = 0x0B0;
+const REG_SPURIOUS: usize = 0x0F0;
+const REG_ICR_LOW: usize = 0x300;
+const REG_ICR_HIGH: usize = 0x310;
+const REG_LVT_TIMER: usize = 0x320;
+const REG_TIMER_INITIAL: usize = 0x380;
+const REG_TIMER_CURRENT: usize = 0x390;
+const REG_TIMER_DIVIDE: usize = 0x3E0;
+
+const SOFTWARE_ENABLE: u32 = 1 << 8;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_NMI: u32 = 0b100 << 8;
+const LVT_MASKED: u32 = 1 << 16;
+const DIVIDE_BY_16: u32 = 0b0011;
+
+/// Maps the local APIC registers; every CPU sees its own APIC at the same
+/// address.
+pub fn init() -> Option<()> {
+    let phys = unsafe { msr::read(msr::APIC_BASE) } & APIC_BASE_MASK;
+    paging::reserve_kernel_region(LAPIC_VA)?;
+    paging::map_kernel_page(
+        LAPIC_VA,
+        phys as usize,
+        PageFlags::WRITABLE | PageFlags::NO_CACHE | PageFlags::NO_EXECUTE,
+    )
+}
+
+/// Enables this CPU's APIC.
+pub fn enable() {
+    write(REG_SPURIOUS, SOFTWARE_ENABLE | u32::from(SPURIOUS_VECTOR));
+}
+
+fn read(reg: usize) -> u32 {
+    unsafe { ((LAPIC_VA + reg) as *const u32).read_volatile() }
+}
+
+fn write(reg: usize, value: u32) {
+    unsafe { ((LAPIC_VA + reg) as *mut u32).write_volatile(value) };
+}
+
+pub fn id() -> u32 {
+    read(REG_ID) >> 24
+}
+
+pub fn end_of_interrupt() {
+    write(REG_EOI, 0);
+}
+
+fn send(lapic_id: u32, command: u32) {
+    write(REG_ICR_HIGH, lapic_id << 24);
+    write(REG_ICR_LOW, command);
+    while read(REG_ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+        core::hint::spin_loop();
+    }
+}
+
+pub fn send_ipi(lapic_id: u32, vector: u8) {
+    send(lapic_id, u32::from(vector));
+}
+
+pub fn send_nmi(lapic_id: u32) {
+    send(lapic_id, ICR_NMI);
+}
+
+/// Counts timer ticks (at the /16 divider) while `wait` runs.
+pub fn measure_timer(wait: impl FnOnce()) -> u32 {
+    write(REG_TIMER_DIVIDE, DIVIDE_BY_16);
+    write(REG_LVT_TIMER, LVT_MASKED);
+    write(REG_TIMER_INITIAL, u32::MAX);
+    wait();
+    let elapsed = u32::MAX - read(REG_TIMER_CURRENT);
+    write(REG_TIMER_INITIAL, 0);
+    elapsed
+}
+
+/// Raises `vector` once, after `count` ticks at the /16 divider.
+pub fn start_oneshot_timer(vector: u8, count: u32) {
+    write(REG_TIMER_DIVIDE, DIVIDE_BY_16);
+    write(REG_LVT_TIMER, u32::from(vector));
+    write(REG_TIMER_INITIAL, count.max(1));
+}
+
+pub fn stop_timer() {
+    write(REG_TIMER_INITIAL, 0);
+}
@@ -1,22 +1,62 @@
 #![no_std]
 #![no_main]
 
+extern crate alloc;
+
+mod acpi;
 mod elf_loader;
+mod exceptions;
+mod fpu;
+mod frame;
+mod gdt;
+mod heap;
+mod hpet;
 mod interrupts;
+mod kstack;
+mod lapic;
 mod memory;
+mod msr;
+mod page_cache;
+mod paging;
+mod percpu;
+mod pic;
+mod port;
+mod random;
+mod sched;
 mod serial;
+mod smp;
+mod sync;
+mod timer;
 mod tty;
+mod uaccess;
 mod vfs;
 
-use common::elf::parse_elf64;
-use common::process::ProcessStack;
-use common::syscall::{SYS_EXECVE, SYS_EXIT, SYS_FORK, SYS_MEMMAP, SYS_OPEN, SYS_READ, SYS_WRITE};
+use alloc::vec::Vec;
+use common::acpi::HPET_SIGNATURE;
+use common::elf::{
+    AT_BASE, AT_ENTRY, AT_PAGESZ, AT_PHDR, AT_PHENT, AT_PHNUM, ElfImage, parse_elf64,
+};
+use common::exec::{ARG_MAX, StackError, initial_stack};
+use common::process::{AddressSpace, BlockReason, ProcessContext, ProcessState, WaitResult};
+use common::syscall::{
+    ARCH_GET_FS, ARCH_SET_FS, CLOCK_BOOTTIME, CLOCK_MONOTONIC, CLONE_CHILD_CLEARTID,
+    CLONE_CHILD_SETTID, CLONE_FILES, CLONE_PARENT_SETTID, CLONE_SETTLS, CLONE_SIGHAND,
+    CLONE_THREAD, CLONE_VM, FUTEX_PRIVATE_FLAG, FUTEX_WAIT, FUTEX_WAKE, MAP_ANONYMOUS,
+    SYS_ARCH_PRCTL, SYS_BRK, SYS_CLOCK_GETTIME, SYS_CLONE, SYS_EXECVE, SYS_EXIT, SYS_EXIT_GROUP,
+    SYS_FORK, SYS_FUTEX, SYS_GETCPU, SYS_GETPID, SYS_GETTID, SYS_MMAP, SYS_MPROTECT, SYS_MUNMAP,
+    SYS_NANOSLEEP, SYS_OPEN, SYS_READ, SYS_WAIT4, SYS_WRITE,
+};
+use common::time::Timespec;
 use common::ustar::find_file;
+use common::vma::{MemoryMap, VmaFile};
 use core::arch::asm;
 use core::fmt::Write;
 use limine::BaseRevision;
-use limine::request::{FramebufferRequest, ModuleRequest, RequestsEndMarker, RequestsStartMarker};
-use spin::Mutex;
+use limine::request::{
+    ExecutableAddressRequest, ExecutableCmdlineRequest, FramebufferRequest, HhdmRequest,
+    MemoryMapRequest, ModuleRequest, MpRequest, RequestsEndMarker, RequestsStartMarker,
+    RsdpRequest,
+};
 use tty::TTY;
 
 #[used]
@@ -33,6 +73,24 @@ static FRAMEBUFFER_REQUEST: FramebufferRequest = FramebufferRequest::new();
 #[used]
 #[unsafe(link_section = ".requests")]
 static MODULE_REQUEST: ModuleRequest = ModuleRequest::new();
+#[used]
+#[unsafe(link_section = ".requests")]
+static HHDM_REQUEST: HhdmRequest = HhdmRequest::new();
+#[used]
+#[unsafe(link_section = ".requests")]
+static MEMORY_MAP_REQUEST: MemoryMapRequest = MemoryMapRequest::new();
+#[used]
+#[unsafe(link_section = ".requests")]
+static EXECUTABLE_ADDRESS_REQUEST: ExecutableAddressRequest = ExecutableAddressRequest::new();
+#[used]
+#[unsafe(link_section = ".requests")]
+static CMDLINE_REQUEST: ExecutableCmdlineRequest = ExecutableCmdlineRequest::new();
+#[used]
+#[unsafe(link_section = ".requests")]
+static MP_REQUEST: MpRequest = MpRequest::new();
+#[used]
+#[unsafe(link_section = ".requests")]
+static RSDP_REQUEST: RsdpRequest = RsdpRequest::new();
 
 #[used]
 #[unsafe(link_section = ".requests_end_marker")]
@@ -40,19 +98,30 @@ static REQUESTS_END_MARKER: RequestsEndMarker = RequestsEndMarker::new();
 
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo<'_>) -> ! {
-    let _ = writeln!(TTY.lock(), "[panic] {info}");
+    // The panic may have come from inside a TTY write or the lock checker.
+    match TTY.try_lock() {
+        Some(mut tty) => {
+            let _ = writeln!(tty, "[panic] {info}");
+        }
+        None => {
+            let _ = writeln!(serial::SerialWriter, "[panic] {info}");
+        }
+    }
     loop {
         unsafe { asm!("hlt") };
     }
 }
 
-static PROCESS_STACK: Mutex<ProcessStack<16>> = Mutex::new(ProcessStack::new());
-
 static mut INITRAMFS_ADDR: usize = 0;
 static mut INITRAMFS_SIZE: usize = 0;
 
+const PATH_MAX: usize = 4096;
+const IO_CHUNK: usize = 64 * 1024;
+
 #[unsafe(no_mangle)]
 extern "C" fn kmain() -> ! {
+    // Locks find their per-CPU state through gs, so this comes first.
+    percpu::init(0);
     assert!(BASE_REVISION.is_supported());
 
     serial::serial_init();
@@ -64,13 +133,69 @@ extern "C" fn kmain() -> ! {
     }
 
     let _ = writeln!(TTY.lock(), "[kernel] limine boot ok");
+
+    gdt::install_gdt(0);
+    interrupts::install_idt();
+    interrupts::install_syscall();
+    if uaccess::init() {
+        let _ = writeln!(TTY.lock(), "[kernel] SMAP enabled for user-memory access");
+    }
+    if !random::init() {
+        let _ = writeln!(
+            TTY.lock(),
+            "[kernel] no RDRAND, seeding randomness from the TSC"
+        );
+    }
+    let cmdline = CMDLINE_REQUEST
+        .get_response()
+        .and_then(|r| r.cmdline().to_str().ok())
+        .unwrap_or("");
+    if cmdline
+        .split_ascii_whitespace()
+        .any(|arg| arg == "norandmaps")
+    {
+        memory::set_randomize(false);
+    }
+    if let Some(addr) = EXECUTABLE_ADDRESS_REQUEST.get_response() {
+        let _ = writeln!(
+            TTY.lock(),
+            "[kernel] loaded at {:#x} (phys {:#x}), user ASLR {}",
+            addr.virtual_base(),
+            addr.physical_base(),
+            if memory::randomize() { "on" } else { "off" }
+        );
+    }
+
+    let hhdm = HHDM_REQUEST.get_response().expect("missing hhdm response");
+    paging::init(hhdm.offset() as usize);
+    let memmap = MEMORY_MAP_REQUEST
+        .get_response()
+        .expect("missing memory map response");
+    let frames = frame::init(memmap.entries()).expect("init frame allocator");
     let _ = writeln!(
         TTY.lock(),
-        "[kernel] simple mem manager ready ({} KiB)",
-        memory::USER_MEM_POOL_SIZE / 1024
+        "[kernel] frame allocator ready: {} KiB free, {} KiB used ({} frames)",
+        frames.free * frame::FRAME_SIZE / 1024,
+        frames.used * frame::FRAME_SIZE / 1024,
+        frames.total
     );
-
-    interrupts::install_idt();
+    let heap_bytes = heap::init().expect("init kernel heap");
+    let _ = writeln!(
+        TTY.lock(),
+        "[kernel] heap ready ({} KiB)",
+        heap_bytes / 1024
+    );
+    let fpu = fpu::init().expect("set up FPU state");
+    let _ = writeln!(
+        TTY.lock(),
+        "[kernel] FPU state: {}, {} bytes per thread, AVX {}",
+        if fpu.xsave { "xsave" } else { "fxsave" },
+        fpu.area_size,
+        if fpu.avx { "on" } else { "off" }
+    );
+    kstack::init().expect("reserve kernel stack region");
+    lapic::init().expect("map local APIC");
+    lapic::enable();
 
     let module = MODULE_REQUEST
         .get_response()
@@ -81,159 +206,588 @@ extern "C" fn kmain() -> ! {
         INITRAMFS_ADDR = module.addr() as usize;
         INITRAMFS_SIZE = module.size() as usize;
     }
-    vfs::init(unsafe { INITRAMFS_ADDR }, unsafe { INITRAMFS_SIZE });
+    vfs::init(unsafe { INITRAMFS_ADDR }, unsafe { INITRAMFS_SIZE }).expect("init vfs");
 
-    let entry_addr = load_init_entry().expect("failed to stage init image");
-    let root_pid = PROCESS_STACK
-        .lock()
-        .push_initial(entry_addr)
+    let init = load_init_image().expect("failed to stage init image");
+    let root_pid = sched::processes()
+        .push_initial(init.context, init.address_space, init.memory)
         .expect("create root process");
 
+    pic::disable();
+    let hpet_hz = RSDP_REQUEST
+        .get_response()
+        .and_then(|rsdp| acpi::find_table(rsdp.address(), HPET_SIGNATURE))
+        .and_then(common::acpi::hpet_base)
+        .and_then(hpet::init);
+    match hpet_hz {
+        Some(hz) => {
+            let _ = writeln!(TTY.lock(), "[kernel] HPET found through ACPI, {} Hz", hz);
+        }
+        None => {
+            let _ = writeln!(TTY.lock(), "[kernel] no HPET, calibrating against the PIT");
+        }
+    }
+    let clock = timer::init(hpet_hz.is_some()).expect("calibrate timers");
+    let _ = writeln!(
+        TTY.lock(),
+        "[kernel] TSC clocksource at {} kHz ({}invariant), APIC timer at {} kHz, calibrated against the {}",
+        clock.tsc_hz / 1000,
+        if clock.invariant_tsc { "" } else { "not " },
+        clock.lapic_hz / 1000,
+        clock.reference
+    );
+    let cpus = smp::start(MP_REQUEST.get_response());
+    let _ = writeln!(
+        TTY.lock(),
+        "[kernel] scheduler ready on {} CPUs: root pid={}, {} ms time slices",
+        cpus,
+        root_pid,
+        timer::SLICE_NS / 1_000_000
+    );
     let _ = writeln!(
         TTY.lock(),
-        "[kernel] process stack ready: root pid={}",
-        root_pid
+        "[kernel] launching init @ {:#x}",
+        init.context.rip
     );
-    let _ = writeln!(TTY.lock(), "[kernel] launching init @ {:#x}", entry_addr);
 
-    let entry: extern "C" fn() -> ! = unsafe { core::mem::transmute(entry_addr) };
-    entry()
+    smp::release();
+    sched::start()
 }
 
-fn load_named_entry(path: &str) -> Option<usize> {
+struct LoadedImage {
+    context: ProcessContext,
+    address_space: AddressSpace,
+    memory: MemoryMap,
+}
+
+fn find_image(path: &str) -> Result<ElfImage<'static>, i64> {
     let archive =
         unsafe { core::slice::from_raw_parts(INITRAMFS_ADDR as *const u8, INITRAMFS_SIZE) };
 
     let cleaned = path.trim_start_matches('/');
-    let file = find_file(archive, cleaned).or_else(|| {
-        let basename = cleaned.rsplit('/').next()?;
-        find_file(archive, basename)
-    })?;
+    let file = find_file(archive, cleaned)
+        .or_else(|| {
+            let basename = cleaned.rsplit('/').next()?;
+            find_file(archive, basename)
+        })
+        .ok_or(-2)?;
+    parse_elf64(file.data).ok_or(-8)
+}
 
-    let image = parse_elf64(file.data)?;
-    elf_loader::load_init_image(file.data, &image.program_headers, image.entry)
+/// Maps the `PT_INTERP` interpreter of a program next to it and returns the
+/// interpreter's load.
+fn load_interpreter(
+    path: &[u8],
+    pml4: usize,
+    memory: &mut MemoryMap,
+) -> Result<elf_loader::LoadedElf, i64> {
+    let path = core::str::from_utf8(path).map_err(|_| -8)?;
+    if path.is_empty() {
+        return Err(-8);
+    }
+    let interp = find_image(path)?;
+    if interp.interpreter().is_some() {
+        return Err(-8);
+    }
+    // The heap stays after the program, not the interpreter.
+    let heap = memory.brk_start();
+    let bias = elf_loader::interpreter_bias(&interp, memory)?;
+    let loaded = elf_loader::load_image(&interp, pml4, memory, bias)?;
+    memory.set_heap_start(heap);
+    Ok(loaded)
 }
 
-fn load_init_entry() -> Option<usize> {
-    load_named_entry("init.elf")
+fn load_named_image(path: &str, argv: &[Vec<u8>], envp: &[Vec<u8>]) -> Result<LoadedImage, i64> {
+    let image = find_image(path)?;
+    let stack_size = elf_loader::stack_size(&image)?;
+    let address_space = paging::owned(paging::new_address_space().ok_or(-12)?);
+    let root = address_space.root();
+    let mut memory = MemoryMap::new();
+    memory::randomize_mmap_base(&mut memory);
+    let bias = elf_loader::load_bias(&image);
+    let program = elf_loader::load_image(&image, root, &mut memory, bias)?;
+    // With an interpreter, TLS and relocations are its job.
+    let (start, interp_base, fs_base) = match image.interpreter() {
+        Some(interp) => {
+            let interp = load_interpreter(interp, root, &mut memory)?;
+            (interp.start, interp.base, 0)
+        }
+        None => (
+            program.start,
+            0,
+            elf_loader::setup_tls(&image, root, &mut memory)?,
+        ),
+    };
+    let top = memory::map_user_stack(&mut memory, stack_size).ok_or(-12)?;
+
+    let mut random = [0u8; 16];
+    random::fill(&mut random);
+    let auxv = [
+        (
+            AT_PHDR,
+            image.phdr_addr().map_or(0, |addr| addr + bias) as u64,
+        ),
+        (AT_PHENT, image.phentsize() as u64),
+        (AT_PHNUM, image.phnum() as u64),
+        (AT_PAGESZ, paging::PAGE_SIZE as u64),
+        (AT_BASE, interp_base as u64),
+        (AT_ENTRY, program.entry as u64),
+    ];
+    let stack =
+        initial_stack(top, argv, envp, path.as_bytes(), &auxv, random).map_err(|e| match e {
+            StackError::TooLarge => -7,
+            StackError::OutOfMemory => -12,
+        })?;
+    memory::write_user(root, &mut memory, stack.rsp, &stack.bytes).ok_or(-12)?;
+    Ok(LoadedImage {
+        context: ProcessContext {
+            fs_base,
+            ..ProcessContext::new(start, stack.rsp)
+        },
+        address_space,
+        memory,
+    })
 }
 
-#[unsafe(no_mangle)]
-extern "C" fn syscall_dispatch(nr: u64, fd: u64, ptr: u64, len: u64) -> i64 {
+fn load_init_image() -> Result<LoadedImage, i64> {
+    load_named_image("init.elf", &[b"init.elf".to_vec()], &[])
+}
+
+fn with_memory<T>(f: impl FnOnce(usize, &mut MemoryMap) -> Result<T, i64>) -> Result<T, i64> {
+    let mut table = sched::processes();
+    let proc = table.current_mut().ok_or(-3)?;
+    let pagemap = proc.pagemap().ok_or(-3)?;
+    f(pagemap, &mut proc.memory)
+}
+
+fn mapped_file(fd: u64, offset: u64) -> Result<VmaFile, i64> {
+    let offset = offset as usize;
+    if !offset.is_multiple_of(paging::PAGE_SIZE) {
+        return Err(-22);
+    }
+    let (handle, _) = sched::processes()
+        .current()
+        .and_then(|proc| proc.resolve_fd(fd))
+        .ok_or(-9)?;
+    vfs::mappable(handle)?;
+    Ok(VmaFile { handle, offset })
+}
+
+/// Reads a NULL-terminated array of C strings, charging their bytes to `budget`.
+fn read_user_strings(ptr: u64, budget: &mut usize) -> Result<Vec<Vec<u8>>, i64> {
+    let mut strings = Vec::new();
+    if ptr == 0 {
+        return Ok(strings);
+    }
+    let mut slot = ptr as usize;
+    loop {
+        let mut word = [0u8; 8];
+        uaccess::copy_from_user(&mut word, slot)?;
+        let addr = u64::from_le_bytes(word) as usize;
+        if addr == 0 {
+            return Ok(strings);
+        }
+        let string = uaccess::read_user_cstr(addr, *budget)?;
+        *budget -= string.len() + 1;
+        strings.try_reserve(1).map_err(|_| -12)?;
+        strings.push(string);
+        slot = slot.checked_add(8).ok_or(uaccess::EFAULT)?;
+    }
+}
+
+/// `clone` for threads only: the child shares everything but its stack,
+/// registers and TLS.
+fn clone_thread(flags: u64, stack: u64, parent_tid: u64, child_tid: u64, tls: u64) -> i64 {
+    const SHARED: u64 = CLONE_VM | CLONE_FILES | CLONE_SIGHAND | CLONE_THREAD;
+    if flags & SHARED != SHARED
+        || (flags & CLONE_SETTLS != 0 && tls as usize >= paging::USER_SPACE_END)
+    {
+        return -22;
+    }
+    let mut table = sched::processes();
+    fpu::save_current(&mut table);
+    let Some(parent) = table.current_thread() else {
+        return -3;
+    };
+    let mut context = ProcessContext {
+        rax: 0,
+        ..parent.context
+    };
+    if stack != 0 {
+        context.rsp = stack as usize;
+    }
+    if flags & CLONE_SETTLS != 0 {
+        context.fs_base = tls as usize;
+    }
+    let Ok(tid) = table.spawn_thread(context) else {
+        return -12;
+    };
+    if let Some(thread) = table.thread_mut(tid) {
+        // Its tid words must be in place before it can run and clear them.
+        thread.state = ProcessState::Blocked(BlockReason::Starting);
+        if flags & CLONE_CHILD_CLEARTID != 0 {
+            thread.clear_child_tid = child_tid as usize;
+        }
+    }
+    drop(table);
+
+    let bytes = (tid as u32).to_le_bytes();
+    let mut ret = tid as i64;
+    for (flag, addr) in [
+        (CLONE_PARENT_SETTID, parent_tid),
+        (CLONE_CHILD_SETTID, child_tid),
+    ] {
+        if flags & flag != 0
+            && let Err(e) = uaccess::copy_to_user(addr as usize, &bytes)
+        {
+            ret = e;
+            break;
+        }
+    }
+
+    // A sibling's exit_group may have killed it in the meantime.
+    if let Some(thread) = sched::processes().thread_mut(tid)
+        && thread.state == ProcessState::Blocked(BlockReason::Starting)
+    {
+        thread.state = ProcessState::Runnable;
+    }
+    smp::kick_idle();
+    ret
+}
+
+fn arch_prctl(code: u64, addr: u64) -> i64 {
+    let mut table = sched::processes();
+    let Some(thread) = table.current_thread_mut() else {
+        return -3;
+    };
+    match code {
+        ARCH_SET_FS if (addr as usize) < paging::USER_SPACE_END => {
+            thread.context.fs_base = addr as usize;
+            0
+        }
+        ARCH_SET_FS => -1,
+        ARCH_GET_FS => {
+            let fs_base = thread.context.fs_base as u64;
+            drop(table);
+            uaccess::copy_to_user(addr as usize, &fs_base.to_le_bytes()).map_or_else(|e| e, |()| 0)
+        }
+        _ => -22,
+    }
+}
+
+/// Private futexes without timeouts; waiters are keyed by process and address.
+fn futex(addr: u64, op: u64, val: u64, timeout: u64) -> i64 {
+    let addr = addr as usize;
+    if !addr.is_multiple_of(4) {
+        return -22;
+    }
+    match op & !FUTEX_PRIVATE_FLAG {
+        FUTEX_WAIT => {
+            if timeout != 0 {
+                return -22;
+            }
+            // Block before checking the word: a wake from another CPU in
+            // between then just leaves this thread runnable.
+            if sched::processes()
+                .block_current(BlockReason::Futex(addr))
+                .is_err()
+            {
+                return -3;
+            }
+            let mut word = [0u8; 4];
+            let result = match uaccess::copy_from_user(&mut word, addr) {
+                Ok(()) if u32::from_le_bytes(word) == val as u32 => 0,
+                Ok(()) => -11,
+                Err(e) => e,
+            };
+            if result != 0 {
+                if let Some(thread) = sched::processes().current_thread_mut() {
+                    thread.state = ProcessState::Runnable;
+                }
+                return result;
+            }
+            sched::schedule(false);
+            0
+        }
+        FUTEX_WAKE => {
+            let woken = sched::processes().wake_futex(addr, val as u32 as usize);
+            smp::kick_idle();
+            woken.map_or(-3, |n| n as i64)
+        }
+        _ => -38,
+    }
+}
+
+/// Sleeps on the monotonic clock. Nothing interrupts a sleep, so `rem` is
+/// never written.
+fn nanosleep(req: u64) -> i64 {
+    let mut bytes = [0u8; Timespec::SIZE];
+    if let Err(e) = uaccess::copy_from_user(&mut bytes, req as usize) {
+        return e;
+    }
+    let Some(ns) = Timespec::from_le_bytes(bytes).to_nanos() else {
+        return -22;
+    };
+    let deadline = timer::now_ns().saturating_add(ns);
+    sched::block_current(BlockReason::Sleep(deadline));
+    0
+}
+
+/// Both clocks count from boot; there is no wall clock yet.
+fn clock_gettime(clock: u64, tp: u64) -> i64 {
+    if clock != CLOCK_MONOTONIC && clock != CLOCK_BOOTTIME {
+        return -22;
+    }
+    let now = Timespec::from_nanos(timer::now_ns());
+    uaccess::copy_to_user(tp as usize, &now.to_le_bytes()).map_or_else(|e| e, |()| 0)
+}
+
+/// Clears and wakes the `CLONE_CHILD_CLEARTID` word, as a joining thread expects.
+fn clear_child_tid() {
+    let addr = sched::processes()
+        .current_thread()
+        .map_or(0, |t| t.clear_child_tid);
+    if addr != 0 && uaccess::copy_to_user(addr, &0u32.to_le_bytes()).is_ok() {
+        let _ = sched::processes().wake_futex(addr, 1);
+        smp::kick_idle();
+    }
+}
+
+fn read_user_path(ptr: u64, len: u64) -> Result<Vec<u8>, i64> {
+    if len as usize > PATH_MAX {
+        return Err(-36);
+    }
+    uaccess::read_user(ptr as usize, len as usize)
+}
+
+pub fn syscall_dispatch(nr: u64, args: [u64; 6]) -> i64 {
+    let [fd, ptr, len, ..] = args;
     match nr {
         SYS_OPEN => {
-            let bytes = unsafe { core::slice::from_raw_parts(ptr as *const u8, fd as usize) };
-            let Ok(path) = core::str::from_utf8(bytes) else {
+            let bytes = match read_user_path(ptr, fd) {
+                Ok(bytes) => bytes,
+                Err(e) => return e,
+            };
+            let Ok(path) = core::str::from_utf8(&bytes) else {
                 return -22;
             };
             let Some(handle) = vfs::open(path) else {
                 return -2;
             };
-            let mut stack = PROCESS_STACK.lock();
+            let mut stack = sched::processes();
             let Some(proc) = stack.current_mut() else {
                 return -3;
             };
-            proc.install_fd(handle).map(|n| n as i64).unwrap_or(-24)
+            proc.install_fd(handle).map(|n| n as i64).unwrap_or(-12)
         }
         SYS_WRITE => {
-            let bytes = unsafe { core::slice::from_raw_parts(ptr as *const u8, len as usize) };
-            let mut stack = PROCESS_STACK.lock();
+            let bytes = match uaccess::read_user(ptr as usize, (len as usize).min(IO_CHUNK)) {
+                Ok(bytes) => bytes,
+                Err(e) => return e,
+            };
+            let mut stack = sched::processes();
             let Some(proc) = stack.current_mut() else {
                 return -3;
             };
             let Some((handle, _)) = proc.resolve_fd(fd) else {
                 return -9;
             };
-            match vfs::write(handle, bytes) {
+            match vfs::write(handle, &bytes) {
                 Ok(n) => n as i64,
                 Err(e) => e,
             }
         }
-        SYS_READ => {
-            let mut stack = PROCESS_STACK.lock();
+        SYS_READ => loop {
+            let mut buf = Vec::new();
+            let want = (len as usize).min(IO_CHUNK);
+            if buf.try_reserve_exact(want).is_err() {
+                return -12;
+            }
+            buf.resize(want, 0);
+            let mut stack = sched::processes();
             let Some(proc) = stack.current_mut() else {
                 return -3;
             };
             let Some((handle, offset)) = proc.resolve_fd(fd) else {
                 return -9;
             };
-            let dst = unsafe { core::slice::from_raw_parts_mut(ptr as *mut u8, len as usize) };
-            match vfs::read(handle, offset, dst) {
+            match vfs::read(handle, offset, &mut buf) {
                 Ok(n) => {
-                    let _ = proc.advance_fd(fd, n);
-                    n as i64
+                    drop(stack);
+                    if let Err(e) = uaccess::copy_to_user(ptr as usize, &buf[..n]) {
+                        return e;
+                    }
+                    if let Some(proc) = sched::processes().current_mut() {
+                        let _ = proc.advance_fd(fd, n);
+                    }
+                    return n as i64;
                 }
-                Err(e) => e,
+                Err(-11) => {
+                    drop(stack);
+                    sched::block_current(BlockReason::Stdin);
+                }
+                Err(e) => return e,
             }
+        },
+        SYS_MMAP => {
+            let [addr, len, prot, flags, fd, offset] = args;
+            let file = if flags as u32 & MAP_ANONYMOUS == 0 {
+                match mapped_file(fd, offset) {
+                    Ok(file) => Some(file),
+                    Err(e) => return e,
+                }
+            } else {
+                None
+            };
+            with_memory(|pagemap, vm| {
+                memory::mmap(
+                    pagemap,
+                    vm,
+                    addr as usize,
+                    len as usize,
+                    prot as u32,
+                    flags as u32,
+                    file,
+                )
+            })
+            .map_or_else(|e| e, |addr| addr as i64)
         }
-        SYS_MEMMAP => {
-            let req_len = fd as usize;
-            memory::MEM_MANAGER
-                .lock()
-                .memmap(req_len)
-                .map(|addr| addr as i64)
-                .unwrap_or(-12)
-        }
+        SYS_MUNMAP => with_memory(|pagemap, vm| {
+            memory::munmap(pagemap, vm, args[0] as usize, args[1] as usize)
+        })
+        .map_or_else(|e| e, |()| 0),
+        SYS_MPROTECT => with_memory(|pagemap, vm| {
+            memory::mprotect(
+                pagemap,
+                vm,
+                args[0] as usize,
+                args[1] as usize,
+                args[2] as u32,
+            )
+        })
+        .map_or_else(|e| e, |()| 0),
+        SYS_BRK => with_memory(|pagemap, vm| Ok(memory::brk(pagemap, vm, args[0] as usize)))
+            .map_or_else(|e| e, |brk| brk as i64),
         SYS_FORK => {
-            let mut stack = PROCESS_STACK.lock();
-            match stack.fork_current((ptr != 0).then_some(ptr as usize)) {
+            let mut table = sched::processes();
+            let Some(parent_map) = table.current().and_then(|p| p.pagemap()) else {
+                return -3;
+            };
+            let Some(child_map) = paging::clone_address_space(parent_map) else {
+                return -12;
+            };
+            fpu::save_current(&mut table);
+            match table.fork_current(paging::owned(child_map)) {
                 Ok(child_pid) => {
+                    drop(table);
+                    smp::kick_idle();
                     let _ = writeln!(
                         TTY.lock(),
-                        "[kernel] fork: pushed child pid={} (running child first)",
+                        "[kernel] fork: created child pid={} with a copied address space",
                         child_pid
                     );
-                    0
+                    child_pid as i64
                 }
                 Err(_) => -12,
             }
         }
         SYS_EXECVE => {
-            let bytes = unsafe { core::slice::from_raw_parts(ptr as *const u8, fd as usize) };
-            let Ok(path) = core::str::from_utf8(bytes) else {
+            let bytes = match read_user_path(ptr, fd) {
+                Ok(bytes) => bytes,
+                Err(e) => return e,
+            };
+            let Ok(path) = core::str::from_utf8(&bytes) else {
                 return -22;
             };
+            let mut budget = ARG_MAX;
+            let (argv, envp) = match read_user_strings(len, &mut budget)
+                .and_then(|argv| Ok((argv, read_user_strings(args[3], &mut budget)?)))
+            {
+                Ok(strings) => strings,
+                Err(e) => return e,
+            };
 
-            let Some(entry_addr) = load_named_entry(path) else {
-                return -2;
+            let image = match load_named_image(path, &argv, &envp) {
+                Ok(image) => image,
+                Err(e) => return e,
             };
-            if PROCESS_STACK.lock().exec_current(entry_addr).is_err() {
-                return -1;
+            // Another thread may be tearing the process down already.
+            if !sched::stop_other_threads() {
+                return -4;
             }
+            let root = image.address_space.root();
+            let old = match sched::processes().exec_current(
+                image.context,
+                image.address_space,
+                image.memory,
+            ) {
+                Ok(old) => old,
+                Err(_) => return -1,
+            };
             let _ = writeln!(
                 TTY.lock(),
                 "[kernel] execve: replaced current process image with {}",
                 path
             );
-            let entry: extern "C" fn() -> ! = unsafe { core::mem::transmute(entry_addr) };
-            entry()
+            paging::activate(root);
+            fpu::reset();
+            drop(old);
+            0
+        }
+        SYS_CLONE => clone_thread(args[0], args[1], args[2], args[3], args[4]),
+        SYS_ARCH_PRCTL => arch_prctl(args[0], args[1]),
+        SYS_FUTEX => futex(args[0], args[1], args[2], args[3]),
+        SYS_NANOSLEEP => nanosleep(fd),
+        SYS_CLOCK_GETTIME => clock_gettime(fd, ptr),
+        SYS_GETPID => sched::processes()
+            .current_pid()
+            .map_or(-3, |pid| pid as i64),
+        SYS_GETTID => sched::running().map_or(-3, |tid| tid as i64),
+        SYS_GETCPU => {
+            let cpu = percpu::current().index() as u32;
+            // One NUMA node.
+            for (addr, value) in [(fd, cpu), (ptr, 0)] {
+                if addr != 0
+                    && let Err(e) = uaccess::copy_to_user(addr as usize, &value.to_le_bytes())
+                {
+                    return e;
+                }
+            }
+            0
         }
         SYS_EXIT => {
             let code = fd as i32;
-            let mut stack = PROCESS_STACK.lock();
-            let next = stack.exit_current().ok().flatten();
-            if let Some(pid) = next {
-                let resume_rip = stack.current().map(|p| p.context.rip).unwrap_or(0);
-                let _ = writeln!(
-                    TTY.lock(),
-                    "[kernel] exit({}): popped current process, now pid={} on top",
-                    code,
-                    pid
-                );
-                drop(stack);
-                if resume_rip != 0 {
-                    let entry: extern "C" fn() -> ! = unsafe { core::mem::transmute(resume_rip) };
-                    entry()
-                }
-                pid as i64
-            } else {
-                let _ = writeln!(TTY.lock(), "[kernel] exit({}): process stack empty", code);
-                unsafe {
-                    asm!("out dx, al", in("dx") 0xF4u16, in("al") 0x10u8, options(nostack, nomem));
-                }
-                0
+            clear_child_tid();
+            if let Some(pid) = sched::exit_thread(code) {
+                let _ = writeln!(TTY.lock(), "[kernel] exit({}): pid={} exited", code, pid);
+            }
+            0
+        }
+        SYS_EXIT_GROUP => {
+            let code = fd as i32;
+            if let Some(pid) = sched::exit_current(code) {
+                let _ = writeln!(TTY.lock(), "[kernel] exit({}): pid={} exited", code, pid);
             }
+            0
         }
+        SYS_WAIT4 => loop {
+            let target = (fd as i64 > 0).then_some(fd);
+            let result = sched::processes().wait_current(target);
+            match result {
+                Ok(WaitResult::Reaped { pid, code }) => {
+                    if ptr != 0 {
+                        let status = ((code & 0xff) << 8).to_le_bytes();
+                        if let Err(e) = uaccess::copy_to_user(ptr as usize, &status) {
+                            return e;
+                        }
+                    }
+                    return pid as i64;
+                }
+                Ok(WaitResult::WouldBlock) => sched::schedule(false),
+                Ok(WaitResult::NoChildren) => return -10,
+                Err(_) => return -3,
+            }
+        },
         _ => -38,
     }
 }
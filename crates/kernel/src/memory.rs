@@ -1,27 +1,402 @@
-use spin::Mutex;
+use crate::paging::{self, PAGE_SIZE, PageFlags, USER_SPACE_END};
+use crate::{frame, random, sched, smp, vfs};
+use common::syscall::{
+    MAP_ANONYMOUS, MAP_FIXED, MAP_PRIVATE, MAP_SHARED, PROT_EXEC, PROT_NONE, PROT_READ, PROT_WRITE,
+};
+use common::vma::{MemoryMap, Vma, VmaFile, VmaKind, page_align_up};
+use core::sync::atomic::{AtomicBool, Ordering};
 
-pub const USER_MEM_POOL_SIZE: usize = 1024 * 1024;
-static mut USER_MEM_POOL: [u8; USER_MEM_POOL_SIZE] = [0; USER_MEM_POOL_SIZE];
+pub const MMAP_BASE: usize = 0x0000_1000_0000_0000;
+pub const MMAP_WINDOW_SIZE: usize = 64 * 1024 * 1024 * 1024;
+const MMAP_MIN_ADDR: usize = 0x1_0000;
+// Frames unmapped per TLB shootdown.
+const SHOOTDOWN_BATCH: usize = 64;
 
-pub struct MemManager {
-    next: usize,
+pub const USER_STACK_TOP: usize = 0x0000_7fff_ffff_f000;
+pub const USER_STACK_SIZE: usize = 64 * 1024;
+/// How far the stack may grow below `USER_STACK_TOP`; the page under it is a guard.
+pub const USER_STACK_LIMIT: usize = 8 * 1024 * 1024;
+
+/// PIE executables are loaded at `PIE_BASE` plus up to `PIE_RANDOM_PAGES`
+/// pages; stacks and mmap bases shift by up to their own page counts.
+pub const PIE_BASE: usize = 0x0000_5555_0000_0000;
+const PIE_RANDOM_PAGES: usize = 1 << 24;
+const STACK_RANDOM_PAGES: usize = 1 << 20;
+const MMAP_RANDOM_PAGES: usize = 1 << 23;
+
+static RANDOMIZE: AtomicBool = AtomicBool::new(true);
+
+const EACCES: i64 = -13;
+const ENOMEM: i64 = -12;
+const EINVAL: i64 = -22;
+
+/// Turns user address-space randomization on or off (`norandmaps`).
+pub fn set_randomize(on: bool) {
+    RANDOMIZE.store(on, Ordering::Relaxed);
+}
+
+pub fn randomize() -> bool {
+    RANDOMIZE.load(Ordering::Relaxed)
 }
 
-impl MemManager {
-    pub const fn new() -> Self {
-        Self { next: 0 }
+/// A random page-aligned offset below `pages` pages, or 0 with randomization off.
+pub fn random_offset(pages: usize) -> usize {
+    if !randomize() {
+        return 0;
     }
+    (random::next_u64() as usize % pages) * PAGE_SIZE
+}
 
-    pub fn memmap(&mut self, length: usize) -> Option<usize> {
-        let aligned = (length + 0xfff) & !0xfff;
-        let off = (self.next + 0xfff) & !0xfff;
-        if off.checked_add(aligned)? > USER_MEM_POOL_SIZE {
+/// Where a PIE whose lowest segment is linked at `link_base` gets loaded.
+pub fn pie_bias(link_base: usize) -> usize {
+    if !randomize() {
+        return 0;
+    }
+    PIE_BASE
+        .checked_sub(link_base & !(PAGE_SIZE - 1))
+        .map_or(0, |bias| bias + random_offset(PIE_RANDOM_PAGES))
+}
+
+pub fn randomize_mmap_base(memory: &mut MemoryMap) {
+    memory.set_mmap_base(MMAP_BASE + random_offset(MMAP_RANDOM_PAGES));
+}
+
+pub fn page_flags(prot: u32, shared: bool) -> PageFlags {
+    let mut flags = PageFlags::NO_EXECUTE;
+    if prot != PROT_NONE {
+        flags |= PageFlags::USER;
+    }
+    if prot & PROT_WRITE != 0 {
+        flags |= PageFlags::WRITABLE;
+    }
+    if prot & PROT_EXEC != 0 {
+        flags.remove(PageFlags::NO_EXECUTE);
+    }
+    if shared {
+        flags |= PageFlags::SHARED;
+    }
+    flags
+}
+
+pub fn prot_of(flags: PageFlags) -> u32 {
+    let mut prot = PROT_READ;
+    if flags.intersects(PageFlags::WRITABLE | PageFlags::COPY_ON_WRITE) {
+        prot |= PROT_WRITE;
+    }
+    if !flags.contains(PageFlags::NO_EXECUTE) {
+        prot |= PROT_EXEC;
+    }
+    prot
+}
+
+fn check_prot(prot: u32) -> Result<(), i64> {
+    if prot & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0 {
+        return Err(EINVAL);
+    }
+    if prot & (PROT_WRITE | PROT_EXEC) == PROT_WRITE | PROT_EXEC {
+        return Err(EACCES);
+    }
+    Ok(())
+}
+
+fn user_range(addr: usize, len: usize) -> Result<usize, i64> {
+    if !addr.is_multiple_of(PAGE_SIZE) || len == 0 {
+        return Err(EINVAL);
+    }
+    let end = addr
+        .checked_add(len)
+        .filter(|&end| end <= USER_SPACE_END)
+        .ok_or(EINVAL)?;
+    Ok(page_align_up(end))
+}
+
+fn map_fresh(pml4: usize, start: usize, end: usize, flags: PageFlags) -> Option<()> {
+    for page in (start..end).step_by(PAGE_SIZE) {
+        let mapped = frame::alloc_frame().and_then(|phys| {
+            let mapped = paging::map_page(pml4, page, phys, flags);
+            if mapped.is_none() {
+                frame::free_frame(phys);
+            }
+            mapped
+        });
+        if mapped.is_none() {
+            unmap_and_free(pml4, start, page);
             return None;
         }
-        self.next = off + aligned;
-        let base = core::ptr::addr_of_mut!(USER_MEM_POOL) as *mut u8;
-        Some(base as usize + off)
     }
+    Some(())
+}
+
+/// Frees the frames only once other CPUs running the same page tables can
+/// no longer reach them through stale translations.
+fn unmap_and_free(pml4: usize, start: usize, end: usize) {
+    let mut batch = [0; SHOOTDOWN_BATCH];
+    let mut len = 0;
+    for page in (start..end).step_by(PAGE_SIZE) {
+        if let Some(phys) = paging::unmap_page(pml4, page) {
+            batch[len] = phys;
+            len += 1;
+        }
+        if len == SHOOTDOWN_BATCH || (len > 0 && page + PAGE_SIZE >= end) {
+            smp::flush_tlb_others();
+            batch[..len]
+                .iter()
+                .for_each(|&phys| frame::free_frame(phys));
+            len = 0;
+        }
+    }
+}
+
+/// Finds `len` free bytes in the mmap window, preferring the process's
+/// randomized base.
+pub fn free_area(memory: &MemoryMap, len: usize) -> Option<usize> {
+    let window_end = MMAP_BASE + MMAP_WINDOW_SIZE;
+    memory
+        .find_free(len, memory.mmap_base().max(MMAP_BASE), window_end)
+        .or_else(|| memory.find_free(len, MMAP_BASE, window_end))
 }
 
-pub static MEM_MANAGER: Mutex<MemManager> = Mutex::new(MemManager::new());
+pub fn mmap(
+    pml4: usize,
+    memory: &mut MemoryMap,
+    addr: usize,
+    len: usize,
+    prot: u32,
+    flags: u32,
+    file: Option<VmaFile>,
+) -> Result<usize, i64> {
+    let file = if flags & MAP_ANONYMOUS != 0 {
+        None
+    } else {
+        Some(file.ok_or(EINVAL)?)
+    };
+    let shared = match flags & (MAP_SHARED | MAP_PRIVATE) {
+        MAP_SHARED => true,
+        MAP_PRIVATE => false,
+        _ => return Err(EINVAL),
+    };
+    check_prot(prot)?;
+    // Mappable files all live in the read-only initramfs.
+    if shared && file.is_some() && prot & PROT_WRITE != 0 {
+        return Err(EACCES);
+    }
+    if len == 0 {
+        return Err(EINVAL);
+    }
+    let len = len.checked_add(PAGE_SIZE - 1).ok_or(ENOMEM)? & !(PAGE_SIZE - 1);
+
+    let start = if flags & MAP_FIXED != 0 {
+        if addr < MMAP_MIN_ADDR {
+            return Err(EINVAL);
+        }
+        munmap(pml4, memory, addr, len)?;
+        addr
+    } else {
+        let hint = addr & !(PAGE_SIZE - 1);
+        let hint_end = hint.saturating_add(len);
+        if hint >= MMAP_MIN_ADDR && hint_end <= USER_SPACE_END && memory.is_free(hint, hint_end) {
+            hint
+        } else {
+            free_area(memory, len).ok_or(ENOMEM)?
+        }
+    };
+
+    let kind = if file.is_some() {
+        VmaKind::File
+    } else {
+        VmaKind::Anonymous
+    };
+    let vma = Vma {
+        shared,
+        file,
+        ..Vma::new(start, start + len, prot, kind)
+    };
+    memory.insert(vma).map_err(|_| ENOMEM)?;
+    // Private and file pages are filled in on first touch; shared anonymous
+    // ones must exist before a fork so both sides see the same frames.
+    if shared
+        && file.is_none()
+        && map_fresh(pml4, vma.start, vma.end, page_flags(prot, true)).is_none()
+    {
+        let _ = memory.remove(vma.start, vma.end);
+        return Err(ENOMEM);
+    }
+    Ok(start)
+}
+
+pub fn munmap(pml4: usize, memory: &mut MemoryMap, addr: usize, len: usize) -> Result<(), i64> {
+    let end = user_range(addr, len)?;
+    memory.remove(addr, end).map_err(|_| ENOMEM)?;
+    unmap_and_free(pml4, addr, end);
+    Ok(())
+}
+
+pub fn mprotect(
+    pml4: usize,
+    memory: &mut MemoryMap,
+    addr: usize,
+    len: usize,
+    prot: u32,
+) -> Result<(), i64> {
+    check_prot(prot)?;
+    let end = user_range(addr, len)?;
+    if prot & PROT_WRITE != 0
+        && memory
+            .overlapping(addr, end)
+            .any(|vma| vma.shared && vma.file.is_some())
+    {
+        return Err(EACCES);
+    }
+    memory.protect(addr, end, prot).map_err(|_| ENOMEM)?;
+    for page in (addr..end).step_by(PAGE_SIZE) {
+        let (Some(phys), Some(shared)) = (
+            paging::translate(pml4, page),
+            memory.find(page).map(|vma| vma.shared),
+        ) else {
+            continue;
+        };
+        let mut flags = page_flags(prot, shared);
+        // A private frame still shared with a fork relative stays copy-on-write.
+        if flags.contains(PageFlags::WRITABLE) && !shared && frame::refcount(phys) > 1 {
+            flags.remove(PageFlags::WRITABLE);
+            flags |= PageFlags::COPY_ON_WRITE;
+        }
+        paging::set_flags(pml4, page, flags);
+    }
+    smp::flush_tlb_others();
+    Ok(())
+}
+
+/// Moves the program break and returns the new one, or the old one on failure.
+pub fn brk(pml4: usize, memory: &mut MemoryMap, brk: usize) -> usize {
+    let old = memory.brk();
+    if brk < memory.brk_start() || brk > USER_SPACE_END || memory.set_brk(brk).is_err() {
+        return old;
+    }
+    unmap_and_free(pml4, page_align_up(brk), page_align_up(old));
+    brk
+}
+
+/// Reserves `size` bytes of initial stack below a randomized top; its pages
+/// are populated on first touch.
+pub fn map_user_stack(memory: &mut MemoryMap, size: usize) -> Option<usize> {
+    let top = USER_STACK_TOP - random_offset(STACK_RANDOM_PAGES);
+    let start = top - size;
+    memory
+        .insert(Vma::new(start, top, PROT_READ | PROT_WRITE, VmaKind::Stack))
+        .ok()?;
+    Some(top)
+}
+
+/// Faults in the writable pages under `addr..addr + bytes.len()` and copies
+/// `bytes` there.
+pub fn write_user(pml4: usize, memory: &mut MemoryMap, addr: usize, bytes: &[u8]) -> Option<()> {
+    let end = addr.checked_add(bytes.len())?;
+    for page in (addr & !(PAGE_SIZE - 1)..end).step_by(PAGE_SIZE) {
+        if !fault_in(pml4, memory, page, true, false) {
+            return None;
+        }
+    }
+    paging::copy_into(pml4, addr, bytes)
+}
+
+/// Extends the stack area right above `page` down to it, keeping a free guard
+/// page below the new bottom and the whole stack within `USER_STACK_LIMIT`.
+fn grow_stack(memory: &mut MemoryMap, page: usize) -> bool {
+    let Some(stack) = memory.areas().iter().find(|a| a.start > page).copied() else {
+        return false;
+    };
+    if stack.kind != VmaKind::Stack
+        || stack.end - page > USER_STACK_LIMIT
+        || page < MMAP_MIN_ADDR + PAGE_SIZE
+        || !memory.is_free(page - PAGE_SIZE, stack.start)
+    {
+        return false;
+    }
+    memory
+        .insert(Vma::new(page, stack.start, stack.prot, VmaKind::Stack))
+        .is_ok()
+}
+
+/// Populates the not-present page at `addr` from its VMA, growing the stack
+/// when `addr` is just below it. Returns false if the access is not allowed.
+pub fn fault_in(pml4: usize, memory: &mut MemoryMap, addr: usize, write: bool, exec: bool) -> bool {
+    let page = addr & !(PAGE_SIZE - 1);
+    if memory.find(page).is_none() && !grow_stack(memory, page) {
+        return false;
+    }
+    let Some(vma) = memory.find(page).copied() else {
+        return false;
+    };
+    if vma.prot == PROT_NONE
+        || (write && vma.prot & PROT_WRITE == 0)
+        || (exec && vma.prot & PROT_EXEC == 0)
+    {
+        return false;
+    }
+    if paging::translate(pml4, page).is_some() {
+        return true;
+    }
+    let flags = page_flags(vma.prot, vma.shared);
+    match (vma.file, vma.file_offset(page)) {
+        (Some(file), Some(offset)) => map_file_page(pml4, page, file.handle, offset, flags),
+        _ => map_fresh(pml4, page, page + PAGE_SIZE, flags).is_some(),
+    }
+}
+
+/// Maps the page-cache frame for `offset` of a file. Private writable
+/// mappings start out copy-on-write so stores never reach the cache.
+fn map_file_page(
+    pml4: usize,
+    page: usize,
+    handle: u64,
+    offset: usize,
+    mut flags: PageFlags,
+) -> bool {
+    let Some(phys) = vfs::file_page(handle, offset / PAGE_SIZE) else {
+        return false;
+    };
+    if !flags.contains(PageFlags::SHARED) && flags.contains(PageFlags::WRITABLE) {
+        flags.remove(PageFlags::WRITABLE);
+        flags |= PageFlags::COPY_ON_WRITE;
+    }
+    if paging::map_page(pml4, page, phys, flags).is_none() {
+        frame::free_frame(phys);
+        return false;
+    }
+    true
+}
+
+/// Resolves a copy-on-write fault in the running process. Holds the process
+/// table so sibling threads faulting on the same page copy it once; the later
+/// ones find the entry already resolved and retry through their stale
+/// translation. Must not be called with the process table locked.
+pub fn resolve_copy_on_write_current(addr: usize) -> bool {
+    if addr >= USER_SPACE_END {
+        return false;
+    }
+    let table = sched::processes();
+    let Some(pml4) = table.current().and_then(|proc| proc.pagemap()) else {
+        return false;
+    };
+    if pml4 != paging::current_address_space() {
+        return false;
+    }
+    paging::resolve_copy_on_write(pml4, addr)
+        || paging::user_flags(pml4, addr).is_some_and(|flags| flags.contains(PageFlags::WRITABLE))
+}
+
+/// `fault_in` for the running process. Must not be called with the process table locked.
+pub fn fault_in_current(addr: usize, write: bool, exec: bool) -> bool {
+    if addr >= USER_SPACE_END {
+        return false;
+    }
+    let mut table = sched::processes();
+    let Some(proc) = table.current_mut() else {
+        return false;
+    };
+    let Some(pagemap) = proc.pagemap() else {
+        return false;
+    };
+    fault_in(pagemap, &mut proc.memory, addr, write, exec)
+}
@@ -0,0 +1,31 @@
+use core::arch::asm;
+
+pub const APIC_BASE: u32 = 0x1B;
+pub const EFER: u32 = 0xC000_0080;
+pub const STAR: u32 = 0xC000_0081;
+pub const LSTAR: u32 = 0xC000_0082;
+pub const SFMASK: u32 = 0xC000_0084;
+pub const FS_BASE: u32 = 0xC000_0100;
+pub const GS_BASE: u32 = 0xC000_0101;
+pub const KERNEL_GS_BASE: u32 = 0xC000_0102;
+
+pub const EFER_SCE: u64 = 1 << 0;
+pub const EFER_NXE: u64 = 1 << 11;
+
+pub unsafe fn read(msr: u32) -> u64 {
+    let (lo, hi): (u32, u32);
+    unsafe { asm!("rdmsr", in("ecx") msr, out("eax") lo, out("edx") hi, options(nomem, nostack)) };
+    (u64::from(hi) << 32) | u64::from(lo)
+}
+
+pub unsafe fn write(msr: u32, value: u64) {
+    unsafe {
+        asm!(
+            "wrmsr",
+            in("ecx") msr,
+            in("eax") value as u32,
+            in("edx") (value >> 32) as u32,
+            options(nomem, nostack)
+        )
+    };
+}
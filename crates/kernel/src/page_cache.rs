@@ -0,0 +1,31 @@
+use crate::frame;
+use crate::paging::{self, PAGE_SIZE};
+use crate::sync::{LockClass, SpinLock};
+use alloc::collections::BTreeMap;
+
+/// A cached page is identified by its file's backing address and page index.
+type Key = (usize, usize);
+
+/// Frames holding file contents. The cache owns one reference to each frame;
+/// every mapping of the page takes another. Initrd files are read-only, so
+/// entries are never invalidated.
+static CACHE: SpinLock<BTreeMap<Key, usize>> = SpinLock::new(LockClass::PageCache, BTreeMap::new());
+
+/// Returns the frame caching page `index` of `file`, filling it on a miss.
+/// The caller receives its own reference and must release it with `free_frame`.
+pub fn get(file: usize, index: usize, fill: impl FnOnce(&mut [u8])) -> Option<usize> {
+    let mut cache = CACHE.lock();
+    let phys = match cache.get(&(file, index)) {
+        Some(&phys) => phys,
+        None => {
+            let phys = frame::alloc_frame()?;
+            let page = unsafe {
+                core::slice::from_raw_parts_mut(paging::phys_to_virt(phys) as *mut u8, PAGE_SIZE)
+            };
+            fill(page);
+            cache.insert((file, index), phys);
+            phys
+        }
+    };
+    frame::share_frame(phys).then_some(phys)
+}
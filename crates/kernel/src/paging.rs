@@ -0,0 +1,316 @@
+use crate::frame::{self, alloc_frame, free_frame};
+use crate::{msr, smp};
+use bitflags::bitflags;
+use common::process::AddressSpace;
+use core::arch::asm;
+
+pub const PAGE_SIZE: usize = 0x1000;
+pub const USER_SPACE_END: usize = 0x0000_8000_0000_0000;
+const PTE_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+const KERNEL_HALF_START: usize = 256;
+const CR0_WRITE_PROTECT: u64 = 1 << 16;
+
+bitflags! {
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct PageFlags: u64 {
+        const PRESENT = 1 << 0;
+        const WRITABLE = 1 << 1;
+        const USER = 1 << 2;
+        const NO_CACHE = 1 << 4;
+        const HUGE = 1 << 7;
+        const COPY_ON_WRITE = 1 << 9;
+        const SHARED = 1 << 10;
+        const NO_EXECUTE = 1 << 63;
+    }
+}
+
+static mut HHDM_OFFSET: usize = 0;
+static mut KERNEL_PML4: usize = 0;
+
+pub fn init(hhdm_offset: usize) {
+    unsafe {
+        HHDM_OFFSET = hhdm_offset;
+        KERNEL_PML4 = (read_cr3() & PTE_ADDR_MASK) as usize;
+    }
+    init_cpu();
+}
+
+/// Turns on NX and write protection for supervisor writes on this CPU.
+pub fn init_cpu() {
+    unsafe {
+        msr::write(msr::EFER, msr::read(msr::EFER) | msr::EFER_NXE);
+        asm!(
+            "mov {tmp}, cr0",
+            "or {tmp}, {wp}",
+            "mov cr0, {tmp}",
+            tmp = out(reg) _,
+            wp = const CR0_WRITE_PROTECT,
+            options(nostack, preserves_flags)
+        );
+    }
+}
+
+pub fn phys_to_virt(phys: usize) -> usize {
+    phys + unsafe { HHDM_OFFSET }
+}
+
+fn read_cr3() -> u64 {
+    let cr3: u64;
+    unsafe { asm!("mov {}, cr3", out(reg) cr3, options(nostack, preserves_flags)) };
+    cr3
+}
+
+fn table(phys: usize) -> &'static mut [u64; 512] {
+    unsafe { &mut *(phys_to_virt(phys) as *mut [u64; 512]) }
+}
+
+pub fn new_address_space() -> Option<usize> {
+    let pml4 = alloc_frame()?;
+    let kernel = table(unsafe { KERNEL_PML4 });
+    table(pml4)[KERNEL_HALF_START..].copy_from_slice(&kernel[KERNEL_HALF_START..]);
+    Some(pml4)
+}
+
+pub fn owned(pml4: usize) -> AddressSpace {
+    unsafe { AddressSpace::from_raw(pml4, destroy_address_space) }
+}
+
+pub fn kernel_address_space() -> usize {
+    unsafe { KERNEL_PML4 }
+}
+
+pub fn reserve_kernel_region(va: usize) -> Option<()> {
+    let entry = &mut table(unsafe { KERNEL_PML4 })[(va >> 39) & 0x1ff];
+    if *entry & PageFlags::PRESENT.bits() == 0 {
+        *entry = alloc_frame()? as u64 | (PageFlags::PRESENT | PageFlags::WRITABLE).bits();
+    }
+    Some(())
+}
+
+pub fn map_kernel_page(va: usize, phys: usize, flags: PageFlags) -> Option<()> {
+    map_page(unsafe { KERNEL_PML4 }, va, phys, flags)
+}
+
+pub fn unmap_kernel_page(va: usize) -> Option<usize> {
+    unmap_page(unsafe { KERNEL_PML4 }, va)
+}
+
+pub fn clone_address_space(src: usize) -> Option<usize> {
+    let dst = new_address_space()?;
+    let shared = for_each_user_page(src, &mut |va, entry| {
+        if *entry & PageFlags::WRITABLE.bits() != 0 && *entry & PageFlags::SHARED.bits() == 0 {
+            *entry = (*entry & !PageFlags::WRITABLE.bits()) | PageFlags::COPY_ON_WRITE.bits();
+        }
+        let phys = (*entry & PTE_ADDR_MASK) as usize;
+        let flags = PageFlags::from_bits_retain(*entry & !PTE_ADDR_MASK);
+        if !frame::share_frame(phys) {
+            return None;
+        }
+        let mapped = map_page(dst, va, phys, flags);
+        if mapped.is_none() {
+            free_frame(phys);
+        }
+        mapped
+    });
+    // Sibling threads on other CPUs must see the pages go read-only too.
+    flush_tlb();
+    smp::flush_tlb_others();
+    if shared.is_none() {
+        destroy_address_space(dst);
+        return None;
+    }
+    Some(dst)
+}
+
+pub fn resolve_copy_on_write(pml4: usize, va: usize) -> bool {
+    let Some(entry) = walk(pml4, va, false) else {
+        return false;
+    };
+    let cow = (PageFlags::PRESENT | PageFlags::COPY_ON_WRITE).bits();
+    if *entry & cow != cow {
+        return false;
+    }
+    let old = (*entry & PTE_ADDR_MASK) as usize;
+    let flags =
+        (*entry & !PTE_ADDR_MASK & !PageFlags::COPY_ON_WRITE.bits()) | PageFlags::WRITABLE.bits();
+    if frame::refcount(old) <= 1 {
+        *entry = old as u64 | flags;
+        invlpg(va);
+    } else {
+        let Some(new) = alloc_frame() else {
+            return false;
+        };
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                phys_to_virt(old) as *const u8,
+                phys_to_virt(new) as *mut u8,
+                PAGE_SIZE,
+            )
+        };
+        *entry = new as u64 | flags;
+        invlpg(va);
+        // Sibling threads on other CPUs may still read through the old frame.
+        smp::flush_tlb_others();
+        free_frame(old);
+    }
+    true
+}
+
+pub fn destroy_address_space(pml4: usize) {
+    free_user_tables(pml4, 3);
+    free_frame(pml4);
+}
+
+fn user_entries(level: usize) -> usize {
+    if level == 3 { KERNEL_HALF_START } else { 512 }
+}
+
+fn for_each_user_page(
+    pml4: usize,
+    f: &mut impl FnMut(usize, &mut u64) -> Option<()>,
+) -> Option<()> {
+    fn visit(
+        phys: usize,
+        level: usize,
+        base: usize,
+        f: &mut impl FnMut(usize, &mut u64) -> Option<()>,
+    ) -> Option<()> {
+        for idx in 0..user_entries(level) {
+            let entry = &mut table(phys)[idx];
+            if *entry & PageFlags::PRESENT.bits() == 0 {
+                continue;
+            }
+            let va = base | (idx << (12 + 9 * level));
+            if level == 0 {
+                f(va, entry)?;
+            } else if *entry & PageFlags::HUGE.bits() == 0 {
+                visit((*entry & PTE_ADDR_MASK) as usize, level - 1, va, f)?;
+            }
+        }
+        Some(())
+    }
+    visit(pml4, 3, 0, f)
+}
+
+fn free_user_tables(phys: usize, level: usize) {
+    for idx in 0..user_entries(level) {
+        let entry = table(phys)[idx];
+        if entry & PageFlags::PRESENT.bits() == 0 || entry & PageFlags::HUGE.bits() != 0 {
+            continue;
+        }
+        let next = (entry & PTE_ADDR_MASK) as usize;
+        if level > 0 {
+            free_user_tables(next, level - 1);
+        }
+        free_frame(next);
+    }
+}
+
+fn invlpg(va: usize) {
+    unsafe { asm!("invlpg [{}]", in(reg) va, options(nostack, preserves_flags)) };
+}
+
+pub fn flush_tlb() {
+    unsafe { asm!("mov cr3, {}", in(reg) read_cr3(), options(nostack, preserves_flags)) };
+}
+
+pub fn current_address_space() -> usize {
+    (read_cr3() & PTE_ADDR_MASK) as usize
+}
+
+pub fn activate(pml4: usize) {
+    if read_cr3() & PTE_ADDR_MASK != pml4 as u64 {
+        unsafe { asm!("mov cr3, {}", in(reg) pml4 as u64, options(nostack, preserves_flags)) };
+    }
+}
+
+fn walk(pml4: usize, va: usize, create: bool) -> Option<&'static mut u64> {
+    let mut phys = pml4;
+    for level in (1..4).rev() {
+        let idx = (va >> (12 + 9 * level)) & 0x1ff;
+        let entry = &mut table(phys)[idx];
+        if *entry & PageFlags::PRESENT.bits() == 0 {
+            if !create {
+                return None;
+            }
+            let next = alloc_frame()?;
+            *entry =
+                next as u64 | (PageFlags::PRESENT | PageFlags::WRITABLE | PageFlags::USER).bits();
+        }
+        if *entry & PageFlags::HUGE.bits() != 0 {
+            return None;
+        }
+        phys = (*entry & PTE_ADDR_MASK) as usize;
+    }
+    Some(&mut table(phys)[(va >> 12) & 0x1ff])
+}
+
+pub fn map_page(pml4: usize, va: usize, phys: usize, flags: PageFlags) -> Option<()> {
+    let entry = walk(pml4, va, true)?;
+    *entry = phys as u64 | (flags | PageFlags::PRESENT).bits();
+    Some(())
+}
+
+pub fn unmap_page(pml4: usize, va: usize) -> Option<usize> {
+    let entry = walk(pml4, va, false)?;
+    if *entry & PageFlags::PRESENT.bits() == 0 {
+        return None;
+    }
+    let phys = (*entry & PTE_ADDR_MASK) as usize;
+    *entry = 0;
+    invlpg(va);
+    Some(phys)
+}
+
+pub fn translate(pml4: usize, va: usize) -> Option<usize> {
+    let entry = *walk(pml4, va, false)?;
+    if entry & PageFlags::PRESENT.bits() == 0 {
+        return None;
+    }
+    Some((entry & PTE_ADDR_MASK) as usize + (va & (PAGE_SIZE - 1)))
+}
+
+pub fn user_flags(pml4: usize, va: usize) -> Option<PageFlags> {
+    let entry = *walk(pml4, va, false)?;
+    let flags = PageFlags::from_bits_retain(entry & !PTE_ADDR_MASK);
+    flags
+        .contains(PageFlags::PRESENT | PageFlags::USER)
+        .then_some(flags)
+}
+
+pub fn set_flags(pml4: usize, va: usize, flags: PageFlags) -> Option<()> {
+    let entry = walk(pml4, va, false)?;
+    if *entry & PageFlags::PRESENT.bits() == 0 {
+        return None;
+    }
+    *entry = (*entry & PTE_ADDR_MASK) | (flags | PageFlags::PRESENT).bits();
+    invlpg(va);
+    Some(())
+}
+
+pub fn map_zeroed(pml4: usize, va: usize, len: usize, flags: PageFlags) -> Option<()> {
+    let start = va & !(PAGE_SIZE - 1);
+    let end = va.checked_add(len)?.checked_add(PAGE_SIZE - 1)? & !(PAGE_SIZE - 1);
+    for page in (start..end).step_by(PAGE_SIZE) {
+        if translate(pml4, page).is_none() {
+            let frame = alloc_frame()?;
+            if map_page(pml4, page, frame, flags).is_none() {
+                free_frame(frame);
+                return None;
+            }
+        }
+    }
+    Some(())
+}
+
+pub fn copy_into(pml4: usize, va: usize, bytes: &[u8]) -> Option<()> {
+    let mut done = 0;
+    while done < bytes.len() {
+        let cur = va.checked_add(done)?;
+        let n = (PAGE_SIZE - (cur & (PAGE_SIZE - 1))).min(bytes.len() - done);
+        let dst = phys_to_virt(translate(pml4, cur)?) as *mut u8;
+        unsafe { core::ptr::copy_nonoverlapping(bytes.as_ptr().add(done), dst, n) };
+        done += n;
+    }
+    Some(())
+}
@@ -0,0 +1,106 @@
+use crate::msr;
+#[cfg(debug_assertions)]
+use crate::sync::LockClass;
+#[cfg(debug_assertions)]
+use common::sync::HeldLocks;
+use core::arch::asm;
+#[cfg(debug_assertions)]
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+
+pub const MAX_CPUS: usize = 16;
+#[cfg(debug_assertions)]
+const MAX_HELD_LOCKS: usize = 8;
+
+/// State each CPU keeps to itself, reached through `gs` in the kernel. Entry
+/// code addresses the first fields by offset.
+#[repr(C)]
+pub struct PerCpu {
+    self_ptr: AtomicUsize,
+    /// Top of the running thread's kernel stack, loaded by `syscall`.
+    pub syscall_rsp: AtomicU64,
+    /// The user `rsp` while `syscall` switches stacks.
+    pub user_rsp: AtomicU64,
+    /// The running thread's TLS pointer, written back after every fs reload.
+    pub user_fs_base: AtomicU64,
+    index: AtomicUsize,
+    lapic_id: AtomicU32,
+    online: AtomicBool,
+    /// Tid of the thread on this CPU, or 0 while it idles.
+    pub running: AtomicU64,
+    /// Saved `rsp` of this CPU's idle loop.
+    pub idle_rsp: AtomicUsize,
+    /// When the running thread's slice ends, on the `timer::now_ns` clock.
+    pub slice_end: AtomicU64,
+    #[cfg(debug_assertions)]
+    held_locks: UnsafeCell<HeldLocks<LockClass, MAX_HELD_LOCKS>>,
+}
+
+// `held_locks` is only touched by its own CPU, with interrupts off.
+unsafe impl Sync for PerCpu {}
+
+impl PerCpu {
+    const fn new() -> Self {
+        Self {
+            self_ptr: AtomicUsize::new(0),
+            syscall_rsp: AtomicU64::new(0),
+            user_rsp: AtomicU64::new(0),
+            user_fs_base: AtomicU64::new(0),
+            index: AtomicUsize::new(0),
+            lapic_id: AtomicU32::new(0),
+            online: AtomicBool::new(false),
+            running: AtomicU64::new(0),
+            idle_rsp: AtomicUsize::new(0),
+            slice_end: AtomicU64::new(0),
+            #[cfg(debug_assertions)]
+            held_locks: UnsafeCell::new(HeldLocks::new()),
+        }
+    }
+
+    pub fn index(&self) -> usize {
+        self.index.load(Ordering::Relaxed)
+    }
+
+    pub fn lapic_id(&self) -> u32 {
+        self.lapic_id.load(Ordering::Relaxed)
+    }
+
+    pub fn is_online(&self) -> bool {
+        self.online.load(Ordering::Acquire)
+    }
+
+    /// Locks this CPU holds, for the lock-order checker.
+    #[cfg(debug_assertions)]
+    pub fn held_locks(&self) -> *mut HeldLocks<LockClass, MAX_HELD_LOCKS> {
+        self.held_locks.get()
+    }
+
+    pub fn set_online(&self, lapic_id: u32) {
+        self.lapic_id.store(lapic_id, Ordering::Relaxed);
+        self.online.store(true, Ordering::Release);
+    }
+}
+
+static CPUS: [PerCpu; MAX_CPUS] = [const { PerCpu::new() }; MAX_CPUS];
+
+/// Points this CPU's `gs` at slot `index`; user `gs` starts out as 0.
+pub fn init(index: usize) {
+    let cpu = &CPUS[index];
+    cpu.self_ptr
+        .store(cpu as *const _ as usize, Ordering::Relaxed);
+    cpu.index.store(index, Ordering::Relaxed);
+    unsafe {
+        msr::write(msr::GS_BASE, cpu as *const _ as u64);
+        msr::write(msr::KERNEL_GS_BASE, 0);
+    }
+}
+
+pub fn current() -> &'static PerCpu {
+    let ptr: usize;
+    unsafe { asm!("mov {}, gs:[0]", out(reg) ptr, options(nostack, readonly, preserves_flags)) };
+    unsafe { &*(ptr as *const PerCpu) }
+}
+
+pub fn online() -> impl Iterator<Item = &'static PerCpu> {
+    CPUS.iter().filter(|cpu| cpu.is_online())
+}
@@ -0,0 +1,25 @@
+use crate::port::outb;
+
+const IRQ_BASE: u8 = 0x20;
+
+const PIC1_CMD: u16 = 0x20;
+const PIC1_DATA: u16 = 0x21;
+const PIC2_CMD: u16 = 0xA0;
+const PIC2_DATA: u16 = 0xA1;
+
+/// Remaps the legacy PICs off the exception vectors and masks every line;
+/// interrupts come through the local APICs instead.
+pub fn disable() {
+    unsafe {
+        outb(PIC1_CMD, 0x11);
+        outb(PIC2_CMD, 0x11);
+        outb(PIC1_DATA, IRQ_BASE);
+        outb(PIC2_DATA, IRQ_BASE + 8);
+        outb(PIC1_DATA, 4);
+        outb(PIC2_DATA, 2);
+        outb(PIC1_DATA, 0x01);
+        outb(PIC2_DATA, 0x01);
+        outb(PIC1_DATA, 0xff);
+        outb(PIC2_DATA, 0xff);
+    }
+}
@@ -0,0 +1,11 @@
+use core::arch::asm;
+
+pub unsafe fn outb(port: u16, val: u8) {
+    unsafe { asm!("out dx, al", in("dx") port, in("al") val, options(nostack, nomem)) }
+}
+
+pub unsafe fn inb(port: u16) -> u8 {
+    let val: u8;
+    unsafe { asm!("in al, dx", out("al") val, in("dx") port, options(nostack, nomem)) };
+    val
+}
@@ -0,0 +1,60 @@
+use core::arch::asm;
+use core::arch::x86_64::{__cpuid, _rdtsc};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+const CPUID_RDRAND: u32 = 1 << 30;
+const RDRAND_RETRIES: usize = 10;
+
+static HAS_RDRAND: AtomicBool = AtomicBool::new(false);
+static FALLBACK: AtomicU64 = AtomicU64::new(0x9e37_79b9_7f4a_7c15);
+
+pub fn init() -> bool {
+    let available = __cpuid(1).ecx & CPUID_RDRAND != 0;
+    HAS_RDRAND.store(available, Ordering::Relaxed);
+    available
+}
+
+fn rdrand() -> Option<u64> {
+    for _ in 0..RDRAND_RETRIES {
+        let (value, ok): (u64, u8);
+        unsafe {
+            asm!(
+                "rdrand {value}",
+                "setc {ok}",
+                value = out(reg) value,
+                ok = out(reg_byte) ok,
+                options(nomem, nostack)
+            );
+        }
+        if ok != 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// Without RDRAND, mixes the TSC into a splitmix64 stream. Not
+/// cryptographically strong, but distinct across boots and calls.
+fn fallback() -> u64 {
+    let step = 0x9e37_79b9_7f4a_7c15 ^ unsafe { _rdtsc() };
+    let mut z = FALLBACK
+        .fetch_add(step, Ordering::Relaxed)
+        .wrapping_add(step);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+pub fn next_u64() -> u64 {
+    HAS_RDRAND
+        .load(Ordering::Relaxed)
+        .then(rdrand)
+        .flatten()
+        .unwrap_or_else(fallback)
+}
+
+pub fn fill(buf: &mut [u8]) {
+    for chunk in buf.chunks_mut(8) {
+        chunk.copy_from_slice(&next_u64().to_le_bytes()[..chunk.len()]);
+    }
+}
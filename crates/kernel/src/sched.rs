@@ -0,0 +1,246 @@
+use crate::interrupts::{self, TrapFrame};
+use crate::percpu;
+use crate::port::outb;
+use crate::serial::serial_has_data;
+use crate::sync::{LockClass, SpinLock, SpinLockGuard};
+use crate::tty::TTY;
+use crate::{fpu, gdt, kstack, paging, smp, timer};
+use common::process::{BlockReason, ProcessState, ProcessTable};
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+static PROCESSES: SpinLock<ProcessTable> = SpinLock::new(
+    LockClass::Processes,
+    ProcessTable::with_stack_allocator(kstack::alloc).with_fpu_state(fpu::initial_state),
+);
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+
+/// Locks the process table with this CPU's thread as its current one.
+pub fn processes() -> SpinLockGuard<'static, ProcessTable> {
+    let mut table = PROCESSES.lock();
+    table.set_current(running());
+    table
+}
+
+/// `processes`, unless the table is already locked, perhaps by the kernel
+/// code that just faulted.
+pub fn try_processes() -> Option<SpinLockGuard<'static, ProcessTable>> {
+    let mut table = PROCESSES.try_lock()?;
+    table.set_current(running());
+    Some(table)
+}
+
+/// Tid of the thread on this CPU.
+pub fn running() -> Option<u64> {
+    match percpu::current().running.load(Ordering::Relaxed) {
+        0 => None,
+        tid => Some(tid),
+    }
+}
+
+pub fn save_current(frame: &TrapFrame) {
+    let Some(tid) = running() else {
+        return;
+    };
+    if let Some(thread) = processes().thread_mut(tid) {
+        thread.context = frame.save();
+    }
+}
+
+pub fn load_current(frame: &mut TrapFrame) {
+    let Some(tid) = running() else {
+        return;
+    };
+    if let Some(thread) = processes().thread(tid) {
+        frame.load(&thread.context);
+    }
+}
+
+pub fn complete_syscall(ret: i64) {
+    let Some(tid) = running() else {
+        return;
+    };
+    if let Some(thread) = processes().thread_mut(tid) {
+        thread.context.rax = ret as usize;
+    }
+}
+
+/// Sleepers are woken by `wake_sleepers` instead, so none is left behind
+/// with its deadline past.
+fn ready(reason: BlockReason) -> bool {
+    match reason {
+        BlockReason::Stdin => serial_has_data(),
+        BlockReason::WaitChild(_)
+        | BlockReason::Futex(_)
+        | BlockReason::Sleep(_)
+        | BlockReason::Starting => false,
+    }
+}
+
+/// When this CPU's timer must next fire: at the end of the slice if it runs
+/// a thread, at the first sleeper's deadline, and every slice while a reader
+/// waits on the serial port, which has no interrupt. Never, if none apply.
+fn next_wakeup(table: &ProcessTable, slice_end: Option<u64>, now: u64) -> Option<u64> {
+    let poll = table
+        .is_blocked_on(BlockReason::Stdin)
+        .then_some(now + timer::SLICE_NS);
+    [slice_end, poll, table.earliest_deadline()]
+        .into_iter()
+        .flatten()
+        .min()
+}
+
+/// Ends the whole current process; returns its pid, or None if another
+/// thread's `exit_group` got there first.
+pub fn exit_current(code: i32) -> Option<u64> {
+    if !stop_other_threads() {
+        return None;
+    }
+    let mut table = processes();
+    let pid = table.exit_current(code).ok()?;
+    release_address_space(table, pid);
+    Some(pid)
+}
+
+/// Kills the other threads of the running process and waits until no CPU is
+/// left on one of them. False if this thread was killed first.
+pub fn stop_other_threads() -> bool {
+    let mut table = processes();
+    if !table.kill_other_threads().unwrap_or(false) {
+        return false;
+    }
+    smp::reschedule(table.sibling_cpus());
+    drop(wait_for_siblings(table));
+    true
+}
+
+/// Spins, with the table unlocked in between, until no other CPU is on a
+/// thread of the running process, exited or not.
+fn wait_for_siblings(
+    mut table: SpinLockGuard<'static, ProcessTable>,
+) -> SpinLockGuard<'static, ProcessTable> {
+    while table.sibling_cpus() != 0 {
+        drop(table);
+        core::hint::spin_loop();
+        table = processes();
+    }
+    table
+}
+
+/// Ends the running thread; returns the pid if that ended the process too.
+pub fn exit_thread(code: i32) -> Option<u64> {
+    let mut table = processes();
+    let pid = table.exit_thread(code).ok()??;
+    // Threads that exited just before may still be leaving their CPUs.
+    release_address_space(wait_for_siblings(table), pid);
+    Some(pid)
+}
+
+fn release_address_space(mut table: SpinLockGuard<'static, ProcessTable>, pid: u64) {
+    let address_space = table.get_mut(pid).and_then(|p| p.address_space.take());
+    drop(table);
+    // Leave the dying page tables before freeing them.
+    paging::activate(paging::kernel_address_space());
+    drop(address_space);
+    // The parent may be waiting to run on an idle CPU.
+    smp::kick_idle();
+}
+
+pub fn block_current(reason: BlockReason) {
+    if let Some(tid) = running() {
+        let mut table = processes();
+        if let Some(thread) = table.thread_mut(tid) {
+            thread.state = ProcessState::Blocked(reason);
+        }
+    }
+    schedule(false);
+}
+
+pub fn schedule(preempt: bool) {
+    let cpu = percpu::current();
+    let mut table = processes();
+    let now = timer::now_ns();
+    if table.wake_sleepers(now) > 0 {
+        smp::kick_idle();
+    }
+    let mut slice_end = cpu.slice_end.load(Ordering::Relaxed);
+    let keep = !preempt
+        && now < slice_end
+        && table
+            .current_thread()
+            .is_some_and(|t| t.state == ProcessState::Runnable);
+    let next = if keep {
+        table.current_tid()
+    } else {
+        table.schedule_next(cpu.index(), ready)
+    };
+    let prev = running();
+    if prev != next || now >= slice_end {
+        slice_end = now + timer::SLICE_NS;
+        cpu.slice_end.store(slice_end, Ordering::Relaxed);
+    }
+    timer::arm(next_wakeup(&table, next.map(|_| slice_end), now));
+    if prev == next {
+        return;
+    }
+
+    if let Some(thread) = prev.and_then(|tid| table.thread_mut(tid)) {
+        fpu::save(&mut thread.fpu);
+    }
+    let new_rsp = match next.and_then(|tid| table.thread_mut(tid)) {
+        Some(thread) => {
+            fpu::restore(&thread.fpu);
+            if thread.kernel_rsp == 0 {
+                thread.kernel_rsp = interrupts::initial_kernel_rsp(thread.kernel_stack.top());
+            }
+            gdt::set_kernel_stack(thread.kernel_stack.top());
+            interrupts::set_syscall_stack(thread.kernel_stack.top());
+            let (pid, rsp) = (thread.pid, thread.kernel_rsp);
+            // Threads of one process share page tables; skip the CR3 reload.
+            let same_space = prev
+                .and_then(|tid| table.thread(tid))
+                .is_some_and(|t| t.pid == pid);
+            if !same_space && let Some(pagemap) = table.get(pid).and_then(|p| p.pagemap()) {
+                paging::activate(pagemap);
+            }
+            rsp
+        }
+        None => {
+            paging::activate(paging::kernel_address_space());
+            cpu.idle_rsp.load(Ordering::Relaxed)
+        }
+    };
+    cpu.running.store(next.unwrap_or(0), Ordering::Relaxed);
+
+    let old_rsp = match prev.and_then(|tid| table.thread_mut(tid)) {
+        Some(t) => &raw mut t.kernel_rsp,
+        None => cpu.idle_rsp.as_ptr(),
+    };
+    // The table stays locked across the switch; whoever resumes unlocks it.
+    core::mem::forget(table);
+    unsafe { interrupts::switch_context(old_rsp, new_rsp) };
+    finish_switch();
+}
+
+pub fn finish_switch() {
+    unsafe { PROCESSES.force_unlock() };
+    processes().reap_orphans();
+}
+
+/// This CPU's idle loop; threads switch back here when nothing is runnable.
+pub fn start() -> ! {
+    loop {
+        schedule(false);
+        if !processes().has_live() && !SHUTTING_DOWN.swap(true, Ordering::Relaxed) {
+            let uptime_ms = timer::now_ns() / 1_000_000;
+            let _ = writeln!(
+                TTY.lock(),
+                "[kernel] no processes left, shutting down after {}.{:03} s",
+                uptime_ms / 1000,
+                uptime_ms % 1000
+            );
+            unsafe { outb(0xF4, 0x10) };
+        }
+        interrupts::idle_until_interrupt();
+    }
+}
@@ -1,4 +1,5 @@
-use core::arch::asm;
+use crate::port::{inb, outb};
+use core::fmt::{self, Write};
 
 const COM1: u16 = 0x3F8;
 
@@ -15,34 +16,28 @@ pub fn serial_init() {
 }
 
 pub fn serial_write_byte(byte: u8) {
-    while unsafe { inb(COM1 + 5) } & 0x20 == 0 {}
+    while (unsafe { inb(COM1 + 5) } & 0x20) == 0 {}
     unsafe { outb(COM1, byte) }
 }
 
+/// Writes straight to the port, for when the TTY lock may be held.
+pub struct SerialWriter;
+
+impl Write for SerialWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        s.bytes().for_each(serial_write_byte);
+        Ok(())
+    }
+}
+
 pub fn serial_try_read_byte() -> Option<u8> {
-    let ready = unsafe { inb(COM1 + 5) } & 1;
-    if ready != 0 {
+    if serial_has_data() {
         Some(unsafe { inb(COM1) })
     } else {
         None
     }
 }
 
-pub fn serial_read_byte_blocking() -> u8 {
-    loop {
-        if let Some(byte) = serial_try_read_byte() {
-            return byte;
-        }
-        core::hint::spin_loop();
-    }
-}
-
-unsafe fn outb(port: u16, val: u8) {
-    unsafe { asm!("out dx, al", in("dx") port, in("al") val, options(nostack, nomem)) }
-}
-
-unsafe fn inb(port: u16) -> u8 {
-    let val: u8;
-    unsafe { asm!("in al, dx", out("al") val, in("dx") port, options(nostack, nomem)) };
-    val
+pub fn serial_has_data() -> bool {
+    (unsafe { inb(COM1 + 5) } & 1) != 0
 }
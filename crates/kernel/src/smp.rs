@@ -0,0 +1,124 @@
+use crate::percpu::{self, MAX_CPUS};
+use crate::sync::{LockClass, SpinLock};
+use crate::tty::TTY;
+use crate::{fpu, gdt, interrupts, lapic, paging, sched, uaccess};
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use limine::mp::Cpu;
+use limine::response::MpResponse;
+
+pub const RESCHEDULE_VECTOR: u8 = 0xF0;
+
+/// Set once the boot CPU has finished setting up and starts scheduling.
+static RELEASED: AtomicBool = AtomicBool::new(false);
+static SHOOTDOWN: SpinLock<()> = SpinLock::new(LockClass::Shootdown, ());
+/// CPUs yet to flush for the shootdown in progress.
+static SHOOTDOWN_PENDING: AtomicUsize = AtomicUsize::new(0);
+
+/// Starts every application processor Limine found and waits for them to come
+/// online; returns how many CPUs run, the boot CPU included.
+pub fn start(mp: Option<&MpResponse>) -> usize {
+    let bsp = mp.map_or_else(lapic::id, MpResponse::bsp_lapic_id);
+    online(bsp);
+    let Some(mp) = mp else {
+        return 1;
+    };
+
+    let mut started = 1;
+    for cpu in mp.cpus().iter().filter(|cpu| cpu.lapic_id != bsp) {
+        if started == MAX_CPUS {
+            let _ = writeln!(
+                TTY.lock(),
+                "[kernel] only {MAX_CPUS} CPUs supported, leaving the rest parked"
+            );
+            break;
+        }
+        cpu.extra.store(started as u64, Ordering::Relaxed);
+        cpu.goto_address.write(ap_entry);
+        started += 1;
+    }
+    while percpu::online().count() < started {
+        core::hint::spin_loop();
+    }
+    started
+}
+
+fn online(lapic_id: u32) {
+    let cpu = percpu::current();
+    cpu.set_online(lapic_id);
+    let _ = writeln!(
+        TTY.lock(),
+        "[kernel] cpu {} online (lapic id {})",
+        cpu.index(),
+        lapic_id
+    );
+}
+
+unsafe extern "C" fn ap_entry(cpu: &Cpu) -> ! {
+    let index = cpu.extra.load(Ordering::Relaxed) as usize;
+    percpu::init(index);
+    gdt::install_gdt(index);
+    interrupts::load_idt();
+    interrupts::install_syscall();
+    paging::init_cpu();
+    fpu::init_cpu();
+    paging::activate(paging::kernel_address_space());
+    uaccess::init();
+    lapic::enable();
+    online(cpu.lapic_id);
+    while !RELEASED.load(Ordering::Acquire) {
+        core::hint::spin_loop();
+    }
+    sched::start()
+}
+
+/// Lets the application processors into the scheduler.
+pub fn release() {
+    RELEASED.store(true, Ordering::Release);
+}
+
+/// Makes every other CPU drop its cached user and kernel-stack translations,
+/// and waits until they have. The request goes out as an NMI so CPUs spinning
+/// on a lock with interrupts off still answer.
+pub fn flush_tlb_others() {
+    let me = percpu::current().index();
+    let _guard = SHOOTDOWN.lock();
+    let targets = percpu::online().filter(|cpu| cpu.index() != me).count();
+    if targets == 0 {
+        return;
+    }
+    SHOOTDOWN_PENDING.store(targets, Ordering::Release);
+    for cpu in percpu::online().filter(|cpu| cpu.index() != me) {
+        lapic::send_nmi(cpu.lapic_id());
+    }
+    while SHOOTDOWN_PENDING.load(Ordering::Acquire) != 0 {
+        core::hint::spin_loop();
+    }
+}
+
+/// Answers a shootdown; false if the NMI was something else. The NMI may
+/// have interrupted a lock holder, so this must not take one.
+pub fn handle_nmi() -> bool {
+    if SHOOTDOWN_PENDING.load(Ordering::Acquire) == 0 {
+        return false;
+    }
+    paging::flush_tlb();
+    SHOOTDOWN_PENDING.fetch_sub(1, Ordering::AcqRel);
+    true
+}
+
+/// Interrupts the CPUs in `mask` so they reschedule.
+pub fn reschedule(mask: u64) {
+    for cpu in percpu::online().filter(|cpu| mask & 1 << cpu.index() != 0) {
+        lapic::send_ipi(cpu.lapic_id(), RESCHEDULE_VECTOR);
+    }
+}
+
+/// Wakes idle CPUs to look for threads that just became runnable.
+pub fn kick_idle() {
+    let me = percpu::current().index();
+    let idle = percpu::online()
+        .filter(|cpu| cpu.index() != me && cpu.running.load(Ordering::Relaxed) == 0)
+        .fold(0, |mask, cpu| mask | 1 << cpu.index());
+    reschedule(idle);
+}
@@ -0,0 +1,185 @@
+//! Kernel locks. `SpinLock` is a ticket lock that keeps interrupts off on its
+//! CPU while held, so an interrupt handler never spins on a lock the code it
+//! interrupted holds.
+//!
+//! Lock hierarchy, outermost first. A lock may only be taken while every lock
+//! held ranks above it; debug builds check each acquisition and panic naming
+//! both locks on an inversion.
+//!
+//! 1. `PROCESSES` (sched): processes, threads and user page tables.
+//! 2. `VFS`: the open-file table.
+//! 3. `CACHE` (page_cache): frames caching file pages.
+//! 4. `SLOTS` (kstack): free kernel stack slots.
+//! 5. `SHOOTDOWN` (smp): one TLB shootdown at a time.
+//! 6. `TTY`: console output; anything above may log.
+//! 7. `HEAP`: the kernel heap, which grows from `FRAMES`.
+//! 8. `FRAMES`: the physical frame allocator.
+
+use common::sync::{TicketGuard, TicketLock};
+use core::arch::asm;
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+
+const RFLAGS_IF: u64 = 1 << 9;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LockClass {
+    Processes,
+    Vfs,
+    PageCache,
+    KernelStacks,
+    Shootdown,
+    Tty,
+    Heap,
+    Frames,
+}
+
+impl LockClass {
+    #[cfg(debug_assertions)]
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Processes => "PROCESSES",
+            Self::Vfs => "VFS",
+            Self::PageCache => "CACHE",
+            Self::KernelStacks => "SLOTS",
+            Self::Shootdown => "SHOOTDOWN",
+            Self::Tty => "TTY",
+            Self::Heap => "HEAP",
+            Self::Frames => "FRAMES",
+        }
+    }
+}
+
+pub struct SpinLock<T> {
+    class: LockClass,
+    inner: TicketLock<T>,
+}
+
+pub struct SpinLockGuard<'a, T> {
+    class: LockClass,
+    guard: ManuallyDrop<TicketGuard<'a, T>>,
+    interrupts: bool,
+}
+
+/// Turns interrupts off and returns whether they were on.
+fn disable_interrupts() -> bool {
+    let rflags: u64;
+    unsafe { asm!("pushfq", "pop {}", "cli", out(reg) rflags, options(nomem)) };
+    rflags & RFLAGS_IF != 0
+}
+
+fn restore_interrupts(enabled: bool) {
+    if enabled {
+        unsafe { asm!("sti", options(nomem, nostack)) };
+    }
+}
+
+impl<T> SpinLock<T> {
+    pub const fn new(class: LockClass, value: T) -> Self {
+        Self {
+            class,
+            inner: TicketLock::new(value),
+        }
+    }
+
+    pub fn lock(&self) -> SpinLockGuard<'_, T> {
+        let interrupts = disable_interrupts();
+        lockdep::acquire(self.class);
+        SpinLockGuard {
+            class: self.class,
+            guard: ManuallyDrop::new(self.inner.lock()),
+            interrupts,
+        }
+    }
+
+    /// Takes the lock if it is free. Never deadlocks, so the order check is
+    /// skipped; the panic handler uses this for the TTY.
+    pub fn try_lock(&self) -> Option<SpinLockGuard<'_, T>> {
+        let interrupts = disable_interrupts();
+        let Some(guard) = self.inner.try_lock() else {
+            restore_interrupts(interrupts);
+            return None;
+        };
+        lockdep::record(self.class);
+        Some(SpinLockGuard {
+            class: self.class,
+            guard: ManuallyDrop::new(guard),
+            interrupts,
+        })
+    }
+
+    /// Releases a lock whose guard was forgotten. Interrupts stay as they
+    /// are, so the lock must have been taken with them off.
+    ///
+    /// # Safety
+    /// The lock must be held on this CPU through a forgotten guard.
+    pub unsafe fn force_unlock(&self) {
+        unsafe { self.inner.force_unlock() };
+        lockdep::release(self.class);
+    }
+}
+
+impl<T> Deref for SpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for SpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T> Drop for SpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        lockdep::release(self.class);
+        restore_interrupts(self.interrupts);
+    }
+}
+
+/// Per-CPU tracking of held locks against the hierarchy above.
+#[cfg(debug_assertions)]
+mod lockdep {
+    use super::LockClass;
+    use crate::percpu;
+    use common::sync::LockOrderError;
+
+    pub fn acquire(class: LockClass) {
+        let held = unsafe { &mut *percpu::current().held_locks() };
+        match held.acquire(class) {
+            Ok(()) => {}
+            Err(LockOrderError::Inversion { held }) => panic!(
+                "lock order inversion: taking {} while holding {}",
+                class.name(),
+                held.name()
+            ),
+            Err(LockOrderError::TooDeep) => {
+                panic!("too many locks held to take {}", class.name())
+            }
+        }
+    }
+
+    /// A lock too deep to record is simply not checked later.
+    pub fn record(class: LockClass) {
+        let _ = unsafe { &mut *percpu::current().held_locks() }.record(class);
+    }
+
+    pub fn release(class: LockClass) {
+        unsafe { &mut *percpu::current().held_locks() }.release(class);
+    }
+}
+
+#[cfg(not(debug_assertions))]
+mod lockdep {
+    use super::LockClass;
+
+    pub fn acquire(_: LockClass) {}
+
+    pub fn record(_: LockClass) {}
+
+    pub fn release(_: LockClass) {}
+}
@@ -0,0 +1,119 @@
+//! Timekeeping. The TSC, calibrated against the HPET (or the PIT when ACPI
+//! lists none), is the clocksource for a monotonic nanosecond clock; each
+//! CPU's local APIC timer runs one-shot, armed for whichever comes first of
+//! the end of the running thread's slice and the next sleeper's deadline.
+
+use crate::hpet;
+use crate::lapic;
+use crate::port::{inb, outb};
+use common::time::{NANOS_PER_SEC, Scale};
+use core::arch::x86_64::{__cpuid, _rdtsc};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+pub const TIMER_VECTOR: u8 = 0x20;
+/// How long a thread runs before another runnable one gets the CPU.
+pub const SLICE_NS: u64 = 10_000_000;
+/// How long calibration measures against the reference clock.
+const CALIBRATION_NS: u64 = 10_000_000;
+
+const PIT_FREQUENCY: u64 = 1_193_182;
+const PIT_CHANNEL2: u16 = 0x42;
+const PIT_COMMAND: u16 = 0x43;
+const PIT_GATE: u16 = 0x61;
+const GATE_ENABLE: u8 = 1 << 0;
+const SPEAKER_ENABLE: u8 = 1 << 1;
+const GATE_OUTPUT: u8 = 1 << 5;
+
+const CPUID_POWER_LEAF: u32 = 0x8000_0007;
+const CPUID_INVARIANT_TSC: u32 = 1 << 8;
+
+static mut BOOT_TSC: u64 = 0;
+static mut TSC_TO_NS: Scale = Scale::ZERO;
+static mut NS_TO_LAPIC: Scale = Scale::ZERO;
+/// The latest time handed out, so CPUs whose TSCs disagree slightly still
+/// never see the clock go backwards.
+static LAST_NS: AtomicU64 = AtomicU64::new(0);
+
+pub struct Calibration {
+    pub reference: &'static str,
+    pub tsc_hz: u64,
+    pub lapic_hz: u64,
+    pub invariant_tsc: bool,
+}
+
+/// Busy-waits about `ns` (at most 54 ms) on PIT channel 2, which raises its
+/// output when the one-shot count runs out; returns the exact time.
+fn pit_wait(ns: u64) -> u64 {
+    let count = (PIT_FREQUENCY * ns / NANOS_PER_SEC).min(u64::from(u16::MAX)) as u16;
+    unsafe {
+        let gate = inb(PIT_GATE) & !(GATE_ENABLE | SPEAKER_ENABLE);
+        outb(PIT_GATE, gate);
+        outb(PIT_COMMAND, 0xB0);
+        outb(PIT_CHANNEL2, count as u8);
+        outb(PIT_CHANNEL2, (count >> 8) as u8);
+        outb(PIT_GATE, gate | GATE_ENABLE);
+        while inb(PIT_GATE) & GATE_OUTPUT == 0 {
+            core::hint::spin_loop();
+        }
+        outb(PIT_GATE, gate);
+    }
+    u64::from(count) * NANOS_PER_SEC / PIT_FREQUENCY
+}
+
+fn per_second(ticks: u64, ns: u64) -> u64 {
+    (u128::from(ticks) * u128::from(NANOS_PER_SEC) / u128::from(ns)) as u64
+}
+
+/// Measures the TSC and the local APIC timer against the HPET if `hpet` is
+/// set up, the PIT otherwise, and starts the clock at zero.
+pub fn init(hpet: bool) -> Option<Calibration> {
+    let start = unsafe { _rdtsc() };
+    let mut elapsed_ns = 0;
+    let mut tsc_ticks = 0;
+    let lapic_ticks = lapic::measure_timer(|| {
+        let before = unsafe { _rdtsc() };
+        elapsed_ns = if hpet {
+            hpet::wait(CALIBRATION_NS)
+        } else {
+            pit_wait(CALIBRATION_NS)
+        };
+        tsc_ticks = unsafe { _rdtsc() } - before;
+    });
+    unsafe {
+        TSC_TO_NS = Scale::new(elapsed_ns, tsc_ticks)?;
+        NS_TO_LAPIC = Scale::new(u64::from(lapic_ticks), elapsed_ns)?;
+        BOOT_TSC = start;
+    }
+    Some(Calibration {
+        reference: if hpet { "HPET" } else { "PIT" },
+        tsc_hz: per_second(tsc_ticks, elapsed_ns),
+        lapic_hz: per_second(u64::from(lapic_ticks), elapsed_ns),
+        invariant_tsc: __cpuid(CPUID_POWER_LEAF).edx & CPUID_INVARIANT_TSC != 0,
+    })
+}
+
+/// Nanoseconds since the clock started; never goes backwards.
+pub fn now_ns() -> u64 {
+    let ticks = unsafe { _rdtsc().wrapping_sub(BOOT_TSC) };
+    let ns = unsafe { TSC_TO_NS }.apply(ticks);
+    LAST_NS.fetch_max(ns, Ordering::Relaxed).max(ns)
+}
+
+/// Makes this CPU's timer fire at `deadline` on the `now_ns` clock, or
+/// stops it. A deadline already past fires right away.
+pub fn arm(deadline: Option<u64>) {
+    let Some(deadline) = deadline else {
+        lapic::stop_timer();
+        return;
+    };
+    let ticks = unsafe { NS_TO_LAPIC }.apply(deadline.saturating_sub(now_ns()));
+    // A count too large just fires early, and the scheduler re-arms.
+    lapic::start_oneshot_timer(TIMER_VECTOR, ticks.min(u64::from(u32::MAX)) as u32);
+}
+
+/// Answers the timer and arms a full slice, so a CPU that does not
+/// reschedule now is still preempted later; `sched::schedule` narrows it.
+pub fn tick() {
+    lapic::end_of_interrupt();
+    arm(Some(now_ns() + SLICE_NS));
+}
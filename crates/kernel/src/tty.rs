@@ -1,6 +1,6 @@
 use crate::serial::serial_write_byte;
+use crate::sync::{LockClass, SpinLock};
 use core::fmt::{self, Write};
-use spin::Mutex;
 
 pub struct Tty {
     fb: Option<limine::framebuffer::Framebuffer<'static>>,
@@ -56,7 +56,7 @@ impl Write for Tty {
     }
 }
 
-pub static TTY: Mutex<Tty> = Mutex::new(Tty::new());
+pub static TTY: SpinLock<Tty> = SpinLock::new(LockClass::Tty, Tty::new());
 
 pub fn set_framebuffer(framebuffer: limine::framebuffer::Framebuffer<'static>) {
     TTY.lock().fb = Some(framebuffer);
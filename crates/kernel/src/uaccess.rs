@@ -0,0 +1,141 @@
+use crate::memory;
+use crate::paging::{self, PAGE_SIZE, PageFlags, USER_SPACE_END};
+use alloc::vec::Vec;
+use core::arch::{asm, global_asm, x86_64::__cpuid_count};
+
+pub const EFAULT: i64 = -14;
+const E2BIG: i64 = -7;
+const CR4_SMAP: u64 = 1 << 21;
+const CPUID_SMAP: u32 = 1 << 20;
+const FIXUP_COUNT: usize = 1;
+
+static mut SMAP_ENABLED: u8 = 0;
+
+// user_copy(dst, src, len) returns the number of bytes left uncopied. A fault
+// inside `rep movsb` resumes at the fixup with rcx holding the remainder.
+global_asm!(
+    r#"
+.global user_copy
+user_copy:
+    cmp byte ptr [rip + {smap}], 0
+    je 1f
+    stac
+1:
+    mov rcx, rdx
+user_copy_insn:
+    rep movsb
+user_copy_done:
+    cmp byte ptr [rip + {smap}], 0
+    je 2f
+    clac
+2:
+    mov rax, rcx
+    ret
+
+.pushsection .data.rel.ro, "aw"
+.balign 8
+.global exception_fixups
+exception_fixups:
+    .quad user_copy_insn, user_copy_done
+.popsection
+"#,
+    smap = sym SMAP_ENABLED,
+);
+
+unsafe extern "C" {
+    fn user_copy(dst: *mut u8, src: *const u8, len: usize) -> usize;
+    static exception_fixups: [[u64; 2]; FIXUP_COUNT];
+}
+
+pub fn init() -> bool {
+    if __cpuid_count(7, 0).ebx & CPUID_SMAP == 0 {
+        return false;
+    }
+    unsafe {
+        asm!(
+            "mov {tmp}, cr4",
+            "or {tmp}, {smap}",
+            "mov cr4, {tmp}",
+            tmp = out(reg) _,
+            smap = const CR4_SMAP,
+            options(nostack, preserves_flags)
+        );
+        SMAP_ENABLED = 1;
+    }
+    true
+}
+
+pub fn fixup(rip: u64) -> Option<u64> {
+    let table = unsafe { &exception_fixups };
+    table
+        .iter()
+        .find(|&&[fault, _]| fault == rip)
+        .map(|&[_, resume]| resume)
+}
+
+fn range_ok(addr: usize, len: usize, write: bool) -> bool {
+    if len == 0 {
+        return true;
+    }
+    let Some(end) = addr.checked_add(len).filter(|&end| end <= USER_SPACE_END) else {
+        return false;
+    };
+    let pml4 = paging::current_address_space();
+    (addr & !(PAGE_SIZE - 1)..end)
+        .step_by(PAGE_SIZE)
+        .all(|page| {
+            if paging::user_flags(pml4, page).is_none() {
+                memory::fault_in_current(page, write, false);
+            }
+            paging::user_flags(pml4, page).is_some_and(|flags| {
+                !write || flags.intersects(PageFlags::WRITABLE | PageFlags::COPY_ON_WRITE)
+            })
+        })
+}
+
+pub fn copy_from_user(dst: &mut [u8], src: usize) -> Result<(), i64> {
+    if !range_ok(src, dst.len(), false) {
+        return Err(EFAULT);
+    }
+    match unsafe { user_copy(dst.as_mut_ptr(), src as *const u8, dst.len()) } {
+        0 => Ok(()),
+        _ => Err(EFAULT),
+    }
+}
+
+pub fn copy_to_user(dst: usize, src: &[u8]) -> Result<(), i64> {
+    if !range_ok(dst, src.len(), true) {
+        return Err(EFAULT);
+    }
+    match unsafe { user_copy(dst as *mut u8, src.as_ptr(), src.len()) } {
+        0 => Ok(()),
+        _ => Err(EFAULT),
+    }
+}
+
+pub fn read_user(src: usize, len: usize) -> Result<Vec<u8>, i64> {
+    let mut buf = Vec::new();
+    buf.try_reserve_exact(len).map_err(|_| -12)?;
+    buf.resize(len, 0);
+    copy_from_user(&mut buf, src)?;
+    Ok(buf)
+}
+
+/// Copies a NUL-terminated string of fewer than `max` bytes, without the NUL.
+pub fn read_user_cstr(src: usize, max: usize) -> Result<Vec<u8>, i64> {
+    let mut out = Vec::new();
+    let mut addr = src;
+    while out.len() < max {
+        let start = out.len();
+        let chunk = (PAGE_SIZE - (addr & (PAGE_SIZE - 1))).min(max - start);
+        out.try_reserve(chunk).map_err(|_| -12)?;
+        out.resize(start + chunk, 0);
+        copy_from_user(&mut out[start..], addr)?;
+        if let Some(nul) = out[start..].iter().position(|&b| b == 0) {
+            out.truncate(start + nul);
+            return Ok(out);
+        }
+        addr += chunk;
+    }
+    Err(E2BIG)
+}
@@ -1,14 +1,16 @@
-use crate::serial::{serial_read_byte_blocking, serial_try_read_byte, serial_write_byte};
+use crate::page_cache;
+use crate::paging::PAGE_SIZE;
+use crate::serial::{serial_try_read_byte, serial_write_byte};
+use crate::sync::{LockClass, SpinLock};
 use crate::tty::{framebuffer_info, framebuffer_read, framebuffer_write, write_bytes};
+use alloc::vec::Vec;
 use common::ustar::find_file;
-use spin::Mutex;
 
 const HANDLE_STDIN: u64 = 0;
 const HANDLE_STDOUT: u64 = 1;
 const HANDLE_STDERR: u64 = 2;
 const HANDLE_FB0: u64 = 3;
 const HANDLE_BASE_INITRD: u64 = 4;
-const MAX_OPEN_FILES: usize = 32;
 
 #[derive(Clone, Copy)]
 enum Node {
@@ -22,30 +24,33 @@ enum Node {
 struct VfsState {
     initrd_addr: usize,
     initrd_size: usize,
-    nodes: [Option<Node>; MAX_OPEN_FILES],
+    nodes: Vec<Option<Node>>,
 }
 
 impl VfsState {
     const fn new() -> Self {
-        let mut nodes = [None; MAX_OPEN_FILES];
-        nodes[0] = Some(Node::DevStdin);
-        nodes[1] = Some(Node::DevStdout);
-        nodes[2] = Some(Node::DevStderr);
-        nodes[3] = Some(Node::DevFramebuffer);
         Self {
             initrd_addr: 0,
             initrd_size: 0,
-            nodes,
+            nodes: Vec::new(),
         }
     }
 }
 
-static VFS: Mutex<VfsState> = Mutex::new(VfsState::new());
+static VFS: SpinLock<VfsState> = SpinLock::new(LockClass::Vfs, VfsState::new());
 
-pub fn init(initrd_addr: usize, initrd_size: usize) {
+pub fn init(initrd_addr: usize, initrd_size: usize) -> Option<()> {
     let mut vfs = VFS.lock();
     vfs.initrd_addr = initrd_addr;
     vfs.initrd_size = initrd_size;
+    vfs.nodes.try_reserve(HANDLE_BASE_INITRD as usize).ok()?;
+    vfs.nodes.extend([
+        Some(Node::DevStdin),
+        Some(Node::DevStdout),
+        Some(Node::DevStderr),
+        Some(Node::DevFramebuffer),
+    ]);
+    Some(())
 }
 
 pub fn open(path: &str) -> Option<u64> {
@@ -68,16 +73,19 @@ pub fn open(path: &str) -> Option<u64> {
         unsafe { core::slice::from_raw_parts(vfs.initrd_addr as *const u8, vfs.initrd_size) };
     let file = find_file(archive, clean)?;
 
-    for i in HANDLE_BASE_INITRD as usize..MAX_OPEN_FILES {
-        if vfs.nodes[i].is_none() {
-            vfs.nodes[i] = Some(Node::Initrd {
-                data_addr: file.data.as_ptr() as usize,
-                len: file.data.len(),
-            });
-            return Some(i as u64);
-        }
+    let node = Some(Node::Initrd {
+        data_addr: file.data.as_ptr() as usize,
+        len: file.data.len(),
+    });
+
+    let start = HANDLE_BASE_INITRD as usize;
+    if let Some(i) = (start..vfs.nodes.len()).find(|&i| vfs.nodes[i].is_none()) {
+        vfs.nodes[i] = node;
+        return Some(i as u64);
     }
-    None
+    vfs.nodes.try_reserve(1).ok()?;
+    vfs.nodes.push(node);
+    Some(vfs.nodes.len() as u64 - 1)
 }
 
 pub fn read(handle: u64, offset: usize, dst: &mut [u8]) -> Result<usize, i64> {
@@ -90,13 +98,8 @@ pub fn read(handle: u64, offset: usize, dst: &mut [u8]) -> Result<usize, i64> {
 
             let mut n = 0;
             while n < dst.len() {
-                let mut b = if n == 0 {
-                    serial_read_byte_blocking()
-                } else {
-                    let Some(byte) = serial_try_read_byte() else {
-                        break;
-                    };
-                    byte
+                let Some(mut b) = serial_try_read_byte() else {
+                    break;
                 };
 
                 if b == b'\r' {
@@ -110,7 +113,7 @@ pub fn read(handle: u64, offset: usize, dst: &mut [u8]) -> Result<usize, i64> {
                     break;
                 }
             }
-            Ok(n)
+            if n == 0 { Err(-11) } else { Ok(n) }
         }
         Node::Initrd { data_addr, len } => {
             if offset >= len {
@@ -150,6 +153,27 @@ pub fn write(handle: u64, bytes: &[u8]) -> Result<usize, i64> {
     }
 }
 
+pub fn mappable(handle: u64) -> Result<(), i64> {
+    match node_for(handle).ok_or(-9)? {
+        Node::Initrd { .. } => Ok(()),
+        _ => Err(-19),
+    }
+}
+
+/// Returns a referenced page-cache frame holding page `index` of a file, or
+/// `None` past the end of the file.
+pub fn file_page(handle: u64, index: usize) -> Option<usize> {
+    let Node::Initrd { data_addr, len } = node_for(handle)? else {
+        return None;
+    };
+    let offset = index.checked_mul(PAGE_SIZE).filter(|&o| o < len)?;
+    page_cache::get(data_addr, index, |page| {
+        let n = (len - offset).min(PAGE_SIZE);
+        let src = unsafe { core::slice::from_raw_parts((data_addr + offset) as *const u8, n) };
+        page[..n].copy_from_slice(src);
+    })
+}
+
 fn node_for(handle: u64) -> Option<Node> {
     let idx = usize::try_from(handle).ok()?;
     let vfs = VFS.lock();
@@ -0,0 +1,319 @@
+//! `/lib/ld.so`: the interpreter the kernel starts for programs with a
+//! `PT_INTERP`. It maps their `DT_NEEDED` libraries from `/lib`, binds
+//! symbols across the program and every library, then jumps to the program.
+#![no_std]
+#![no_main]
+
+use common::elf::{
+    AT_ENTRY, AT_EXECFN, AT_NULL, ET_DYN, ElfImage, Fixup, PF_R, PF_W, PF_X, PT_GNU_RELRO, PT_TLS,
+    ProgramHeader, RelocError, parse_elf64,
+};
+use common::syscall::{
+    FD_STDERR, MAP_ANONYMOUS, MAP_FIXED, MAP_PRIVATE, PROT_EXEC, PROT_NONE, PROT_READ, PROT_WRITE,
+    SYS_MMAP, SYS_MPROTECT, SYS_MUNMAP, SYS_OPEN, SYS_READ,
+};
+use core::arch::naked_asm;
+use core::ffi::CStr;
+use rt::{syscall3, syscall6};
+
+const PAGE_SIZE: usize = 4096;
+const MAX_OBJECTS: usize = 8;
+const LIB_DIR: &[u8] = b"/lib/";
+const PATH_MAX: usize = 128;
+/// Enough of a file to hold its ELF header and program headers.
+const HEADER_SIZE: usize = 1024;
+/// The shell convention for a program that could not be started.
+const FAILURE_STATUS: i32 = 127;
+
+/// The program or one of its shared libraries.
+#[derive(Clone, Copy)]
+struct Object {
+    name: &'static [u8],
+    /// A read-only view of the whole file, dropped once everything is bound.
+    image: ElfImage<'static>,
+    bias: usize,
+}
+
+#[panic_handler]
+fn panic(_: &core::panic::PanicInfo<'_>) -> ! {
+    fail(&[b"panic"])
+}
+
+fn fail(parts: &[&[u8]]) -> ! {
+    for part in [&b"ld.so: "[..]].iter().chain(parts).chain([&&b"\n"[..]]) {
+        let _ = rt::write(FD_STDERR, part);
+    }
+    rt::exit(FAILURE_STATUS)
+}
+
+fn mmap(addr: usize, len: usize, prot: u32, flags: u32, fd: u64, offset: usize) -> Option<usize> {
+    let args = [
+        addr,
+        len,
+        prot as usize,
+        flags as usize,
+        fd as usize,
+        offset,
+    ];
+    let [a, b, c, d, e, f] = args.map(|arg| arg as u64);
+    usize::try_from(syscall6(SYS_MMAP, a, b, c, d, e, f)).ok()
+}
+
+fn page_down(addr: usize) -> usize {
+    addr & !(PAGE_SIZE - 1)
+}
+
+fn page_up(addr: usize) -> usize {
+    page_down(addr + PAGE_SIZE - 1)
+}
+
+fn decimal(mut n: u32, buf: &mut [u8; 10]) -> &[u8] {
+    let mut i = buf.len();
+    loop {
+        i -= 1;
+        buf[i] = b'0' + (n % 10) as u8;
+        n /= 10;
+        if n == 0 {
+            return &buf[i..];
+        }
+    }
+}
+
+/// The program's entry point and the path it was started from.
+///
+/// # Safety
+/// `sp` must be the System V initial stack pointer.
+unsafe fn startup_info(sp: *const u64) -> (usize, &'static [u8]) {
+    let (mut entry, mut execfn) = (0, 0);
+    unsafe {
+        let argc = *sp as usize;
+        let mut word = sp.add(argc + 2);
+        while *word != 0 {
+            word = word.add(1);
+        }
+        word = word.add(1);
+        while *word != AT_NULL {
+            match *word {
+                AT_ENTRY => entry = *word.add(1) as usize,
+                AT_EXECFN => execfn = *word.add(1) as usize,
+                _ => {}
+            }
+            word = word.add(2);
+        }
+    }
+    if entry == 0 || execfn == 0 {
+        fail(&[b"started without AT_ENTRY or AT_EXECFN"]);
+    }
+    if entry == _start as *const () as usize {
+        fail(&[b"run a dynamically linked program instead"]);
+    }
+    let execfn = unsafe { CStr::from_ptr(execfn as *const core::ffi::c_char) };
+    (entry, execfn.to_bytes())
+}
+
+/// Opens `path` and maps enough of it read-only to parse as an ELF image.
+fn open_image(path: &[u8]) -> (ElfImage<'static>, u64) {
+    let fd = syscall3(SYS_OPEN, path.len() as u64, path.as_ptr() as u64, 0);
+    if fd < 0 {
+        fail(&[b"cannot open ", path]);
+    }
+    let fd = fd as u64;
+    let mut header = [0u8; HEADER_SIZE];
+    let n = syscall3(SYS_READ, fd, header.as_mut_ptr() as u64, HEADER_SIZE as u64);
+    let Some(head) = parse_elf64(&header[..usize::try_from(n).unwrap_or(0)]) else {
+        fail(&[path, b": not an ELF file"]);
+    };
+    // There is no fstat; the segments tell how far the file reaches.
+    let len = head
+        .segments()
+        .map(|hdr| hdr.file_offset.saturating_add(hdr.file_size))
+        .fold(n as usize, usize::max);
+    let Some(view) = mmap(0, len, PROT_READ, MAP_PRIVATE, fd, 0) else {
+        fail(&[path, b": cannot map the file"]);
+    };
+    let data = unsafe { core::slice::from_raw_parts(view as *const u8, len) };
+    let Some(image) = parse_elf64(data) else {
+        fail(&[path, b": not an ELF file"]);
+    };
+    (image, fd)
+}
+
+fn map_segment(fd: u64, hdr: &ProgramHeader, bias: usize, name: &[u8]) {
+    let prot = [(PF_R, PROT_READ), (PF_W, PROT_WRITE), (PF_X, PROT_EXEC)]
+        .iter()
+        .filter(|&&(flag, _)| hdr.flags & flag != 0)
+        .fold(PROT_NONE, |prot, &(_, bit)| prot | bit);
+    let start = bias + hdr.virt_addr;
+    let (file_end, mem_end) = (start + hdr.file_size, start + hdr.mem_size);
+    let mut anon = page_down(start);
+    if hdr.file_size > 0 {
+        let offset = hdr.file_offset - (start - anon);
+        let flags = MAP_PRIVATE | MAP_FIXED;
+        if mmap(anon, file_end - anon, prot, flags, fd, offset).is_none() {
+            fail(&[name, b": cannot map a segment"]);
+        }
+        anon = page_up(file_end);
+        // The rest of the last file page belongs to .bss.
+        let zero_end = anon.min(mem_end);
+        if zero_end > file_end {
+            if prot & PROT_WRITE == 0 {
+                fail(&[name, b": .bss in a read-only segment"]);
+            }
+            unsafe { core::ptr::write_bytes(file_end as *mut u8, 0, zero_end - file_end) };
+        }
+    }
+    let flags = MAP_PRIVATE | MAP_FIXED | MAP_ANONYMOUS;
+    if mem_end > anon && mmap(anon, mem_end - anon, prot, flags, u64::MAX, 0).is_none() {
+        fail(&[name, b": cannot map .bss"]);
+    }
+}
+
+/// Maps `/lib/<name>` anywhere in the `mmap` area.
+fn load_library(name: &'static [u8]) -> Object {
+    let mut path = [0u8; PATH_MAX];
+    let len = LIB_DIR.len() + name.len();
+    if len > PATH_MAX {
+        fail(&[name, b": name too long"]);
+    }
+    path[..LIB_DIR.len()].copy_from_slice(LIB_DIR);
+    path[LIB_DIR.len()..len].copy_from_slice(name);
+    let (image, fd) = open_image(&path[..len]);
+    if image.kind != ET_DYN {
+        fail(&[name, b": not a shared object"]);
+    }
+
+    let lo = image.program_headers().map(|hdr| hdr.virt_addr).min();
+    let hi = image
+        .program_headers()
+        .map(|hdr| hdr.virt_addr + hdr.mem_size)
+        .max();
+    let (Some(lo), Some(hi)) = (lo.map(page_down), hi.map(page_up)) else {
+        fail(&[name, b": nothing to load"]);
+    };
+    // Reserve the whole span so the segments keep their relative layout.
+    let flags = MAP_PRIVATE | MAP_ANONYMOUS;
+    let Some(base) = mmap(0, hi - lo, PROT_NONE, flags, u64::MAX, 0) else {
+        fail(&[name, b": out of address space"]);
+    };
+    let bias = base - lo;
+    for hdr in image.program_headers() {
+        map_segment(fd, &hdr, bias, name);
+    }
+    Object { name, image, bias }
+}
+
+fn relocate(object: &Object, objects: &[Object]) {
+    // Symbols bind to the first object that defines them, the program first.
+    let lookup = |name: &[u8]| {
+        objects
+            .iter()
+            .find_map(|other| other.image.symbol(name, other.bias))
+    };
+    let reloc_fail = |error: RelocError| -> ! {
+        let mut buf = [0u8; 10];
+        match error {
+            RelocError::Malformed => fail(&[object.name, b": malformed dynamic section"]),
+            RelocError::Unsupported(kind) => fail(&[
+                object.name,
+                b": unsupported relocation type ",
+                decimal(kind, &mut buf),
+            ]),
+            RelocError::Undefined(index) => fail(&[
+                object.name,
+                b": undefined symbol #",
+                decimal(index, &mut buf),
+            ]),
+        }
+    };
+    let fixups = object
+        .image
+        .relocations_with(object.bias, lookup)
+        .unwrap_or_else(|error| reloc_fail(error));
+    for fixup in fixups {
+        match fixup.unwrap_or_else(|error| reloc_fail(error)) {
+            Fixup::Write { addr, value } => unsafe { (addr as *mut u64).write_unaligned(value) },
+            Fixup::Resolve { addr, resolver } => unsafe {
+                let resolver: extern "C" fn() -> u64 = core::mem::transmute(resolver);
+                (addr as *mut u64).write_unaligned(resolver());
+            },
+        }
+    }
+}
+
+fn protect_relro(object: &Object) {
+    let Some(hdr) = object.image.segment(PT_GNU_RELRO) else {
+        return;
+    };
+    let start = page_down(object.bias + hdr.virt_addr);
+    let end = page_down(object.bias + hdr.virt_addr + hdr.mem_size);
+    if end > start {
+        let _ = syscall3(
+            SYS_MPROTECT,
+            start as u64,
+            (end - start) as u64,
+            u64::from(PROT_READ),
+        );
+    }
+}
+
+/// Loads and binds everything, then returns the program's entry point.
+extern "C" fn main(sp: *const u64) -> usize {
+    let (entry, execfn) = unsafe { startup_info(sp) };
+    let (image, _) = open_image(execfn);
+    let program = Object {
+        name: execfn,
+        image,
+        bias: entry.wrapping_sub(image.entry),
+    };
+    let mut objects = [program; MAX_OBJECTS];
+    let mut count = 1;
+    let mut next = 0;
+    while next < count {
+        let image = objects[next].image;
+        for name in image.needed() {
+            if objects[1..count].iter().any(|object| object.name == name) {
+                continue;
+            }
+            if count == MAX_OBJECTS {
+                fail(&[b"too many shared objects"]);
+            }
+            objects[count] = load_library(name);
+            count += 1;
+        }
+        next += 1;
+    }
+    let objects = &objects[..count];
+
+    if let Some(object) = objects
+        .iter()
+        .find(|object| object.image.segment(PT_TLS).is_some())
+    {
+        fail(&[object.name, b": PT_TLS is not supported"]);
+    }
+    // Libraries first, so nothing of theirs runs before they are bound.
+    for object in objects.iter().rev() {
+        relocate(object, objects);
+    }
+    for object in objects {
+        protect_relro(object);
+        let view = object.image.data;
+        let _ = syscall3(SYS_MUNMAP, view.as_ptr() as u64, view.len() as u64, 0);
+    }
+    entry
+}
+
+/// Runs `main` on the initial stack, then enters the program with that stack
+/// intact and `rdx` zeroed (no finalizer to register).
+#[unsafe(naked)]
+#[unsafe(no_mangle)]
+pub extern "C" fn _start() -> ! {
+    naked_asm!(
+        "mov r12, rsp",
+        "mov rdi, rsp",
+        "call {main}",
+        "mov rsp, r12",
+        "xor edx, edx",
+        "jmp rax",
+        main = sym main,
+    )
+}
@@ -0,0 +1,12 @@
+/// Built as a bin because `cdylib` is unavailable on x86_64-unknown-none; the
+/// link arguments turn it into the shared object installed as `/lib/librt.so`.
+fn main() {
+    for arg in [
+        "-no-pie",
+        "-shared",
+        "-soname=librt.so",
+        "--hash-style=sysv",
+    ] {
+        println!("cargo:rustc-link-arg-bin=librt={arg}");
+    }
+}
@@ -0,0 +1,27 @@
+//! `librt.so`: the syscall entry, exit and abort paths shared by every
+//! dynamically linked program. The exports are listed in `crates/rt/build.rs`
+//! and implemented by `rt`'s static build.
+#![no_std]
+#![no_main]
+
+#[panic_handler]
+fn panic(_: &core::panic::PanicInfo<'_>) -> ! {
+    rt::abort(b"[librt] panic")
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn rt_syscall(n: u64, a: u64, b: u64, c: u64, d: u64, e: u64, f: u64) -> i64 {
+    rt::syscall6(n, a, b, c, d, e, f) as i64
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn rt_exit(code: i32) -> ! {
+    rt::exit(code)
+}
+
+/// # Safety
+/// `msg` must point to `len` readable bytes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rt_abort(msg: *const u8, len: usize) -> ! {
+    rt::abort(unsafe { core::slice::from_raw_parts(msg, len) })
+}
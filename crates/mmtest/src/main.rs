@@ -0,0 +1,229 @@
+#![no_std]
+#![no_main]
+
+use common::syscall::{
+    FD_STDOUT, MAP_ANONYMOUS, MAP_FIXED, MAP_PRIVATE, MAP_SHARED, PROT_EXEC, PROT_READ, PROT_WRITE,
+    SYS_BRK, SYS_FORK, SYS_MMAP, SYS_MPROTECT, SYS_MUNMAP, SYS_OPEN, SYS_READ, SYS_WAIT4,
+};
+use core::hint::black_box;
+use core::ptr::{read_volatile, write_volatile};
+use rt::{syscall3, syscall6};
+
+const PAGE: usize = 4096;
+const HINT: usize = 0x2000_0000;
+const SIGSEGV_STATUS: i32 = (128 + 11) << 8;
+const LAZY_LEN: usize = 1 << 30;
+
+#[panic_handler]
+fn panic(_: &core::panic::PanicInfo<'_>) -> ! {
+    rt::abort(b"[mmtest] panic")
+}
+
+fn fail(msg: &[u8]) -> ! {
+    for part in [&b"[mmtest] FAILED: "[..], msg, b"\n"] {
+        let _ = rt::write(FD_STDOUT, part);
+    }
+    rt::exit(1)
+}
+
+fn mmap_file(addr: usize, len: usize, prot: u32, flags: u32, fd: u64, offset: usize) -> isize {
+    syscall6(
+        SYS_MMAP,
+        addr as u64,
+        len as u64,
+        u64::from(prot),
+        u64::from(flags),
+        fd,
+        offset as u64,
+    )
+}
+
+fn mmap(addr: usize, len: usize, prot: u32, flags: u32) -> isize {
+    mmap_file(
+        addr,
+        len,
+        prot,
+        flags | MAP_PRIVATE | MAP_ANONYMOUS,
+        u64::MAX,
+        0,
+    )
+}
+
+fn bytes_at(addr: isize, len: usize) -> &'static [u8] {
+    unsafe { core::slice::from_raw_parts(addr as *const u8, len) }
+}
+
+/// Maps this program's own ELF privately and shared and compares both with `read`.
+fn check_file_mapping() {
+    let path = b"/bin/mmtest.elf";
+    let fd = syscall3(SYS_OPEN, path.len() as u64, path.as_ptr() as u64, 0);
+    if fd < 0 {
+        fail(b"open /bin/mmtest.elf");
+    }
+    let fd = fd as u64;
+    let mut contents = [0u8; 2 * PAGE];
+    let len = contents.len();
+    if syscall3(SYS_READ, fd, contents.as_mut_ptr() as u64, len as u64) != len as isize {
+        fail(b"read /bin/mmtest.elf");
+    }
+
+    let private = mmap_file(0, len, PROT_READ | PROT_WRITE, MAP_PRIVATE, fd, 0);
+    let shared = mmap_file(0, PAGE, PROT_READ, MAP_SHARED, fd, PAGE);
+    if private < 0 || shared < 0 {
+        fail(b"mmap of a file");
+    }
+    if bytes_at(private, len) != contents || bytes_at(shared, PAGE) != &contents[PAGE..] {
+        fail(b"file mapping does not match read()");
+    }
+
+    let second_page = (private as usize + PAGE) as *mut u8;
+    unsafe { write_volatile(second_page, !contents[PAGE]) };
+    if unsafe { read_volatile(shared as *const u8) } != contents[PAGE] {
+        fail(b"private file write reached the page cache");
+    }
+    if mmap_file(0, PAGE, PROT_READ | PROT_WRITE, MAP_SHARED, fd, 0) != -13 {
+        fail(b"writable shared mapping of a read-only file was allowed");
+    }
+
+    let _ = syscall3(SYS_MUNMAP, private as u64, len as u64, 0);
+    let _ = syscall3(SYS_MUNMAP, shared as u64, PAGE as u64, 0);
+}
+
+fn brk(addr: usize) -> usize {
+    syscall3(SYS_BRK, addr as u64, 0, 0) as usize
+}
+
+/// Runs `probe` on `addr` in a child and returns its wait status.
+fn run_child(addr: usize, probe: fn(*mut u8)) -> Option<i32> {
+    let pid = syscall3(SYS_FORK, 0, 0, 0);
+    if pid < 0 {
+        return None;
+    }
+    if pid == 0 {
+        probe(addr as *mut u8);
+        rt::exit(0);
+    }
+    let mut status = 0i32;
+    let waited = syscall3(SYS_WAIT4, pid as u64, &raw mut status as u64, 0);
+    (waited == pid).then_some(status)
+}
+
+fn faults(addr: usize, probe: fn(*mut u8)) -> bool {
+    run_child(addr, probe) == Some(SIGSEGV_STATUS)
+}
+
+fn store(ptr: *mut u8) {
+    unsafe { write_volatile(ptr, 1) };
+}
+
+fn load(ptr: *mut u8) {
+    let _ = unsafe { read_volatile(ptr) };
+}
+
+/// Touches `depth` pages of stack, one frame per page.
+fn use_stack(depth: usize) -> u8 {
+    let mut frame = [0u8; PAGE];
+    black_box(&mut frame);
+    if black_box(depth) == 0 {
+        return frame[0];
+    }
+    use_stack(depth - 1).wrapping_add(frame[PAGE - 1])
+}
+
+fn deep_stack(_: *mut u8) {
+    black_box(use_stack(1024));
+}
+
+fn overflow_stack(_: *mut u8) {
+    black_box(use_stack(usize::MAX));
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn _start() -> ! {
+    let _ = rt::write(FD_STDOUT, b"[mmtest] checking mmap/munmap/mprotect/brk\n");
+
+    let rw = PROT_READ | PROT_WRITE;
+    let addr = mmap(HINT, 2 * PAGE, rw, 0);
+    if addr != HINT as isize {
+        fail(b"mmap ignored a free address hint");
+    }
+    unsafe { write_volatile((HINT + PAGE) as *mut u8, 0x5a) };
+
+    if syscall3(SYS_MPROTECT, HINT as u64, PAGE as u64, u64::from(PROT_READ)) != 0 {
+        fail(b"mprotect");
+    }
+    if !faults(HINT, store) {
+        fail(b"write to a PROT_READ page did not fault");
+    }
+    if unsafe { read_volatile((HINT + PAGE) as *const u8) } != 0x5a {
+        fail(b"mprotect changed the neighbouring page");
+    }
+
+    if mmap(HINT + PAGE, PAGE, rw, MAP_FIXED) != (HINT + PAGE) as isize
+        || unsafe { read_volatile((HINT + PAGE) as *const u8) } != 0
+    {
+        fail(b"MAP_FIXED did not replace the old page");
+    }
+
+    if syscall3(SYS_MUNMAP, HINT as u64, (2 * PAGE) as u64, 0) != 0 {
+        fail(b"munmap");
+    }
+    if !faults(HINT, load) {
+        fail(b"read from an unmapped page did not fault");
+    }
+
+    if mmap(0, PAGE, PROT_WRITE | PROT_EXEC, 0) != -13 {
+        fail(b"writable+executable mapping was allowed");
+    }
+
+    for _ in 0..2048 {
+        let chunk = mmap(0, 16 * PAGE, rw, 0);
+        if chunk < 0 {
+            fail(b"mmap ran out of memory in an alloc/free loop");
+        }
+        unsafe { write_volatile((chunk as usize + 15 * PAGE) as *mut u8, 1) };
+        if syscall3(SYS_MUNMAP, chunk as u64, (16 * PAGE) as u64, 0) != 0 {
+            fail(b"munmap in an alloc/free loop");
+        }
+    }
+
+    let lazy = mmap(0, LAZY_LEN, rw, 0);
+    if lazy < 0 {
+        fail(b"large mapping was not reserved lazily");
+    }
+    unsafe {
+        write_volatile(lazy as *mut u8, 1);
+        write_volatile((lazy as usize + LAZY_LEN - 1) as *mut u8, 2);
+    }
+    if syscall3(SYS_MUNMAP, lazy as u64, LAZY_LEN as u64, 0) != 0 {
+        fail(b"munmap of the lazy mapping");
+    }
+
+    if run_child(0, deep_stack) != Some(0) {
+        fail(b"stack did not grow on demand");
+    }
+    if !faults(0, overflow_stack) {
+        fail(b"stack overflow was not stopped by the guard page");
+    }
+
+    check_file_mapping();
+
+    let start = brk(0);
+    let grown = start + 3 * PAGE + 100;
+    if brk(grown) != grown {
+        fail(b"brk did not grow");
+    }
+    unsafe { write_volatile((grown - 1) as *mut u8, 1) };
+    if brk(start) != start {
+        fail(b"brk did not shrink");
+    }
+    if !faults(start, store) {
+        fail(b"heap page survived shrinking brk");
+    }
+
+    let _ = rt::write(
+        FD_STDOUT,
+        b"[mmtest] ok: mmap/munmap/mprotect/brk behave, pages fault in on demand, files map through the page cache\n",
+    );
+    rt::exit(0)
+}
@@ -0,0 +1,48 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+
+/// The functions `librt.so` exports; keep in sync with `crates/librt`.
+const EXPORTS: &[&str] = &["rt_syscall", "rt_exit", "rt_abort"];
+
+/// Programs link against a stub `librt.so` that only carries the export
+/// names; `/lib/ld.so` binds them to the real library at startup. Static
+/// builds link nothing.
+fn main() {
+    if env::var_os("CARGO_FEATURE_STATIC").is_some() {
+        return;
+    }
+    let out = PathBuf::from(env::var("OUT_DIR").expect("OUT_DIR"));
+    let script = out.join("stub.ld");
+    let mut text = String::from("SECTIONS { .text : { } }\n");
+    for name in EXPORTS {
+        text.push_str(&format!("{name} = 0;\n"));
+    }
+    fs::write(&script, text).expect("write stub script");
+
+    let rustc = env::var("RUSTC").expect("RUSTC");
+    let sysroot = Command::new(rustc)
+        .args(["--print", "sysroot"])
+        .output()
+        .expect("rustc --print sysroot");
+    let lld = PathBuf::from(String::from_utf8(sysroot.stdout).expect("sysroot").trim())
+        .join("lib/rustlib")
+        .join(env::var("HOST").expect("HOST"))
+        .join("bin/rust-lld");
+    let status = Command::new(lld)
+        .args(["-flavor", "gnu", "-m", "elf_x86_64", "-shared"])
+        .args(["-soname", "librt.so", "--hash-style=sysv", "-o"])
+        .arg(out.join("librt.so"))
+        .arg(&script)
+        .status()
+        .expect("run rust-lld");
+    assert!(
+        status.success(),
+        "rust-lld failed to build the librt.so stub"
+    );
+
+    println!("cargo:rustc-link-search=native={}", out.display());
+    println!("cargo:rustc-link-lib=dylib=rt");
+    println!("cargo:rerun-if-changed=build.rs");
+}
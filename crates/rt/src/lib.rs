@@ -0,0 +1,66 @@
+//! Calls into `librt.so`, the runtime shared by dynamically linked programs.
+//! Depending on this crate puts `librt.so` in `DT_NEEDED`, so the program
+//! must be linked with `--dynamic-linker=/lib/ld.so`.
+//!
+//! The `static` feature compiles the runtime into the program instead, for
+//! the few that must not wait for `/lib/ld.so`: the loader and `librt.so`
+//! themselves, `init`, so booting does not depend on the loader, and
+//! `testbin`, which checks how the kernel loads static programs. Cargo
+//! unifies features across one build, so build those separately, as
+//! `scripts/build_image.sh` does.
+#![no_std]
+
+use common::syscall::SYS_WRITE;
+
+#[cfg(feature = "static")]
+mod native;
+
+#[cfg(feature = "static")]
+use native::{rt_abort, rt_exit, rt_syscall};
+
+#[cfg(not(feature = "static"))]
+unsafe extern "C" {
+    fn rt_syscall(n: u64, a: u64, b: u64, c: u64, d: u64, e: u64, f: u64) -> i64;
+    safe fn rt_exit(code: i32) -> !;
+    fn rt_abort(msg: *const u8, len: usize) -> !;
+}
+
+pub fn syscall6(n: u64, a: u64, b: u64, c: u64, d: u64, e: u64, f: u64) -> isize {
+    unsafe { rt_syscall(n, a, b, c, d, e, f) as isize }
+}
+
+pub fn syscall4(n: u64, a: u64, b: u64, c: u64, d: u64) -> isize {
+    syscall6(n, a, b, c, d, 0, 0)
+}
+
+pub fn syscall3(n: u64, a: u64, b: u64, c: u64) -> isize {
+    syscall6(n, a, b, c, 0, 0, 0)
+}
+
+pub fn write(fd: u64, bytes: &[u8]) -> isize {
+    syscall3(SYS_WRITE, fd, bytes.as_ptr() as u64, bytes.len() as u64)
+}
+
+/// Writes `n` in decimal.
+pub fn write_decimal(fd: u64, mut n: u64) -> isize {
+    let mut buf = [0u8; 20];
+    let mut i = buf.len();
+    loop {
+        i -= 1;
+        buf[i] = b'0' + (n % 10) as u8;
+        n /= 10;
+        if n == 0 {
+            break;
+        }
+    }
+    write(fd, &buf[i..])
+}
+
+pub fn exit(code: i32) -> ! {
+    rt_exit(code)
+}
+
+/// Prints `msg` to stderr and exits with the `SIGABRT` status.
+pub fn abort(msg: &[u8]) -> ! {
+    unsafe { rt_abort(msg.as_ptr(), msg.len()) }
+}
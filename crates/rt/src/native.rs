@@ -0,0 +1,48 @@
+//! What `librt.so` exports, compiled into programs built with the `static`
+//! feature.
+
+use common::syscall::{FD_STDERR, SYS_EXIT_GROUP, SYS_WRITE};
+use core::arch::asm;
+
+/// Exit status of a process killed by `SIGABRT`.
+const ABORT_STATUS: i32 = 128 + 6;
+
+/// # Safety
+/// The call must be one whose pointer arguments are valid, as for any
+/// system call.
+pub unsafe fn rt_syscall(n: u64, a: u64, b: u64, c: u64, d: u64, e: u64, f: u64) -> i64 {
+    let ret: i64;
+    unsafe {
+        asm!(
+            "syscall",
+            in("rax") n,
+            in("rdi") a,
+            in("rsi") b,
+            in("rdx") c,
+            in("r10") d,
+            in("r8") e,
+            in("r9") f,
+            lateout("rax") ret,
+            lateout("rcx") _,
+            lateout("r11") _,
+            options(nostack)
+        );
+    }
+    ret
+}
+
+pub fn rt_exit(code: i32) -> ! {
+    let _ = unsafe { rt_syscall(SYS_EXIT_GROUP, code as u64, 0, 0, 0, 0, 0) };
+    loop {
+        core::hint::spin_loop();
+    }
+}
+
+/// # Safety
+/// `msg` must point to `len` readable bytes.
+pub unsafe fn rt_abort(msg: *const u8, len: usize) -> ! {
+    for (ptr, len) in [(msg, len), (b"\n".as_ptr(), 1)] {
+        let _ = unsafe { rt_syscall(SYS_WRITE, FD_STDERR, ptr as u64, len as u64, 0, 0, 0) };
+    }
+    rt_exit(ABORT_STATUS)
+}
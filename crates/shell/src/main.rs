@@ -1,35 +1,12 @@
 #![no_std]
 #![no_main]
 
-use common::syscall::{FD_STDIN, FD_STDOUT, SYS_EXECVE, SYS_EXIT, SYS_FORK, SYS_READ, SYS_WRITE};
-use core::arch::asm;
+use common::syscall::{FD_STDIN, FD_STDOUT, SYS_EXECVE, SYS_FORK, SYS_READ, SYS_WAIT4, SYS_WRITE};
+use rt::{syscall3, syscall4};
 
 #[panic_handler]
 fn panic(_: &core::panic::PanicInfo<'_>) -> ! {
-    loop {
-        core::hint::spin_loop();
-    }
-}
-
-fn syscall3(n: u64, a: u64, b: u64, c: u64) -> isize {
-    let ret: i64;
-    unsafe {
-        asm!(
-            "int 0x80",
-            in("rax") n,
-            in("rdi") a,
-            in("rsi") b,
-            in("rdx") c,
-            lateout("rax") ret,
-            lateout("rcx") _,
-            lateout("r8") _,
-            lateout("r9") _,
-            lateout("r10") _,
-            lateout("r11") _,
-            options(nostack)
-        );
-    }
-    ret as isize
+    rt::abort(b"[shell] panic")
 }
 
 fn write(bytes: &[u8]) {
@@ -71,6 +48,29 @@ fn first_word(line: &[u8]) -> &[u8] {
     &line[start..end]
 }
 
+const MAX_ARGS: usize = 16;
+
+/// Copies `cmd` into `buf` as NUL-terminated words and returns the
+/// NULL-terminated argv pointing at them.
+fn build_argv(cmd: &[u8], buf: &mut [u8; 65]) -> [u64; MAX_ARGS + 1] {
+    let mut argv = [0u64; MAX_ARGS + 1];
+    let len = cmd.len().min(buf.len() - 1);
+    buf[..len].copy_from_slice(&cmd[..len]);
+    buf[len] = 0;
+    let (mut argc, mut in_word) = (0, false);
+    for i in 0..len {
+        if matches!(buf[i], b' ' | b'\t') {
+            buf[i] = 0;
+            in_word = false;
+        } else if !in_word && argc < MAX_ARGS {
+            argv[argc] = buf[i..].as_ptr() as u64;
+            argc += 1;
+            in_word = true;
+        }
+    }
+    argv
+}
+
 fn build_exec_path<'a>(cmd: &[u8], out: &'a mut [u8]) -> Option<&'a str> {
     let prefix = b"/bin/";
     let suffix = b".elf";
@@ -91,17 +91,22 @@ fn shell_loop() -> ! {
     loop {
         write(b"shell> ");
         let used = read_line(&mut line);
-        let word = first_word(&line[..used]);
+        let mut cmd = &line[..used];
+        while let [rest @ .., b' ' | b'\t' | b'\n' | b'\r'] = cmd {
+            cmd = rest;
+        }
+        let background = cmd.last() == Some(&b'&');
+        if background {
+            cmd = &cmd[..cmd.len() - 1];
+        }
+        let word = first_word(cmd);
         if word.is_empty() {
             continue;
         }
 
         if word == b"exit" {
             write(b"[shell] bye\n");
-            let _ = syscall3(SYS_EXIT, 0, 0, 0);
-            loop {
-                unsafe { asm!("hlt") };
-            }
+            rt::exit(0)
         }
 
         let Some(path) = build_exec_path(word, &mut exec_path_buf) else {
@@ -113,24 +118,29 @@ fn shell_loop() -> ! {
         write(path.as_bytes());
         write(b"\n");
 
-        let pid = syscall3(SYS_FORK, 0, shell_resume as usize as u64, 0);
+        let pid = syscall3(SYS_FORK, 0, 0, 0);
         if pid == 0 {
-            let _ = syscall3(SYS_EXECVE, path.len() as u64, path.as_ptr() as u64, 0);
+            let mut arg_buf = [0u8; 65];
+            let argv = build_argv(cmd, &mut arg_buf);
+            let _ = syscall4(
+                SYS_EXECVE,
+                path.len() as u64,
+                path.as_ptr() as u64,
+                argv.as_ptr() as u64,
+                0,
+            );
             write(b"[shell] exec failed: ");
             write(path.as_bytes());
             write(b"\n");
-            let _ = syscall3(SYS_EXIT, 127, 0, 0);
-            loop {
-                unsafe { asm!("hlt") };
-            }
+            rt::exit(127)
+        }
+        if pid > 0 && !background {
+            let mut status = 0i32;
+            let _ = syscall3(SYS_WAIT4, pid as u64, &mut status as *mut i32 as u64, 0);
         }
     }
 }
 
-extern "C" fn shell_resume() -> ! {
-    shell_loop()
-}
-
 #[unsafe(no_mangle)]
 pub extern "C" fn _start() -> ! {
     write(b"[shell] tiny shell started\n");
@@ -0,0 +1,146 @@
+#![no_std]
+#![no_main]
+
+use common::syscall::{
+    CLONE_CHILD_CLEARTID, CLONE_FILES, CLONE_FS, CLONE_PARENT_SETTID, CLONE_SIGHAND, CLONE_SYSVSEM,
+    CLONE_THREAD, CLONE_VM, FD_STDOUT, FUTEX_PRIVATE_FLAG, FUTEX_WAIT, MAP_ANONYMOUS, MAP_PRIVATE,
+    PROT_READ, PROT_WRITE, SYS_CLONE, SYS_EXIT, SYS_FUTEX, SYS_GETCPU, SYS_MMAP,
+};
+use core::arch::asm;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use rt::{syscall3, syscall6};
+
+const THREADS: usize = 4;
+const STACK_SIZE: u64 = 16 * 1024;
+/// Rounds of busy work per thread before giving up on seeing a second CPU.
+const ROUNDS: u32 = 2000;
+const SPINS_PER_ROUND: u32 = 100_000;
+
+#[panic_handler]
+fn panic(_: &core::panic::PanicInfo<'_>) -> ! {
+    rt::abort(b"[smptest] panic")
+}
+
+fn fail(msg: &[u8]) -> ! {
+    for part in [&b"[smptest] FAILED: "[..], msg, b"\n"] {
+        let _ = rt::write(FD_STDOUT, part);
+    }
+    rt::exit(1)
+}
+
+fn getcpu() -> u32 {
+    let mut cpu = u32::MAX;
+    if syscall3(SYS_GETCPU, &raw mut cpu as u64, 0, 0) != 0 {
+        fail(b"getcpu");
+    }
+    cpu
+}
+
+/// Bit n is set once some thread has run on CPU n.
+static SEEN: AtomicU64 = AtomicU64::new(0);
+/// Set to the child's tid by the kernel, cleared and woken when it exits.
+static JOIN: [AtomicU32; THREADS] = [const { AtomicU32::new(0) }; THREADS];
+
+fn record_cpu() {
+    let cpu = getcpu();
+    if cpu >= u64::BITS {
+        fail(b"getcpu returned a bad index");
+    }
+    SEEN.fetch_or(1 << cpu, Ordering::Relaxed);
+}
+
+/// Busy-loops so the threads compete for CPU time, noting every CPU they run
+/// on, until two CPUs have shown up or the rounds run out.
+extern "C" fn worker() -> ! {
+    for _ in 0..ROUNDS {
+        record_cpu();
+        if SEEN.load(Ordering::Relaxed).count_ones() >= 2 {
+            break;
+        }
+        for _ in 0..SPINS_PER_ROUND {
+            core::hint::spin_loop();
+        }
+    }
+    let _ = syscall3(SYS_EXIT, 0, 0, 0);
+    fail(b"thread exit returned")
+}
+
+fn spawn(index: usize, stack_top: u64) -> isize {
+    let flags = CLONE_VM
+        | CLONE_FS
+        | CLONE_FILES
+        | CLONE_SIGHAND
+        | CLONE_THREAD
+        | CLONE_SYSVSEM
+        | CLONE_PARENT_SETTID
+        | CLONE_CHILD_CLEARTID;
+    let tid_word = JOIN[index].as_ptr() as u64;
+    let ret: i64;
+    unsafe {
+        asm!(
+            "syscall",
+            "test rax, rax",
+            "jnz 2f",
+            "call r12",
+            "ud2",
+            "2:",
+            inlateout("rax") SYS_CLONE as i64 => ret,
+            in("rdi") flags,
+            in("rsi") stack_top,
+            in("rdx") tid_word,
+            in("r10") tid_word,
+            in("r8") 0,
+            in("r12") worker as *const () as u64,
+            lateout("rcx") _,
+            lateout("r11") _,
+        );
+    }
+    ret as isize
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn _start() -> ! {
+    let _ = rt::write(FD_STDOUT, b"[smptest] starting threads\n");
+    record_cpu();
+
+    let stacks = syscall6(
+        SYS_MMAP,
+        0,
+        STACK_SIZE * THREADS as u64,
+        u64::from(PROT_READ | PROT_WRITE),
+        u64::from(MAP_PRIVATE | MAP_ANONYMOUS),
+        u64::MAX,
+        0,
+    );
+    if stacks < 0 {
+        fail(b"mmap");
+    }
+    for index in 0..THREADS {
+        let top = stacks as u64 + STACK_SIZE * (index as u64 + 1);
+        if spawn(index, top) <= 0 {
+            fail(b"clone");
+        }
+    }
+
+    let op = FUTEX_WAIT | FUTEX_PRIVATE_FLAG;
+    for word in &JOIN {
+        loop {
+            let tid = word.load(Ordering::Acquire);
+            if tid == 0 {
+                break;
+            }
+            let _ = syscall6(SYS_FUTEX, word.as_ptr() as u64, op, tid.into(), 0, 0, 0);
+        }
+    }
+
+    let cpus = SEEN.load(Ordering::Relaxed).count_ones();
+    if cpus < 2 {
+        fail(b"every thread ran on one CPU (boot with -smp 2 or more)");
+    }
+    let _ = rt::write(FD_STDOUT, b"[smptest] ok: ");
+    let _ = rt::write_decimal(FD_STDOUT, THREADS as u64);
+    let _ = rt::write(FD_STDOUT, b" busy threads ran on ");
+    let _ = rt::write_decimal(FD_STDOUT, cpus.into());
+    let _ = rt::write(FD_STDOUT, b" CPUs\n");
+    rt::exit(0)
+}
@@ -1,25 +1,4 @@
-use std::env;
-use std::fs;
-use std::path::PathBuf;
-
 fn main() {
-    let out = PathBuf::from(env::var("OUT_DIR").expect("OUT_DIR"));
-    let script = out.join("link.ld");
-    fs::write(
-        &script,
-        r#"OUTPUT_FORMAT(elf64-x86-64)
-ENTRY(_start)
-SECTIONS
-{
-  . = 0xffffffff80200000;
-  .text : { *(.text*) }
-  .rodata : { *(.rodata*) }
-  .data : { *(.data*) }
-  .bss : { *(.bss*) *(COMMON) }
-}
-"#,
-    )
-    .expect("write linker script");
-
-    println!("cargo:rustc-link-arg-bin=testbin=-T{}", script.display());
+    let dir = std::env::var("CARGO_MANIFEST_DIR").expect("manifest dir");
+    println!("cargo:rustc-link-arg=-T{dir}/../user.ld");
 }
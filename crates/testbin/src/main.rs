@@ -1,26 +1,47 @@
 #![no_std]
 #![no_main]
 
-use common::syscall::{SYS_EXIT, SYS_OPEN, SYS_READ, SYS_WRITE};
-use core::arch::asm;
+use common::elf::{AT_ENTRY, AT_NULL, AT_PAGESZ, AT_RANDOM};
+use common::syscall::{FD_STDOUT, SYS_OPEN, SYS_READ, SYS_WRITE};
+use core::arch::{asm, global_asm, naked_asm};
+use core::ffi::CStr;
+use rt::syscall3;
+
+const TLS_MAGIC: u64 = 0x7e57_0000_7e57_0001;
+
+global_asm!(
+    r#"
+.section .tdata,"awT",@progbits
+.balign 8
+tls_word:
+    .quad {magic}
+.section .tbss,"awT",@nobits
+.balign 8
+tls_zero:
+    .zero 8
+.text
+"#,
+    magic = const TLS_MAGIC,
+);
 
 #[panic_handler]
 fn panic(_: &core::panic::PanicInfo<'_>) -> ! {
-    loop {
-        core::hint::spin_loop();
-    }
+    rt::abort(b"[testbin] panic")
+}
+
+fn write(bytes: &[u8]) {
+    let _ = rt::write(FD_STDOUT, bytes);
 }
 
-fn syscall3(n: u64, a: u64, b: u64, c: u64) -> isize {
-    let ret: i64;
+/// Writes through the `int 0x80` compatibility gate, which `rt` never uses.
+fn write_int80(bytes: &[u8]) {
     unsafe {
         asm!(
             "int 0x80",
-            in("rax") n,
-            in("rdi") a,
-            in("rsi") b,
-            in("rdx") c,
-            lateout("rax") ret,
+            inout("rax") SYS_WRITE => _,
+            in("rdi") FD_STDOUT,
+            in("rsi") bytes.as_ptr(),
+            in("rdx") bytes.len(),
             lateout("rcx") _,
             lateout("r8") _,
             lateout("r9") _,
@@ -29,16 +50,83 @@ fn syscall3(n: u64, a: u64, b: u64, c: u64) -> isize {
             options(nostack)
         );
     }
-    ret as isize
 }
 
-fn write(bytes: &[u8]) {
-    let _ = syscall3(SYS_WRITE, 1, bytes.as_ptr() as u64, bytes.len() as u64);
+/// Prints each C string in the NULL-terminated array at `list` and returns
+/// the slot after the terminator.
+unsafe fn write_strings(label: &[u8], mut list: *const *const u8) -> *const *const u8 {
+    write(label);
+    unsafe {
+        while !(*list).is_null() {
+            write(b" ");
+            write(CStr::from_ptr((*list).cast()).to_bytes());
+            list = list.add(1);
+        }
+        write(b"\n");
+        list.add(1)
+    }
+}
+
+/// Checks the auxiliary vector the kernel placed after envp.
+unsafe fn auxv_ok(mut auxv: *const u64) -> bool {
+    let (mut pagesz, mut entry, mut random) = (0, 0, 0);
+    unsafe {
+        while *auxv != AT_NULL {
+            match *auxv {
+                AT_PAGESZ => pagesz = *auxv.add(1),
+                AT_ENTRY => entry = *auxv.add(1),
+                AT_RANDOM => random = *auxv.add(1),
+                _ => {}
+            }
+            auxv = auxv.add(2);
+        }
+    }
+    pagesz == 4096 && entry == _start as *const () as usize as u64 && random != 0
+}
+
+/// Reads the `.tdata` and `.tbss` words through `fs`, directly and via the
+/// TCB self-pointer.
+fn tls_ok() -> bool {
+    let (word, zero, via_tcb): (u64, u64, u64);
+    unsafe {
+        asm!(
+            "mov {word}, qword ptr fs:[tls_word@tpoff]",
+            "mov {zero}, qword ptr fs:[tls_zero@tpoff]",
+            "mov {tcb}, qword ptr fs:[0]",
+            "mov {tcb}, qword ptr [{tcb} + tls_word@tpoff]",
+            word = out(reg) word,
+            zero = out(reg) zero,
+            tcb = out(reg) via_tcb,
+            options(nostack, readonly)
+        );
+    }
+    word == TLS_MAGIC && zero == 0 && via_tcb == TLS_MAGIC
 }
 
+#[unsafe(naked)]
 #[unsafe(no_mangle)]
 pub extern "C" fn _start() -> ! {
-    write(b"[testbin] hello from execve target\n");
+    naked_asm!("mov rdi, rsp", "call {main}", "ud2", main = sym main)
+}
+
+extern "C" fn main(sp: *const u64) -> ! {
+    write_int80(b"[testbin] hello from execve target\n");
+
+    unsafe {
+        let argv = sp.add(1) as *const *const u8;
+        let envp = write_strings(b"[testbin] argv:", argv);
+        let auxv = write_strings(b"[testbin] envp:", envp);
+        if auxv_ok(auxv as *const u64) {
+            write(b"[testbin] auxv: AT_PAGESZ, AT_ENTRY and AT_RANDOM present\n");
+        } else {
+            write(b"[testbin] auxv: missing or wrong entries\n");
+        }
+    }
+    if tls_ok() {
+        write(b"[testbin] tls: PT_TLS block set up behind fs\n");
+    } else {
+        write(b"[testbin] tls: thread pointer or TLS image wrong\n");
+    }
 
     let path = "test.txt";
     let fd = syscall3(SYS_OPEN, path.len() as u64, path.as_ptr() as u64, 0);
@@ -58,9 +146,13 @@ pub extern "C" fn _start() -> ! {
         write(b"[testbin] open test.txt failed\n");
     }
 
-    let _ = syscall3(SYS_EXIT, 0, 0, 0);
-
-    loop {
-        unsafe { asm!("hlt") };
+    let bad = syscall3(SYS_WRITE, 1, 0xffff_8000_0000_0000, 16);
+    let unmapped = syscall3(SYS_OPEN, 8, 0x10, 0);
+    if bad == -14 && unmapped == -14 {
+        write(b"[testbin] bad user pointers rejected with EFAULT\n");
+    } else {
+        write(b"[testbin] bad user pointers were not rejected\n");
     }
+
+    rt::exit(0)
 }
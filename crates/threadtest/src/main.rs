@@ -0,0 +1,227 @@
+#![no_std]
+#![no_main]
+
+use common::syscall::{
+    ARCH_GET_FS, ARCH_SET_FS, CLONE_CHILD_CLEARTID, CLONE_CHILD_SETTID, CLONE_FILES, CLONE_FS,
+    CLONE_PARENT_SETTID, CLONE_SETTLS, CLONE_SIGHAND, CLONE_SYSVSEM, CLONE_THREAD, CLONE_VM,
+    FD_STDOUT, FUTEX_PRIVATE_FLAG, FUTEX_WAIT, FUTEX_WAKE, MAP_ANONYMOUS, MAP_PRIVATE, PROT_READ,
+    PROT_WRITE, SYS_ARCH_PRCTL, SYS_CLONE, SYS_EXIT, SYS_FUTEX, SYS_GETPID, SYS_GETTID, SYS_MMAP,
+};
+use core::arch::asm;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use rt::{syscall3, syscall6};
+
+const THREADS: usize = 3;
+const ITERATIONS: u64 = 2000;
+const STACK_SIZE: u64 = 16 * 1024;
+
+#[panic_handler]
+fn panic(_: &core::panic::PanicInfo<'_>) -> ! {
+    rt::abort(b"[threadtest] panic")
+}
+
+fn fail(msg: &[u8]) -> ! {
+    for part in [&b"[threadtest] FAILED: "[..], msg, b"\n"] {
+        let _ = rt::write(FD_STDOUT, part);
+    }
+    rt::exit(1)
+}
+
+fn futex_wait(word: &AtomicU32, expected: u32) {
+    let op = FUTEX_WAIT | FUTEX_PRIVATE_FLAG;
+    let _ = syscall6(
+        SYS_FUTEX,
+        word.as_ptr() as u64,
+        op,
+        expected.into(),
+        0,
+        0,
+        0,
+    );
+}
+
+fn futex_wake(word: &AtomicU32, count: u32) {
+    let op = FUTEX_WAKE | FUTEX_PRIVATE_FLAG;
+    let _ = syscall6(SYS_FUTEX, word.as_ptr() as u64, op, count.into(), 0, 0, 0);
+}
+
+/// 0 unlocked, 1 locked, 2 locked with possible waiters.
+static LOCK: AtomicU32 = AtomicU32::new(0);
+static mut COUNTER: u64 = 0;
+
+fn lock() {
+    let mut state = match LOCK.compare_exchange(0, 1, Ordering::Acquire, Ordering::Relaxed) {
+        Ok(_) => return,
+        Err(state) => state,
+    };
+    if state != 2 {
+        state = LOCK.swap(2, Ordering::Acquire);
+    }
+    while state != 0 {
+        futex_wait(&LOCK, 2);
+        state = LOCK.swap(2, Ordering::Acquire);
+    }
+}
+
+fn unlock() {
+    if LOCK.fetch_sub(1, Ordering::Release) != 1 {
+        LOCK.store(0, Ordering::Release);
+        futex_wake(&LOCK, 1);
+    }
+}
+
+/// Per-thread TLS blocks: the self pointer the x86-64 ABI puts at `fs:0`,
+/// then the thread's index.
+static mut TLS: [[u64; 2]; THREADS + 1] = [[0; 2]; THREADS + 1];
+/// Set to the child's tid by the kernel, cleared and woken when it exits.
+static JOIN: [AtomicU32; THREADS] = [const { AtomicU32::new(0) }; THREADS];
+/// Set to the child's tid by the kernel and left alone after that.
+static PARENT_TIDS: [AtomicU32; THREADS] = [const { AtomicU32::new(0) }; THREADS];
+static TIDS: [AtomicU64; THREADS] = [const { AtomicU64::new(0) }; THREADS];
+static PIDS: [AtomicU64; THREADS] = [const { AtomicU64::new(0) }; THREADS];
+
+fn tls_index() -> u64 {
+    let index: u64;
+    unsafe { asm!("mov {}, fs:[8]", out(reg) index, options(nostack, readonly)) };
+    index
+}
+
+fn tls_block(index: usize) -> u64 {
+    unsafe {
+        let block = &raw mut TLS[index];
+        (*block)[0] = block as u64;
+        (*block)[1] = index as u64;
+        block as u64
+    }
+}
+
+extern "C" fn worker(index: u64) -> ! {
+    let slot = index as usize - 1;
+    if tls_index() != index {
+        fail(b"thread sees another thread's TLS");
+    }
+    let tid = syscall3(SYS_GETTID, 0, 0, 0) as u64;
+    if PARENT_TIDS[slot].load(Ordering::Relaxed) != tid as u32
+        || JOIN[slot].load(Ordering::Relaxed) != tid as u32
+    {
+        fail(b"thread ran before clone wrote its tid");
+    }
+    TIDS[slot].store(tid, Ordering::Relaxed);
+    PIDS[slot].store(syscall3(SYS_GETPID, 0, 0, 0) as u64, Ordering::Relaxed);
+    for _ in 0..ITERATIONS {
+        lock();
+        unsafe { COUNTER += 1 };
+        unlock();
+    }
+    let _ = syscall3(SYS_EXIT, 0, 0, 0);
+    fail(b"thread exit returned")
+}
+
+/// Starts `worker(index)` on `stack_top` with its own TLS block.
+fn spawn(index: usize, stack_top: u64) -> isize {
+    let flags = CLONE_VM
+        | CLONE_FS
+        | CLONE_FILES
+        | CLONE_SIGHAND
+        | CLONE_THREAD
+        | CLONE_SYSVSEM
+        | CLONE_SETTLS
+        | CLONE_PARENT_SETTID
+        | CLONE_CHILD_SETTID
+        | CLONE_CHILD_CLEARTID;
+    let ret: i64;
+    unsafe {
+        asm!(
+            "syscall",
+            "test rax, rax",
+            "jnz 2f",
+            "mov rdi, r12",
+            "call r13",
+            "ud2",
+            "2:",
+            inlateout("rax") SYS_CLONE as i64 => ret,
+            in("rdi") flags,
+            in("rsi") stack_top,
+            in("rdx") PARENT_TIDS[index - 1].as_ptr() as u64,
+            in("r10") JOIN[index - 1].as_ptr() as u64,
+            in("r8") tls_block(index),
+            in("r12") index as u64,
+            in("r13") worker as *const () as u64,
+            lateout("rcx") _,
+            lateout("r11") _,
+        );
+    }
+    ret as isize
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn _start() -> ! {
+    let _ = rt::write(FD_STDOUT, b"[threadtest] starting threads\n");
+
+    let tls = tls_block(0);
+    let mut fs_base = 0u64;
+    if syscall3(SYS_ARCH_PRCTL, ARCH_SET_FS, tls, 0) != 0
+        || syscall3(SYS_ARCH_PRCTL, ARCH_GET_FS, &raw mut fs_base as u64, 0) != 0
+        || fs_base != tls
+        || tls_index() != 0
+    {
+        fail(b"arch_prctl");
+    }
+
+    let pid = syscall3(SYS_GETPID, 0, 0, 0) as u64;
+    let tid = syscall3(SYS_GETTID, 0, 0, 0) as u64;
+    if tid != pid {
+        fail(b"main thread tid differs from pid");
+    }
+
+    let stacks = syscall6(
+        SYS_MMAP,
+        0,
+        STACK_SIZE * THREADS as u64,
+        u64::from(PROT_READ | PROT_WRITE),
+        u64::from(MAP_PRIVATE | MAP_ANONYMOUS),
+        u64::MAX,
+        0,
+    );
+    if stacks < 0 {
+        fail(b"mmap");
+    }
+    for index in 1..=THREADS {
+        let top = stacks as u64 + STACK_SIZE * index as u64;
+        let child = spawn(index, top);
+        if child <= 0 || PARENT_TIDS[index - 1].load(Ordering::Relaxed) != child as u32 {
+            fail(b"clone");
+        }
+    }
+
+    for word in &JOIN {
+        loop {
+            let tid = word.load(Ordering::Acquire);
+            if tid == 0 {
+                break;
+            }
+            futex_wait(word, tid);
+        }
+    }
+
+    if unsafe { COUNTER } != ITERATIONS * THREADS as u64 {
+        fail(b"lost updates to the shared counter");
+    }
+    for (i, (tid_slot, pid_slot)) in TIDS.iter().zip(&PIDS).enumerate() {
+        let child = tid_slot.load(Ordering::Relaxed);
+        if pid_slot.load(Ordering::Relaxed) != pid
+            || child == tid
+            || TIDS[..i].iter().any(|t| t.load(Ordering::Relaxed) == child)
+        {
+            fail(b"threads do not share a pid with distinct tids");
+        }
+    }
+    if tls_index() != 0 {
+        fail(b"main thread TLS changed");
+    }
+    let _ = rt::write(
+        FD_STDOUT,
+        b"[threadtest] ok: 3 threads with their own TLS shared a futex-locked counter\n",
+    );
+    rt::exit(0)
+}
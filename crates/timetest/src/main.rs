@@ -0,0 +1,119 @@
+#![no_std]
+#![no_main]
+
+use common::syscall::{
+    CLOCK_BOOTTIME, CLOCK_MONOTONIC, FD_STDOUT, SYS_CLOCK_GETTIME, SYS_FORK, SYS_NANOSLEEP,
+    SYS_WAIT4,
+};
+use common::time::{NANOS_PER_SEC, Timespec};
+use rt::syscall3;
+
+const EINVAL: isize = -22;
+const NANOS_PER_MILLI: u64 = 1_000_000;
+const SLEEP_MS: u64 = 20;
+const SLEEPS: u64 = 3;
+const CHILD_SLEEP_MS: u64 = 50;
+/// Generous, as emulators can be slow to get back to a woken thread.
+const OVERSLEEP_LIMIT_MS: u64 = 1000;
+
+#[panic_handler]
+fn panic(_: &core::panic::PanicInfo<'_>) -> ! {
+    rt::abort(b"[timetest] panic")
+}
+
+fn fail(msg: &[u8]) -> ! {
+    for part in [&b"[timetest] FAILED: "[..], msg, b"\n"] {
+        let _ = rt::write(FD_STDOUT, part);
+    }
+    rt::exit(1)
+}
+
+fn clock_gettime(clock: u64) -> Result<u64, isize> {
+    let mut ts = Timespec::default();
+    match syscall3(SYS_CLOCK_GETTIME, clock, &raw mut ts as u64, 0) {
+        0 => ts.to_nanos().ok_or(EINVAL),
+        e => Err(e),
+    }
+}
+
+fn now() -> u64 {
+    clock_gettime(CLOCK_MONOTONIC).unwrap_or_else(|_| fail(b"clock_gettime(CLOCK_MONOTONIC)"))
+}
+
+fn nanosleep(ts: &Timespec) -> isize {
+    syscall3(SYS_NANOSLEEP, ts as *const Timespec as u64, 0, 0)
+}
+
+/// Sleeps `ms` and returns how long that took by the monotonic clock.
+fn timed_sleep(ms: u64) -> u64 {
+    let start = now();
+    if nanosleep(&Timespec::from_nanos(ms * NANOS_PER_MILLI)) != 0 {
+        fail(b"nanosleep");
+    }
+    let elapsed = now() - start;
+    if elapsed < ms * NANOS_PER_MILLI {
+        fail(b"nanosleep woke up early");
+    }
+    if elapsed > OVERSLEEP_LIMIT_MS * NANOS_PER_MILLI {
+        fail(b"nanosleep overslept by more than a second");
+    }
+    elapsed
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn _start() -> ! {
+    let _ = rt::write(
+        FD_STDOUT,
+        b"[timetest] checking clock_gettime and nanosleep\n",
+    );
+
+    let mut last = now();
+    for _ in 0..1000 {
+        let t = now();
+        if t < last {
+            fail(b"CLOCK_MONOTONIC went backwards");
+        }
+        last = t;
+    }
+    if clock_gettime(CLOCK_BOOTTIME).is_err() {
+        fail(b"clock_gettime(CLOCK_BOOTTIME)");
+    }
+    if clock_gettime(0) != Err(EINVAL) {
+        fail(b"clock_gettime accepted a clock it does not have");
+    }
+    let bad = Timespec {
+        tv_sec: 0,
+        tv_nsec: NANOS_PER_SEC as i64,
+    };
+    if nanosleep(&bad) != EINVAL {
+        fail(b"nanosleep accepted tv_nsec out of range");
+    }
+
+    // The child sleeps alongside the parent, so two deadlines are pending.
+    let pid = syscall3(SYS_FORK, 0, 0, 0);
+    if pid < 0 {
+        fail(b"fork");
+    }
+    if pid == 0 {
+        timed_sleep(CHILD_SLEEP_MS);
+        rt::exit(0);
+    }
+
+    let mut slept = 0;
+    for _ in 0..SLEEPS {
+        slept += timed_sleep(SLEEP_MS);
+    }
+    let mut status = 0i32;
+    if syscall3(SYS_WAIT4, pid as u64, &raw mut status as u64, 0) != pid || status != 0 {
+        fail(b"child's sleep went wrong");
+    }
+
+    let _ = rt::write(FD_STDOUT, b"[timetest] ok: ");
+    let _ = rt::write_decimal(FD_STDOUT, SLEEPS);
+    let _ = rt::write(FD_STDOUT, b" sleeps of ");
+    let _ = rt::write_decimal(FD_STDOUT, SLEEP_MS);
+    let _ = rt::write(FD_STDOUT, b" ms took ");
+    let _ = rt::write_decimal(FD_STDOUT, slept / NANOS_PER_MILLI);
+    let _ = rt::write(FD_STDOUT, b" ms by CLOCK_MONOTONIC\n");
+    rt::exit(0)
+}
@@ -0,0 +1,69 @@
+#![no_std]
+#![no_main]
+
+use common::syscall::{FD_STDOUT, SYS_FORK, SYS_WAIT4};
+use core::hint::black_box;
+use core::ptr::write_volatile;
+use rt::syscall3;
+
+const SIGSEGV_STATUS: i32 = (128 + 11) << 8;
+
+#[panic_handler]
+fn panic(_: &core::panic::PanicInfo<'_>) -> ! {
+    rt::abort(b"[wxtest] panic")
+}
+
+type Probe = (&'static [u8], fn());
+
+static RODATA: [u8; 8] = *b"rodata!\0";
+static mut DATA_RET: [u8; 1] = [0xc3];
+
+fn write_text() {
+    unsafe { write_volatile(black_box(_start as *const () as usize as *mut u8), 0xcc) };
+}
+
+fn write_rodata() {
+    unsafe { write_volatile(black_box(RODATA.as_ptr() as *mut u8), 0) };
+}
+
+fn jump_to_data() {
+    let target: extern "C" fn() = unsafe { core::mem::transmute(black_box(&raw mut DATA_RET)) };
+    target();
+}
+
+/// Runs `probe` in a child and reports whether the child was killed by SIGSEGV.
+fn faults(probe: fn()) -> bool {
+    let pid = syscall3(SYS_FORK, 0, 0, 0);
+    if pid < 0 {
+        return false;
+    }
+    if pid == 0 {
+        probe();
+        rt::exit(0);
+    }
+    let mut status = 0i32;
+    let waited = syscall3(SYS_WAIT4, pid as u64, &raw mut status as u64, 0);
+    waited == pid && status == SIGSEGV_STATUS
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn _start() -> ! {
+    let _ = rt::write(FD_STDOUT, b"[wxtest] checking segment permissions\n");
+
+    let probes: [Probe; 3] = [
+        (b"[wxtest] FAILED: .text was writable\n", write_text),
+        (b"[wxtest] FAILED: .rodata was writable\n", write_rodata),
+        (b"[wxtest] FAILED: .data was executable\n", jump_to_data),
+    ];
+    for (failure, probe) in probes {
+        if !faults(probe) {
+            let _ = rt::write(FD_STDOUT, failure);
+            rt::exit(1);
+        }
+    }
+    let _ = rt::write(
+        FD_STDOUT,
+        b"[wxtest] ok: .text and .rodata are read-only, .data is NX\n",
+    );
+    rt::exit(0)
+}
@@ -6,6 +6,7 @@ BUILD="$ROOT/build"
 ISO="$BUILD/os.iso"
 LIMINE_DIR="$BUILD/limine"
 INIT_FEATURES="${INIT_FEATURES:-}"
+ASLR="${ASLR:-on}"
 
 mkdir -p "$BUILD/root/boot"
 
@@ -18,6 +19,16 @@ fi
 cargo build --manifest-path "$ROOT/crates/testbin/Cargo.toml" --release --target x86_64-unknown-none
 cargo build --manifest-path "$ROOT/crates/shell/Cargo.toml" --release --target x86_64-unknown-none
 cargo build --manifest-path "$ROOT/crates/fbfill/Cargo.toml" --release --target x86_64-unknown-none
+cargo build --manifest-path "$ROOT/crates/cowtest/Cargo.toml" --release --target x86_64-unknown-none
+cargo build --manifest-path "$ROOT/crates/threadtest/Cargo.toml" --release --target x86_64-unknown-none
+cargo build --manifest-path "$ROOT/crates/smptest/Cargo.toml" --release --target x86_64-unknown-none
+cargo build --manifest-path "$ROOT/crates/fputest/Cargo.toml" --release --target x86_64-unknown-none
+cargo build --manifest-path "$ROOT/crates/timetest/Cargo.toml" --release --target x86_64-unknown-none
+cargo build --manifest-path "$ROOT/crates/wxtest/Cargo.toml" --release --target x86_64-unknown-none
+cargo build --manifest-path "$ROOT/crates/mmtest/Cargo.toml" --release --target x86_64-unknown-none
+cargo build --manifest-path "$ROOT/crates/dyntest/Cargo.toml" --release --target x86_64-unknown-none
+cargo build --manifest-path "$ROOT/crates/ldso/Cargo.toml" --release --target x86_64-unknown-none
+cargo build --manifest-path "$ROOT/crates/librt/Cargo.toml" --release --target x86_64-unknown-none
 
 cp "$ROOT/target/x86_64-unknown-none/release/kernel" "$BUILD/root/boot/kernel"
 cp "$ROOT/target/x86_64-unknown-none/release/init" "$BUILD/init.elf"
@@ -28,12 +39,26 @@ cp "$ROOT/target/x86_64-unknown-none/release/fbfill" "$BUILD/fbfill.elf"
 cp "$ROOT/target/x86_64-unknown-none/release/testbin" "$BUILD/bin/testbin.elf"
 cp "$ROOT/target/x86_64-unknown-none/release/shell" "$BUILD/bin/shell.elf"
 cp "$ROOT/target/x86_64-unknown-none/release/fbfill" "$BUILD/bin/fbfill.elf"
+cp "$ROOT/target/x86_64-unknown-none/release/cowtest" "$BUILD/bin/cowtest.elf"
+cp "$ROOT/target/x86_64-unknown-none/release/threadtest" "$BUILD/bin/threadtest.elf"
+cp "$ROOT/target/x86_64-unknown-none/release/smptest" "$BUILD/bin/smptest.elf"
+cp "$ROOT/target/x86_64-unknown-none/release/fputest" "$BUILD/bin/fputest.elf"
+cp "$ROOT/target/x86_64-unknown-none/release/timetest" "$BUILD/bin/timetest.elf"
+cp "$ROOT/target/x86_64-unknown-none/release/wxtest" "$BUILD/bin/wxtest.elf"
+cp "$ROOT/target/x86_64-unknown-none/release/mmtest" "$BUILD/bin/mmtest.elf"
+cp "$ROOT/target/x86_64-unknown-none/release/dyntest" "$BUILD/bin/dyntest.elf"
+mkdir -p "$BUILD/lib"
+cp "$ROOT/target/x86_64-unknown-none/release/ldso" "$BUILD/lib/ld.so"
+cp "$ROOT/target/x86_64-unknown-none/release/librt" "$BUILD/lib/librt.so"
 printf "hello-from-initrd\n" > "$BUILD/test.txt"
 printf "Welcome to PromptOS - 100%% certified vibecoded.\n" > "$BUILD/motd.txt"
 
-( cd "$BUILD" && tar --format=ustar -cf initramfs.tar init.elf testbin.elf shell.elf fbfill.elf test.txt motd.txt bin/testbin.elf bin/shell.elf bin/fbfill.elf )
+( cd "$BUILD" && tar --format=ustar -cf initramfs.tar init.elf testbin.elf shell.elf fbfill.elf test.txt motd.txt bin/testbin.elf bin/shell.elf bin/fbfill.elf bin/cowtest.elf bin/threadtest.elf bin/smptest.elf bin/fputest.elf bin/timetest.elf bin/wxtest.elf bin/mmtest.elf bin/dyntest.elf lib/ld.so lib/librt.so )
 cp "$BUILD/initramfs.tar" "$BUILD/root/boot/initramfs.tar"
 cp "$ROOT/limine.conf" "$BUILD/root/boot/limine.conf"
+if [[ "$ASLR" == "off" ]]; then
+  printf "    kaslr: no\n    cmdline: norandmaps\n" >> "$BUILD/root/boot/limine.conf"
+fi
 
 if [[ ! -d "$LIMINE_DIR" ]]; then
   git clone --depth 1 --branch v10.x-binary https://github.com/limine-bootloader/limine.git "$LIMINE_DIR"
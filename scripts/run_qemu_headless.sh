@@ -10,6 +10,7 @@ fi
 
 qemu-system-x86_64 \
   -m 256M \
+  -smp 4 \
   -cdrom "$ISO" \
   -boot d \
   -display none \
@@ -91,16 +91,33 @@ if [[ $status -ne 33 ]]; then
 fi
 
 rg -q "\[kernel\] limine boot ok" "$LOG"
-rg -q "\[kernel\] process stack ready" "$LOG"
-rg -q "\[kernel\] fork: pushed child pid=" "$LOG"
+rg -q "\[kernel\] loaded at 0x[0-9a-f]+ \(phys 0x[0-9a-f]+\), user ASLR on" "$LOG"
+rg -q "\[kernel\] cpu 3 online \(lapic id [0-9]+\)" "$LOG"
+rg -q "\[kernel\] scheduler ready on 4 CPUs" "$LOG"
+rg -q "\[kernel\] fork: created child pid=" "$LOG"
 rg -q "\[init\] motd: Welcome to PromptOS - 100% certified vibecoded." "$LOG"
 rg -q "\[init\] child process is now running" "$LOG"
 rg -q "\[init\] child execve target: testbin.elf" "$LOG"
 rg -q "\[testbin\] hello from execve target" "$LOG"
+rg -q "\[testbin\] argv: testbin.elf hello world" "$LOG"
+rg -q "\[testbin\] envp: PATH=/bin TERM=serial" "$LOG"
+rg -q "\[testbin\] auxv: AT_PAGESZ, AT_ENTRY and AT_RANDOM present" "$LOG"
+rg -q "\[testbin\] tls: PT_TLS block set up behind fs" "$LOG"
 rg -q "\[testbin\] read test.txt: hell" "$LOG"
+rg -q "\[testbin\] bad user pointers rejected with EFAULT" "$LOG"
 rg -q "\[kernel\] execve: replaced current process image with testbin.elf" "$LOG"
-rg -q "\[kernel\] exit\(0\): popped current process" "$LOG"
+rg -q "\[kernel\] exit\(0\): pid=[0-9]+ exited" "$LOG"
 rg -q "\[init\] parent resumed after child exit" "$LOG"
+rg -q "\[cowtest\] ok: parent and child writes stayed private" "$LOG"
+rg -q "\[threadtest\] ok: 3 threads with their own TLS shared a futex-locked counter" "$LOG"
+rg -q "\[smptest\] ok: 4 busy threads ran on [2-4] CPUs" "$LOG"
+rg -q "\[kernel\] FPU state: (xsave|fxsave), [0-9]+ bytes per thread" "$LOG"
+rg -q "\[fputest\] ok: 2 processes kept their SSE( and AVX)? registers" "$LOG"
+rg -q "\[kernel\] TSC clocksource at [0-9]+ kHz .*calibrated against the (HPET|PIT)" "$LOG"
+rg -q "\[timetest\] ok: 3 sleeps of 20 ms took [0-9]+ ms by CLOCK_MONOTONIC" "$LOG"
+rg -q "\[wxtest\] ok: .text and .rodata are read-only, .data is NX" "$LOG"
+rg -q "\[mmtest\] ok: mmap/munmap/mprotect/brk behave, pages fault in on demand, files map through the page cache" "$LOG"
+rg -q "\[dyntest\] ok: librt.so bound through /lib/ld.so" "$LOG"
 rg -q "\[init\] echo: smoke-input" "$LOG"
 rg -q "\[init\] done" "$LOG"
 